@@ -0,0 +1,284 @@
+-- Схема базы данных. Применяется при каждом запуске сервера (db::init_schema),
+-- поэтому все выражения должны быть идемпотентными.
+
+-- Не засоряем лог уведомлениями вида "relation already exists, skipping"
+SET client_min_messages TO WARNING;
+
+CREATE TABLE IF NOT EXISTS users (
+    user_uuid UUID PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    invitation_code TEXT NOT NULL
+);
+
+-- Хэши Argon2id длиннее 60 символов bcrypt
+ALTER TABLE users ALTER COLUMN password_hash TYPE TEXT;
+
+CREATE TABLE IF NOT EXISTS devices (
+    device_id UUID PRIMARY KEY,
+    user_uuid UUID NOT NULL REFERENCES users (user_uuid),
+    ip_address INET NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    session_id UUID PRIMARY KEY,
+    user_uuid UUID NOT NULL REFERENCES users (user_uuid),
+    device_id UUID NOT NULL REFERENCES devices (device_id),
+    expires_at TIMESTAMPTZ
+);
+
+CREATE TABLE IF NOT EXISTS messages (
+    id BIGSERIAL PRIMARY KEY,
+    message TEXT NOT NULL,
+    user_uuid UUID REFERENCES users (user_uuid),
+    timestamp TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Роли пользователей. rank задает старшинство ролей.
+CREATE TABLE IF NOT EXISTS roles (
+    name TEXT PRIMARY KEY,
+    rank INTEGER NOT NULL UNIQUE
+);
+
+INSERT INTO roles (name, rank) VALUES ('member', 0), ('moderator', 1), ('admin', 2)
+    ON CONFLICT (name) DO NOTHING;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'member' REFERENCES roles (name);
+
+-- В старых базах у messages не было идентификатора
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS id BIGSERIAL;
+
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conrelid = 'messages'::regclass AND contype = 'p') THEN
+        ALTER TABLE messages ADD PRIMARY KEY (id);
+    END IF;
+END $$;
+
+-- Модерация: мягкое удаление сообщений, заглушения и баны
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS deleted_by UUID REFERENCES users (user_uuid);
+
+CREATE TABLE IF NOT EXISTS mutes (
+    user_uuid UUID PRIMARY KEY REFERENCES users (user_uuid),
+    muted_until TIMESTAMPTZ NOT NULL,
+    muted_by UUID NOT NULL REFERENCES users (user_uuid),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS bans (
+    id BIGSERIAL PRIMARY KEY,
+    user_uuid UUID REFERENCES users (user_uuid),
+    ip INET,
+    reason TEXT NOT NULL DEFAULT '',
+    created_by UUID NOT NULL REFERENCES users (user_uuid),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ,
+    CHECK (user_uuid IS NOT NULL OR ip IS NOT NULL)
+);
+
+CREATE INDEX IF NOT EXISTS bans_user_uuid_idx ON bans (user_uuid);
+CREATE INDEX IF NOT EXISTS bans_ip_idx ON bans USING gist (ip inet_ops);
+
+-- Журнал аудита. Только добавление: изменять и удалять записи запрещено триггером.
+-- Внешних ключей на users нет намеренно — журнал должен переживать любые изменения в users.
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    actor_uuid UUID,
+    target_uuid UUID,
+    action TEXT NOT NULL,
+    ip INET,
+    details JSONB NOT NULL DEFAULT '{}'
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_created_at_idx ON audit_log (created_at);
+CREATE INDEX IF NOT EXISTS audit_log_action_idx ON audit_log (action);
+CREATE INDEX IF NOT EXISTS audit_log_actor_uuid_idx ON audit_log (actor_uuid);
+CREATE INDEX IF NOT EXISTS audit_log_target_uuid_idx ON audit_log (target_uuid);
+
+CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS audit_log_append_only ON audit_log;
+CREATE TRIGGER audit_log_append_only BEFORE UPDATE OR DELETE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
+
+DROP TRIGGER IF EXISTS audit_log_no_truncate ON audit_log;
+CREATE TRIGGER audit_log_no_truncate BEFORE TRUNCATE ON audit_log
+    FOR EACH STATEMENT EXECUTE FUNCTION audit_log_append_only();
+
+-- Редактирование сообщений: предыдущие версии хранятся в message_edits
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS edited_at TIMESTAMPTZ;
+
+CREATE TABLE IF NOT EXISTS message_edits (
+    id BIGSERIAL PRIMARY KEY,
+    message_id BIGINT NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    previous_message TEXT NOT NULL,
+    edited_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS message_edits_message_id_idx ON message_edits (message_id);
+
+-- Ветки: ответ ссылается на корневое сообщение ветки
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS parent_id BIGINT REFERENCES messages (id) ON DELETE CASCADE;
+CREATE INDEX IF NOT EXISTS messages_parent_id_idx ON messages (parent_id, timestamp);
+
+-- Реакции: каждый пользователь может поставить одно и то же эмодзи на сообщение только один раз
+CREATE TABLE IF NOT EXISTS reactions (
+    message_id BIGINT NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    user_uuid UUID NOT NULL REFERENCES users (user_uuid) ON DELETE CASCADE,
+    emoji TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (message_id, user_uuid, emoji)
+);
+
+-- Комнаты чата. Существующие сообщения попадают в комнату general.
+CREATE TABLE IF NOT EXISTS rooms (
+    name TEXT PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+INSERT INTO rooms (name) VALUES ('general') ON CONFLICT (name) DO NOTHING;
+
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS room TEXT NOT NULL DEFAULT 'general' REFERENCES rooms (name);
+CREATE INDEX IF NOT EXISTS messages_room_idx ON messages (room, timestamp);
+
+-- Отметки прочтения: последнее прочитанное сообщение пользователя в каждой комнате
+CREATE TABLE IF NOT EXISTS read_markers (
+    user_uuid UUID NOT NULL REFERENCES users (user_uuid) ON DELETE CASCADE,
+    room TEXT NOT NULL REFERENCES rooms (name) ON DELETE CASCADE,
+    last_read_id BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_uuid, room)
+);
+
+-- Участники комнат. Подключиться к комнате, читать, выгружать и искать ее сообщения может
+-- только участник. В general состоит каждый пользователь, в остальные комнаты участников
+-- добавляют модераторы, создатель комнаты становится ее участником сам.
+CREATE TABLE IF NOT EXISTS room_members (
+    room TEXT NOT NULL REFERENCES rooms (name) ON DELETE CASCADE,
+    user_uuid UUID NOT NULL REFERENCES users (user_uuid) ON DELETE CASCADE,
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_uuid, room)
+);
+
+-- Кто уже читал комнату до появления таблицы, остается ее участником
+INSERT INTO room_members (room, user_uuid)
+SELECT room, user_uuid FROM read_markers
+ON CONFLICT DO NOTHING;
+
+INSERT INTO room_members (room, user_uuid)
+SELECT 'general', user_uuid FROM users
+ON CONFLICT DO NOTHING;
+
+-- Полнотекстовый поиск по сообщениям. Конфигурация simple: чат смешивает русский и английский,
+-- поэтому без стемминга под один язык.
+CREATE INDEX IF NOT EXISTS messages_search_idx ON messages USING gin (to_tsvector('simple', message));
+
+-- Уведомления об упоминаниях (@username, @here, @room). Одно уведомление на пользователя и сообщение.
+CREATE TABLE IF NOT EXISTS notifications (
+    id BIGSERIAL PRIMARY KEY,
+    user_uuid UUID NOT NULL REFERENCES users (user_uuid) ON DELETE CASCADE,
+    message_id BIGINT NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    read_at TIMESTAMPTZ,
+    UNIQUE (user_uuid, message_id)
+);
+
+CREATE INDEX IF NOT EXISTS notifications_user_idx ON notifications (user_uuid, id DESC);
+
+-- Отрисованный HTML сообщения (безопасное подмножество Markdown, см. src/markdown.rs).
+-- Для старых сообщений без HTML он строится при чтении.
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS message_html TEXT;
+
+-- Кэш превью ссылок (OpenGraph и <title>). Пустые поля — страница загружена, но превью нет;
+-- такие записи тоже кэшируются, чтобы не загружать страницу повторно.
+CREATE TABLE IF NOT EXISTS link_previews (
+    url TEXT PRIMARY KEY,
+    title TEXT,
+    description TEXT,
+    image TEXT,
+    site_name TEXT,
+    fetched_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Ссылка сообщения, для которой показывается превью
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS preview_url TEXT REFERENCES link_previews (url) ON DELETE SET NULL;
+
+-- Выгрузка всех сообщений пользователя
+CREATE INDEX IF NOT EXISTS messages_user_uuid_idx ON messages (user_uuid, timestamp);
+
+-- Загруженные файлы (POST /api/upload). path — путь внутри uploads.root; файл отдается
+-- по /uploaded/{path}. message_id пуст, пока загрузивший не прикрепит файл к сообщению.
+CREATE TABLE IF NOT EXISTS attachments (
+    id BIGSERIAL PRIMARY KEY,
+    message_id BIGINT REFERENCES messages (id) ON DELETE CASCADE,
+    uploaded_by UUID REFERENCES users (user_uuid) ON DELETE SET NULL,
+    path TEXT NOT NULL,
+    file_name TEXT NOT NULL,
+    content_type TEXT,
+    size BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+ALTER TABLE attachments ALTER COLUMN message_id DROP NOT NULL;
+ALTER TABLE attachments ADD COLUMN IF NOT EXISTS uploaded_by UUID REFERENCES users (user_uuid) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS attachments_message_id_idx ON attachments (message_id);
+-- Для очистки загрузок, которые так и не прикрепили к сообщению
+CREATE INDEX IF NOT EXISTS attachments_unattached_idx ON attachments (created_at) WHERE message_id IS NULL;
+
+-- Срок хранения сообщений комнаты: не больше retention_days дней или только последние
+-- retention_messages корневых сообщений. Оба NULL — хранить всегда.
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS retention_days INTEGER CHECK (retention_days > 0);
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS retention_messages INTEGER CHECK (retention_messages > 0);
+
+-- Тема и описание комнаты, которые задают модераторы
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS topic TEXT;
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS description TEXT;
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS topic_updated_by UUID REFERENCES users (user_uuid) ON DELETE SET NULL;
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS topic_updated_at TIMESTAMPTZ;
+
+-- Закрепленные сообщения комнаты
+CREATE TABLE IF NOT EXISTS pins (
+    message_id BIGINT PRIMARY KEY REFERENCES messages (id) ON DELETE CASCADE,
+    room TEXT NOT NULL REFERENCES rooms (name),
+    pinned_by UUID REFERENCES users (user_uuid) ON DELETE SET NULL,
+    pinned_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS pins_room_idx ON pins (room, pinned_at);
+
+-- Присутствие: открытые WebSocket-соединения всех экземпляров (fanout.backend = "postgres").
+-- Экземпляр обновляет seen_at своих записей каждые 10 секунд; записи упавших экземпляров
+-- удаляются через минуту.
+CREATE TABLE IF NOT EXISTS presence (
+    client_id TEXT PRIMARY KEY,
+    instance_id UUID NOT NULL,
+    user_uuid UUID NOT NULL,
+    room TEXT NOT NULL,
+    seen_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS presence_room_idx ON presence (room, seen_at);
+
+-- События, не поместившиеся в уведомление NOTIFY; в уведомлении передается только id
+CREATE TABLE IF NOT EXISTS fanout_events (
+    id BIGSERIAL PRIMARY KEY,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Неудачные попытки входа подряд. После rate_limits.max_failed_logins попыток за
+-- rate_limits.login_lockout_secs вход блокируется до locked_until; успешный вход сбрасывает счетчик.
+CREATE TABLE IF NOT EXISTS login_lockouts (
+    user_uuid UUID PRIMARY KEY REFERENCES users (user_uuid) ON DELETE CASCADE,
+    failed_attempts INTEGER NOT NULL,
+    last_failed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    locked_until TIMESTAMPTZ
+);
@@ -0,0 +1,48 @@
+use crate::db::save_audit_entry;
+use crate::models::AuditEntry;
+use chrono::Utc;
+use log::error;
+use serde_json::Value;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+// Действия, которые попадают в журнал аудита. Префикс задает группу (auth., moderation., admin., retention.).
+pub const LOGIN_SUCCESS: &str = "auth.login_success";
+pub const LOGIN_FAILURE: &str = "auth.login_failure";
+pub const LOGIN_LOCKOUT: &str = "auth.login_lockout";
+pub const SESSION_REVOKED: &str = "auth.session_revoked";
+pub const MESSAGE_DELETED: &str = "moderation.message_deleted";
+pub const USER_MUTED: &str = "moderation.user_muted";
+pub const USER_KICKED: &str = "moderation.user_kicked";
+pub const BAN_CREATED: &str = "moderation.ban_created";
+pub const BAN_LIFTED: &str = "moderation.ban_lifted";
+pub const MESSAGE_PINNED: &str = "moderation.message_pinned";
+pub const MESSAGE_UNPINNED: &str = "moderation.message_unpinned";
+pub const TOPIC_CHANGED: &str = "moderation.topic_changed";
+pub const ROOM_MEMBER_ADDED: &str = "moderation.room_member_added";
+pub const ROOM_MEMBER_REMOVED: &str = "moderation.room_member_removed";
+pub const ROLE_CHANGED: &str = "admin.role_changed";
+pub const ROOM_CREATED: &str = "admin.room_created";
+pub const USER_EXPORTED: &str = "admin.user_exported";
+pub const ROOM_RETENTION_CHANGED: &str = "admin.room_retention_changed";
+pub const MESSAGES_PURGED: &str = "retention.messages_purged";
+
+/// Записывает событие в журнал аудита. Ошибка записи не прерывает само действие,
+/// но обязательно попадает в лог сервера.
+pub async fn record(action: &str, actor: Option<Uuid>, target: Option<Uuid>, ip: Option<IpAddr>, details: Value) {
+    let entry = AuditEntry {
+        id: 0,
+        created_at: Utc::now(),
+        actor_uuid: actor,
+        actor: None,
+        target_uuid: target,
+        target: None,
+        action: action.to_string(),
+        ip: ip.map(|ip| ip.to_string()),
+        details,
+    };
+
+    if let Err(e) = save_audit_entry(&entry).await {
+        error!("Failed to write audit entry {:?}: {}", entry, e);
+    }
+}
@@ -1,253 +1,1826 @@
-use tokio_postgres::{NoTls, types::Type};
+use tokio_postgres::{AsyncMessage, Client, NoTls, Row, RowStream, SimpleQueryMessage, ToStatement, Transaction};
+use tokio_postgres::types::{BorrowToSql, ToSql};
 use std::error::Error as StdError;
-use tokio::sync::Mutex as TokioMutex;
-use futures_util::stream::SplitSink;
-use futures_util::SinkExt;
-use warp::ws::WebSocket;
-use std::sync::Arc;
+use std::future::Future;
+use futures_util::{Stream, StreamExt};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use log::{error, debug};
-use crate::models::{User, Device, Session};
+use crate::models::{User, Device, Session, Role, Ban, AuditEntry, AuditFilter, EditOutcome, SearchFilter, SearchResult, Notification, LinkPreview, ExportScope, ExportedMessage, Attachment, RoomRetention, PurgedBatch, RoomTopic, PinOutcome};
+use crate::protocol::{ChatMessage, RoomUnread, PinnedMessage};
+use crate::utils::{escape_html, uploaded_url};
+use crate::markdown;
+use crate::config;
+use crate::metrics;
 use uuid::Uuid;
+use serde::Deserialize;
 use std::net::IpAddr;
 use chrono::{DateTime, Utc};
-use tokio_postgres::types::{ToSql, accepts};
 use std::result::Result;
-use bytes::BytesMut;
+use std::time::Instant;
 
-// Обертка для DateTime<Utc>, чтобы обойти "orphan rule"
-#[derive(Debug)]
-struct Timestamp(DateTime<Utc>);
+const USER_COLUMNS: &str = "username, password_hash, invitation_code, user_uuid, role";
 
-impl ToSql for Timestamp {
-    accepts!(TIMESTAMP, TIMESTAMPTZ);
-    tokio_postgres::types::to_sql_checked!();
+// Собирает User из строки, выбранной с колонками USER_COLUMNS
+fn user_from_row(row: &Row) -> Result<User, Box<dyn StdError + Send + Sync>> {
+    let role: String = row.get(4);
+    Ok(User {
+        username: row.get(0),
+        password_hash: row.get(1),
+        invitation_code: row.get(2),
+        user_uuid: row.get(3),
+        role: role.parse::<Role>()?,
+    })
+}
+
+/// Число открытых соединений с базой данных; при остановке сервер ждет, пока они закроются
+static OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Засекает время одного запроса к базе данных для метрик
+async fn timed<T>(query: impl Future<Output = T>) -> T {
+    let started = Instant::now();
+    let result = query.await;
+    metrics::observe_db_query(started.elapsed());
+    result
+}
+
+/// Соединение одной функции db. Повторяет нужные ей методы Client, и каждый запрос
+/// попадает в метрику длительности запросов.
+struct Db(Client);
+
+impl Db {
+    async fn query<T: ?Sized + ToStatement>(&self, statement: &T, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, tokio_postgres::Error> {
+        timed(self.0.query(statement, params)).await
+    }
+
+    async fn query_one<T: ?Sized + ToStatement>(&self, statement: &T, params: &[&(dyn ToSql + Sync)]) -> Result<Row, tokio_postgres::Error> {
+        timed(self.0.query_one(statement, params)).await
+    }
+
+    async fn query_opt<T: ?Sized + ToStatement>(&self, statement: &T, params: &[&(dyn ToSql + Sync)]) -> Result<Option<Row>, tokio_postgres::Error> {
+        timed(self.0.query_opt(statement, params)).await
+    }
+
+    /// Время до первой строки: чтение потока зависит от того, кто его читает
+    async fn query_raw<T, P, I>(&self, statement: &T, params: I) -> Result<RowStream, tokio_postgres::Error>
+    where
+        T: ?Sized + ToStatement,
+        P: BorrowToSql,
+        I: IntoIterator<Item = P>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        timed(self.0.query_raw(statement, params)).await
+    }
+
+    async fn execute<T: ?Sized + ToStatement>(&self, statement: &T, params: &[&(dyn ToSql + Sync)]) -> Result<u64, tokio_postgres::Error> {
+        timed(self.0.execute(statement, params)).await
+    }
+
+    async fn batch_execute(&self, query: &str) -> Result<(), tokio_postgres::Error> {
+        timed(self.0.batch_execute(query)).await
+    }
+
+    async fn simple_query(&self, query: &str) -> Result<Vec<SimpleQueryMessage>, tokio_postgres::Error> {
+        timed(self.0.simple_query(query)).await
+    }
+
+    async fn transaction(&mut self) -> Result<DbTransaction<'_>, tokio_postgres::Error> {
+        Ok(DbTransaction(timed(self.0.transaction()).await?))
+    }
+}
+
+/// Транзакция соединения Db; запросы в ней тоже попадают в метрику
+struct DbTransaction<'a>(Transaction<'a>);
+
+impl DbTransaction<'_> {
+    async fn query_one<T: ?Sized + ToStatement>(&self, statement: &T, params: &[&(dyn ToSql + Sync)]) -> Result<Row, tokio_postgres::Error> {
+        timed(self.0.query_one(statement, params)).await
+    }
+
+    async fn query_opt<T: ?Sized + ToStatement>(&self, statement: &T, params: &[&(dyn ToSql + Sync)]) -> Result<Option<Row>, tokio_postgres::Error> {
+        timed(self.0.query_opt(statement, params)).await
+    }
+
+    async fn execute<T: ?Sized + ToStatement>(&self, statement: &T, params: &[&(dyn ToSql + Sync)]) -> Result<u64, tokio_postgres::Error> {
+        timed(self.0.execute(statement, params)).await
+    }
+
+    async fn batch_execute(&self, query: &str) -> Result<(), tokio_postgres::Error> {
+        timed(self.0.batch_execute(query)).await
+    }
 
-    fn to_sql(&self, _type: &Type, out: &mut BytesMut) -> Result<tokio_postgres::types::IsNull, Box<dyn StdError + Sync + Send>> {
-        let timestamp = self.0.timestamp();
-        (timestamp).to_sql(_type, out).map_err(|e| e.into())
+    async fn commit(self) -> Result<(), tokio_postgres::Error> {
+        timed(self.0.commit()).await
     }
 }
 
-/// Сохраняет сообщение в базу данных
-pub async fn save_message_to_db(message: &str, user_uuid: Uuid) -> Result<(), Box<dyn StdError + Send + Sync>> {
+/// Открывает соединение с базой данных
+async fn connect() -> Result<Db, Box<dyn StdError + Send + Sync>> {
     let (client, connection) =
-        tokio_postgres::connect("host=localhost user=cyb3ria password=!Abs123 dbname=cyb3ria_db", NoTls)
-            .await
-            .expect("Failed to connect to database");
+        tokio_postgres::connect(&config::get().database.url, NoTls).await?;
 
+    OPEN_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
     tokio::spawn(async move {
         if let Err(e) = connection.await {
             eprintln!("connection error: {}", e);
         }
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
     });
 
-    debug!("Saving message to database: {}, from user: {}", message, user_uuid);
-
-    client.execute(
-        "INSERT INTO messages (message, user_uuid) VALUES ($1, $2)",
-        &[&message, &user_uuid],
-    )
-    .await?;
-
-    Ok(())
+    Ok(Db(client))
 }
 
-/// Отправляет историю сообщений клиенту
-pub async fn send_message_history(client_ws_sender: Arc<TokioMutex<SplitSink<WebSocket, warp::ws::Message>>>) -> Result<(), Box<dyn StdError + Send + Sync>> {
+/// Долгоживущее соединение (рассылка событий между экземплярами, см. fanout). Его запросы
+/// не учитываются в метриках запросов к базе данных.
+pub async fn connect_dedicated() -> Result<Client, Box<dyn StdError + Send + Sync>> {
     let (client, connection) =
-        tokio_postgres::connect("host=localhost user=cyb3ria password=!Abs123 dbname=cyb3ria_db", NoTls)
-            .await
-            .expect("Failed to connect to database");
+        tokio_postgres::connect(&config::get().database.url, NoTls).await?;
 
+    OPEN_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
     tokio::spawn(async move {
         if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
+            error!("Dedicated connection error: {}", e);
         }
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
     });
 
-    debug!("Fetching message history from database");
-
-    let rows = client.query("SELECT message, user_uuid FROM messages ORDER BY timestamp ASC", &[]).await?;
-
-    for row in rows {
-        let message: String = row.get(0);
-         // Получаем user_uuid, обрабатывая возможность NULL значения
-        let user_uuid: Option<Uuid> = row.get(1);
-        
-         let formatted_message = match user_uuid {
-            Some(uuid) => {
-                // Получаем имя пользователя по user_uuid
-                let username_result = client.query_one("SELECT username FROM users WHERE user_uuid = $1", &[&uuid]).await;
-                 match username_result {
-                    Ok(row) => {
-                         let username: String = row.get(0);
-                         format!("{}: {}", username, message)
-                     },
-                    Err(e) => {
-                       error!("Failed to get username from database: {}", e);
-                         format!("Unknown User: {}", message)
+    Ok(client)
+}
+
+/// Соединение, подписанное через LISTEN на канал. Полезная нагрузка уведомлений приходит
+/// в возвращаемый приемник; он закрывается, когда соединение обрывается.
+pub async fn listen(channel: &str) -> Result<(Client, tokio::sync::mpsc::UnboundedReceiver<String>), Box<dyn StdError + Send + Sync>> {
+    let (client, mut connection) =
+        tokio_postgres::connect(&config::get().database.url, NoTls).await?;
+    let (notifications, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+    OPEN_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+    tokio::spawn(async move {
+        let mut messages = futures_util::stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(notification)) => {
+                    if notifications.send(notification.payload().to_string()).is_err() {
+                        break;
                     }
                 }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Listen connection error: {}", e);
+                    break;
+                }
             }
-             None => format!("Unknown User: {}", message), // Обработка NULL значения
-        };
-        
-        if let Err(e) = client_ws_sender.lock().await.send(warp::ws::Message::text(formatted_message)).await {
-            error!("Failed to send message history: {}", e);
-            return Err(Box::new(e));
         }
-    }
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+    });
+
+    client.batch_execute(&format!("LISTEN {}", channel)).await?;
+
+    Ok((client, receiver))
+}
+
+/// Сколько соединений с базой данных сейчас открыто
+pub fn open_connections() -> usize {
+    OPEN_CONNECTIONS.load(Ordering::SeqCst)
+}
+
+/// Проверяет, что база данных доступна и отвечает на запросы
+pub async fn ping() -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    client.simple_query("SELECT 1").await?;
 
     Ok(())
 }
 
-/// Сохраняет пользователя в базу данных
-pub async fn save_user_to_db(user: User) -> Result<(), Box<dyn StdError + Send + Sync>> {
-    let (client, connection) =
-        tokio_postgres::connect("host=localhost user=cyb3ria password=!Abs123 dbname=cyb3ria_db", NoTls)
-            .await
-            .expect("Failed to connect to database");
+/// Создает недостающие таблицы и колонки (см. schema.sql)
+pub async fn init_schema() -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
 
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
-        }
-    });
+    debug!("Applying database schema");
 
-    debug!("Saving user to database: {}", user.username);
+    client.batch_execute(include_str!("../schema.sql")).await?;
 
-    client.execute(
-        "INSERT INTO users (username, password_hash, invitation_code, user_uuid) VALUES ($1, $2, $3, $4)",
-        &[&user.username, &user.password_hash, &user.invitation_code, &user.user_uuid],
+    Ok(())
+}
+
+// Колонки сообщения вместе со сводкой ответов, реакций и вложений; строка собирается в ChatMessage
+// через message_from_row. Параметр $1 — пользователь, для которого отмечаются его реакции.
+// Сообщения без автора (user_uuid = NULL) показываем как "Unknown User".
+const MESSAGE_SELECT: &str =
+    "SELECT m.id, COALESCE(u.username, 'Unknown User'), m.message, m.timestamp, m.edited_at, m.parent_id, \
+            t.reply_count, t.last_reply_at, rx.reactions, m.message_html, \
+            p.url, p.title, p.description, p.image, p.site_name, a.attachments \
+     FROM messages m \
+     LEFT JOIN users u ON u.user_uuid = m.user_uuid \
+     LEFT JOIN link_previews p ON p.url = m.preview_url \
+     LEFT JOIN LATERAL ( \
+         SELECT count(*) AS reply_count, max(r.timestamp) AS last_reply_at \
+         FROM messages r WHERE r.parent_id = m.id AND r.deleted_at IS NULL \
+     ) t ON true \
+     LEFT JOIN LATERAL ( \
+         SELECT COALESCE(jsonb_agg(jsonb_build_object('emoji', g.emoji, 'count', g.count, 'reacted', g.reacted) \
+                                   ORDER BY g.first_at), '[]'::jsonb) AS reactions \
+         FROM ( \
+             SELECT emoji, count(*) AS count, bool_or(user_uuid = $1) AS reacted, min(created_at) AS first_at \
+             FROM reactions WHERE message_id = m.id GROUP BY emoji \
+         ) g \
+     ) rx ON true \
+     LEFT JOIN LATERAL ( \
+         SELECT COALESCE(jsonb_agg(jsonb_build_object('file_name', file_name, 'content_type', content_type, \
+                                                      'size', size, 'path', path) \
+                                   ORDER BY id), '[]'::jsonb) AS attachments \
+         FROM attachments WHERE message_id = m.id \
+     ) a ON true";
+
+fn message_from_row(row: &Row) -> ChatMessage {
+    let reactions: serde_json::Value = row.get(8);
+    let message: String = row.get(2);
+    let html: Option<String> = row.get(9);
+    let preview_url: Option<String> = row.get(10);
+    let attachments: Vec<AttachmentRow> = serde_json::from_value(row.get(15)).unwrap_or_default();
+    ChatMessage {
+        id: row.get(0),
+        username: row.get(1),
+        html: html.unwrap_or_else(|| markdown::render(&message)),
+        message,
+        timestamp: row.get(3),
+        edited_at: row.get(4),
+        parent_id: row.get(5),
+        reply_count: row.get(6),
+        last_reply_at: row.get(7),
+        reactions: serde_json::from_value(reactions).unwrap_or_default(),
+        preview: preview_url
+            .map(|url| LinkPreview { url, title: row.get(11), description: row.get(12), image: row.get(13), site_name: row.get(14) })
+            .filter(|preview| !preview.is_empty()),
+        attachments: attachments.into_iter().map(AttachmentRow::into_attachment).collect(),
+    }
+}
+
+/// Сохраняет сообщение (исходный текст и отрисованный HTML) в базу данных и возвращает его идентификатор и время
+pub async fn save_message_to_db(message: &str, html: &str, user_uuid: Uuid, room: &str, parent_id: Option<i64>, attachments: &[i64]) -> Result<(i64, DateTime<Utc>, Vec<Attachment>), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Saving message to database: {}, from user: {}, room: {}, attachments: {:?}", message, user_uuid, room, attachments);
+
+    // Прикрепить можно только свои загрузки, еще не прикрепленные к другому сообщению;
+    // остальные id пропускаются
+    let row = client.query_one(
+        "WITH saved AS ( \
+             INSERT INTO messages (message, message_html, user_uuid, room, parent_id) VALUES ($1, $2, $3, $4, $5) \
+             RETURNING id, timestamp \
+         ), attached AS ( \
+             UPDATE attachments SET message_id = saved.id FROM saved \
+             WHERE attachments.id = ANY($6) AND attachments.uploaded_by = $3 AND attachments.message_id IS NULL \
+             RETURNING attachments.id, file_name, content_type, size, path \
+         ) \
+         SELECT saved.id, saved.timestamp, \
+                (SELECT COALESCE(jsonb_agg(jsonb_build_object('file_name', file_name, 'content_type', content_type, \
+                                                              'size', size, 'path', path) \
+                                           ORDER BY id), '[]'::jsonb) FROM attached) \
+         FROM saved",
+        &[&message, &html, &user_uuid, &room, &parent_id, &attachments],
     )
     .await?;
 
-    Ok(())
+    let attached: Vec<AttachmentRow> = serde_json::from_value(row.get(2))?;
+    Ok((row.get(0), row.get(1), attached.into_iter().map(AttachmentRow::into_attachment).collect()))
 }
 
-/// Ищет пользователя по имени
-pub async fn find_user_by_username(username: &str) -> Result<User, Box<dyn StdError + Send + Sync>> {
-    let (client, connection) =
-        tokio_postgres::connect("host=localhost user=cyb3ria password=!Abs123 dbname=cyb3ria_db", NoTls)
-            .await
-            .expect("Failed to connect to database");
+/// Записывает загруженный файл, еще не прикрепленный к сообщению. Возвращает id вложения.
+pub async fn save_upload(uploaded_by: Uuid, path: &str, file_name: &str, content_type: Option<&str>, size: i64) -> Result<i64, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
 
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
-        }
-    });
+    debug!("Saving upload {} ({} bytes) by {}", path, size, uploaded_by);
 
-    debug!("Finding user in database by username: {}", username);
+    let row = client.query_one(
+        "INSERT INTO attachments (uploaded_by, path, file_name, content_type, size) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        &[&uploaded_by, &path, &file_name, &content_type, &size],
+    )
+    .await?;
 
-    let row = client
-        .query_one("SELECT username, password_hash, invitation_code, user_uuid FROM users WHERE username = $1", &[&username])
-        .await?;
+    Ok(row.get(0))
+}
 
-    let user = User {
-        username: row.get(0),
-        password_hash: row.get(1),
-        invitation_code: row.get(2),
-        user_uuid: row.get(3),
-    };
+/// История комнаты: только корневые сообщения со сводкой ответов и реакций пользователя viewer.
+/// Удаленные модераторами сообщения пропускаются.
+pub async fn find_message_history(room: &str, viewer: Uuid) -> Result<Vec<ChatMessage>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Fetching message history of room {} from database", room);
+
+    let query = format!("{} WHERE m.deleted_at IS NULL AND m.parent_id IS NULL AND m.room = $2 ORDER BY m.timestamp ASC, m.id ASC", MESSAGE_SELECT);
+    let rows = client.query(query.as_str(), &[&viewer, &room]).await?;
 
-    Ok(user)
+    Ok(rows.iter().map(message_from_row).collect())
 }
 
-/// Сохраняет устройство в базу данных
-pub async fn save_device_to_db(device: Device) -> Result<(), Box<dyn StdError + Send + Sync>> {
-    let (client, connection) =
-        tokio_postgres::connect("host=localhost user=cyb3ria password=!Abs123 dbname=cyb3ria_db", NoTls)
-            .await
-            .expect("Failed to connect to database");
+/// Возвращает id корня ветки, к которой относится сообщение комнаты `room`. Ответ на ответ
+/// попадает в ту же ветку, поэтому ветки одноуровневые. None, если сообщения нет, оно удалено
+/// или находится в другой комнате.
+pub async fn find_thread_root(message_id: i64, room: &str) -> Result<Option<i64>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
 
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
-        }
-    });
+    let row = client.query_opt(
+        "SELECT COALESCE(parent_id, id) FROM messages WHERE id = $1 AND room = $2 AND deleted_at IS NULL",
+        &[&message_id, &room],
+    )
+    .await?;
 
-    debug!("Saving device to database: {:?}", device);
+    Ok(row.map(|row| row.get(0)))
+}
 
-    // Преобразуем IP-адрес в тип IpAddr
-    let ip_address: IpAddr = device.ip_address.parse().map_err(|e| {
-        error!("Failed to parse IP address: {}", e);
-        e
-    })?;
+/// Проверяет, что неудаленное сообщение находится в комнате `room`
+pub async fn message_in_room(message_id: i64, room: &str) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
 
-    client.execute(
-        "INSERT INTO devices (device_id, user_uuid, ip_address) VALUES ($1, $2, $3)",
-        &[&device.device_id, &device.user_uuid, &ip_address],
+    let row = client.query_one(
+        "SELECT EXISTS (SELECT 1 FROM messages WHERE id = $1 AND room = $2 AND deleted_at IS NULL)",
+        &[&message_id, &room],
     )
     .await?;
 
-    Ok(())
+    Ok(row.get(0))
 }
 
-/// Ищет устройство по IP-адресу
-pub async fn find_device_by_ip_mac(ip_address: &str, mac_address: Option<&str>) -> Result<Option<Device>, Box<dyn StdError + Send + Sync>> {
-    let (client, connection) =
-        tokio_postgres::connect("host=localhost user=cyb3ria password=!Abs123 dbname=cyb3ria_db", NoTls)
-            .await
-            .expect("Failed to connect to database");
+/// Проверяет, что комната существует
+pub async fn room_exists(room: &str) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
 
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
-        }
-    });
+    let row = client.query_one("SELECT EXISTS (SELECT 1 FROM rooms WHERE name = $1)", &[&room]).await?;
 
-    debug!("Finding device by IP: {}", ip_address);
+    Ok(row.get(0))
+}
 
-    // Преобразуем IP-адрес в тип IpAddr
-    let ip_addr: IpAddr = ip_address.parse().map_err(|e| {
-        error!("Failed to parse IP address: {}", e);
-        e
-    })?;
+/// Делает пользователя участником комнаты. Возвращает false, если он уже участник.
+pub async fn join_room(room: &str, user_uuid: Uuid) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
 
+    let inserted = client.execute(
+        "INSERT INTO room_members (room, user_uuid) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        &[&room, &user_uuid],
+    )
+    .await?;
 
-    let row = client.query_opt(
-            "SELECT device_id, user_uuid, ip_address FROM devices WHERE ip_address = $1",
-            &[&ip_addr],
-        )
-        .await?;
-    
+    Ok(inserted > 0)
+}
 
-    if let Some(row) = row {
-        let device = Device {
-            device_id: row.get(0),
-            user_uuid: row.get(1),
-            ip_address: row.get::<_, IpAddr>(2).to_string(),  // Преобразуем обратно в строку
-           
-        };
-        Ok(Some(device))
-    } else {
-        Ok(None)
+/// Исключает пользователя из комнаты вместе с его отметкой прочтения, иначе при следующем
+/// запуске schema.sql вернул бы его в участники. Возвращает false, если он не был участником.
+pub async fn leave_room(room: &str, user_uuid: Uuid) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let row = client.query_one(
+        "WITH removed AS (DELETE FROM room_members WHERE room = $1 AND user_uuid = $2 RETURNING 1), \
+         markers AS (DELETE FROM read_markers WHERE room = $1 AND user_uuid = $2) \
+         SELECT EXISTS (SELECT 1 FROM removed)",
+        &[&room, &user_uuid],
+    )
+    .await?;
+
+    Ok(row.get(0))
+}
+
+/// Проверяет, что пользователь — участник комнаты
+pub async fn is_room_member(room: &str, user_uuid: Uuid) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let row = client.query_one(
+        "SELECT EXISTS (SELECT 1 FROM room_members WHERE room = $1 AND user_uuid = $2)",
+        &[&room, &user_uuid],
+    )
+    .await?;
+
+    Ok(row.get(0))
+}
+
+/// Создает комнату. Возвращает false, если комната с таким именем уже есть.
+pub async fn save_room_to_db(room: &str) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let inserted = client.execute(
+        "INSERT INTO rooms (name) VALUES ($1) ON CONFLICT (name) DO NOTHING",
+        &[&room],
+    )
+    .await?;
+
+    Ok(inserted > 0)
+}
+
+/// Сдвигает отметку прочтения пользователя в комнате вперед до сообщения message_id.
+/// Возвращает false, если сообщение не из этой комнаты или отметка уже дальше.
+pub async fn save_read_marker(user_uuid: Uuid, room: &str, message_id: i64) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let updated = client.execute(
+        "INSERT INTO read_markers (user_uuid, room, last_read_id) \
+         SELECT $1, room, id FROM messages WHERE id = $3 AND room = $2 \
+         ON CONFLICT (user_uuid, room) DO UPDATE SET last_read_id = EXCLUDED.last_read_id, updated_at = now() \
+         WHERE read_markers.last_read_id < EXCLUDED.last_read_id",
+        &[&user_uuid, &room, &message_id],
+    )
+    .await?;
+
+    Ok(updated > 0)
+}
+
+/// Считает непрочитанные сообщения пользователя во всех его комнатах. Собственные сообщения
+/// и ответы в ветках не считаются.
+pub async fn find_unread_counts(user_uuid: Uuid) -> Result<Vec<RoomUnread>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let rows = client.query(
+        "SELECT r.name, rm.last_read_id, \
+                (SELECT count(*) FROM messages m \
+                 WHERE m.room = r.name AND m.deleted_at IS NULL AND m.parent_id IS NULL \
+                   AND m.id > COALESCE(rm.last_read_id, 0) AND m.user_uuid IS DISTINCT FROM $1) \
+         FROM rooms r \
+         JOIN room_members mem ON mem.room = r.name AND mem.user_uuid = $1 \
+         LEFT JOIN read_markers rm ON rm.room = r.name AND rm.user_uuid = $1 \
+         ORDER BY r.name",
+        &[&user_uuid],
+    )
+    .await?;
+
+    Ok(rows.iter().map(|row| RoomUnread {
+        room: row.get(0),
+        last_read_id: row.get(1),
+        unread: row.get(2),
+    }).collect())
+}
+
+/// Загружает ответы ветки в порядке отправки
+pub async fn find_thread_replies(root_id: i64, viewer: Uuid) -> Result<Vec<ChatMessage>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let query = format!("{} WHERE m.deleted_at IS NULL AND m.parent_id = $2 ORDER BY m.timestamp ASC, m.id ASC", MESSAGE_SELECT);
+    let rows = client.query(query.as_str(), &[&viewer, &root_id]).await?;
+
+    Ok(rows.iter().map(message_from_row).collect())
+}
+
+/// Возвращает число ответов в ветке, время последнего ответа и участников ветки
+/// (автора корневого сообщения и всех, кто отвечал)
+pub async fn find_thread_summary(root_id: i64) -> Result<(i64, Option<DateTime<Utc>>, Vec<Uuid>), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let row = client.query_one(
+        "SELECT count(*), max(timestamp) FROM messages WHERE parent_id = $1 AND deleted_at IS NULL",
+        &[&root_id],
+    )
+    .await?;
+
+    let participants = client.query(
+        "SELECT DISTINCT user_uuid FROM messages WHERE (id = $1 OR parent_id = $1) AND user_uuid IS NOT NULL",
+        &[&root_id],
+    )
+    .await?
+    .iter()
+    .map(|row| row.get(0))
+    .collect();
+
+    Ok((row.get(0), row.get(1), participants))
+}
+
+/// Ставит реакцию на сообщение. Возвращает false, если сообщения нет, оно удалено
+/// или пользователь уже поставил такую реакцию.
+pub async fn add_reaction(message_id: i64, user_uuid: Uuid, emoji: &str) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let inserted = client.execute(
+        "INSERT INTO reactions (message_id, user_uuid, emoji) \
+         SELECT id, $2, $3 FROM messages WHERE id = $1 AND deleted_at IS NULL \
+         ON CONFLICT DO NOTHING",
+        &[&message_id, &user_uuid, &emoji],
+    )
+    .await?;
+
+    Ok(inserted > 0)
+}
+
+/// Снимает реакцию пользователя. Возвращает false, если такой реакции не было.
+pub async fn remove_reaction(message_id: i64, user_uuid: Uuid, emoji: &str) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let deleted = client.execute(
+        "DELETE FROM reactions WHERE message_id = $1 AND user_uuid = $2 AND emoji = $3",
+        &[&message_id, &user_uuid, &emoji],
+    )
+    .await?;
+
+    Ok(deleted > 0)
+}
+
+/// Возвращает число реакций с данным эмодзи на сообщении
+pub async fn count_reactions(message_id: i64, emoji: &str) -> Result<i64, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let row = client.query_one(
+        "SELECT count(*) FROM reactions WHERE message_id = $1 AND emoji = $2",
+        &[&message_id, &emoji],
+    )
+    .await?;
+
+    Ok(row.get(0))
+}
+
+/// Редактирует сообщение автора в комнате `room`, если с момента отправки прошло не больше `window`.
+/// Предыдущая версия текста сохраняется в message_edits.
+pub async fn edit_message(message_id: i64, room: &str, author_uuid: Uuid, new_message: &str, new_html: &str, window: chrono::Duration) -> Result<EditOutcome, Box<dyn StdError + Send + Sync>> {
+    let mut client = connect().await?;
+
+    debug!("Editing message {} by {}", message_id, author_uuid);
+
+    let transaction = client.transaction().await?;
+
+    let row = transaction.query_opt(
+        "SELECT message, user_uuid, timestamp FROM messages WHERE id = $1 AND room = $2 AND deleted_at IS NULL FOR UPDATE",
+        &[&message_id, &room],
+    )
+    .await?;
+
+    let Some(row) = row else {
+        return Ok(EditOutcome::NotFound);
+    };
+
+    let previous_message: String = row.get(0);
+    let user_uuid: Option<Uuid> = row.get(1);
+    let timestamp: DateTime<Utc> = row.get(2);
+
+    if user_uuid != Some(author_uuid) {
+        return Ok(EditOutcome::NotAuthor);
     }
+    if Utc::now() - timestamp > window {
+        return Ok(EditOutcome::WindowExpired);
+    }
+
+    transaction.execute(
+        "INSERT INTO message_edits (message_id, previous_message) VALUES ($1, $2)",
+        &[&message_id, &previous_message],
+    )
+    .await?;
+
+    let edited_at: DateTime<Utc> = transaction
+        .query_one(
+            "UPDATE messages SET message = $2, message_html = $3, edited_at = now(), preview_url = NULL WHERE id = $1 RETURNING edited_at",
+            &[&message_id, &new_message, &new_html],
+        )
+        .await?
+        .get(0);
+
+    transaction.commit().await?;
+
+    Ok(EditOutcome::Edited { edited_at })
 }
 
-/// Сохраняет сессию в базу данных
-pub async fn save_session_to_db(session: Session) -> Result<(), Box<dyn StdError + Send + Sync>> {
-    let (client, connection) =
-        tokio_postgres::connect("host=localhost user=cyb3ria password=!Abs123 dbname=cyb3ria_db", NoTls)
-            .await
-            .expect("Failed to connect to database");
+/// Помечает сообщение удаленным, снимает его закрепление и возвращает комнату.
+/// None, если сообщения нет или оно уже удалено.
+pub async fn soft_delete_message(message_id: i64, deleted_by: Uuid) -> Result<Option<String>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
 
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
-        }
-    });
+    debug!("Soft-deleting message {} by {}", message_id, deleted_by);
 
-    debug!("Saving session to database: {:?}", session);
+    let row = client.query_opt(
+        "WITH deleted AS ( \
+             UPDATE messages SET deleted_at = now(), deleted_by = $2 WHERE id = $1 AND deleted_at IS NULL RETURNING id, room \
+         ), unpinned AS ( \
+             DELETE FROM pins WHERE message_id IN (SELECT id FROM deleted) \
+         ) \
+         SELECT room FROM deleted",
+        &[&message_id, &deleted_by],
+    )
+    .await?;
+
+    Ok(row.map(|row| row.get(0)))
+}
+
+/// Заглушает пользователя до указанного времени (повторное заглушение перезаписывает срок)
+pub async fn save_mute_to_db(user_uuid: Uuid, muted_until: DateTime<Utc>, muted_by: Uuid) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
 
-    let expires_at = session.expires_at.map(Timestamp);
+    debug!("Muting user {} until {}", user_uuid, muted_until);
 
     client.execute(
-        "INSERT INTO sessions (session_id, user_uuid, device_id, expires_at) VALUES ($1, $2, $3, $4)",
-        &[&session.session_id, &session.user_uuid, &session.device_id, &expires_at],
+        "INSERT INTO mutes (user_uuid, muted_until, muted_by) VALUES ($1, $2, $3) \
+         ON CONFLICT (user_uuid) DO UPDATE SET muted_until = EXCLUDED.muted_until, muted_by = EXCLUDED.muted_by, created_at = now()",
+        &[&user_uuid, &muted_until, &muted_by],
     )
     .await?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Возвращает время окончания действующего заглушения пользователя, если оно есть
+pub async fn find_active_mute(user_uuid: Uuid) -> Result<Option<DateTime<Utc>>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let row = client.query_opt(
+        "SELECT muted_until FROM mutes WHERE user_uuid = $1 AND muted_until > now()",
+        &[&user_uuid],
+    )
+    .await?;
+
+    Ok(row.map(|row| row.get(0)))
+}
+
+/// Сохраняет бан и возвращает его идентификатор
+pub async fn save_ban_to_db(ban: &Ban) -> Result<i64, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Saving ban to database: {:?}", ban);
+
+    let row = client.query_one(
+        "INSERT INTO bans (user_uuid, ip, reason, created_by, expires_at) VALUES ($1, $2::text::inet, $3, $4, $5) RETURNING id",
+        &[&ban.user_uuid, &ban.ip, &ban.reason, &ban.created_by, &ban.expires_at],
+    )
+    .await?;
+
+    Ok(row.get(0))
+}
+
+fn ban_from_row(row: &Row) -> Ban {
+    Ban {
+        id: row.get(0),
+        user_uuid: row.get(1),
+        ip: row.get(2),
+        reason: row.get(3),
+        created_by: row.get(4),
+        expires_at: row.get(5),
+    }
+}
+
+/// Ищет действующий бан пользователя или IP-адреса (в том числе бан подсети).
+/// Бан по адресу не действует на пользователя с ролью не ниже, чем у создателя бана.
+pub async fn find_active_ban(user_uuid: Option<Uuid>, ip: Option<IpAddr>) -> Result<Option<Ban>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let row = client.query_opt(
+        "SELECT b.id, b.user_uuid, abbrev(b.ip), b.reason, b.created_by, b.expires_at FROM bans b \
+         WHERE (b.expires_at IS NULL OR b.expires_at > now()) \
+           AND (b.user_uuid = $1 OR (b.ip >>= $2 AND NOT EXISTS ( \
+               SELECT 1 FROM users u JOIN roles ur ON ur.name = u.role, users c JOIN roles cr ON cr.name = c.role \
+               WHERE u.user_uuid = $1 AND c.user_uuid = b.created_by AND ur.rank >= cr.rank))) \
+         ORDER BY b.expires_at DESC NULLS FIRST LIMIT 1",
+        &[&user_uuid, &ip],
+    )
+    .await?;
+
+    Ok(row.map(|row| ban_from_row(&row)))
+}
+
+/// Действующий бан по идентификатору и роль его создателя
+pub async fn find_active_ban_by_id(ban_id: i64) -> Result<Option<(Ban, Role)>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let row = client.query_opt(
+        "SELECT b.id, b.user_uuid, abbrev(b.ip), b.reason, b.created_by, b.expires_at, COALESCE(c.role, 'member') FROM bans b \
+         LEFT JOIN users c ON c.user_uuid = b.created_by \
+         WHERE b.id = $1 AND (b.expires_at IS NULL OR b.expires_at > now())",
+        &[&ban_id],
+    )
+    .await?;
+
+    match row {
+        Some(row) => {
+            let role: String = row.get(6);
+            Ok(Some((ban_from_row(&row), role.parse::<Role>()?)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Снимает бан досрочно. Возвращает false, если бана нет.
+pub async fn delete_ban(ban_id: i64) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Lifting ban: {}", ban_id);
+
+    let updated = client.execute(
+        "UPDATE bans SET expires_at = now() WHERE id = $1 AND (expires_at IS NULL OR expires_at > now())",
+        &[&ban_id],
+    )
+    .await?;
+
+    Ok(updated > 0)
+}
+
+/// Сохраняет пользователя в базу данных и возвращает назначенную ему роль.
+/// Первый зарегистрированный пользователь всегда становится администратором.
+pub async fn save_user_to_db(user: User) -> Result<Role, Box<dyn StdError + Send + Sync>> {
+    let mut client = connect().await?;
+
+    debug!("Saving user to database: {}", user.username);
+
+    let transaction = client.transaction().await?;
+
+    // Блокировка не дает двум одновременным регистрациям обеим стать "первыми"
+    transaction.batch_execute("LOCK TABLE users IN SHARE ROW EXCLUSIVE MODE").await?;
+
+    let has_users = transaction.query_one("SELECT EXISTS (SELECT 1 FROM users)", &[]).await?.get::<_, bool>(0);
+    let role = if has_users { user.role } else { Role::Admin };
+
+    transaction.execute(
+        "INSERT INTO users (username, password_hash, invitation_code, user_uuid, role) VALUES ($1, $2, $3, $4, $5)",
+        &[&user.username, &user.password_hash, &user.invitation_code, &user.user_uuid, &role.as_str()],
+    )
+    .await?;
+
+    // Комната general открыта всем: новый пользователь сразу становится ее участником
+    transaction.execute(
+        "INSERT INTO room_members (room, user_uuid) VALUES ('general', $1) ON CONFLICT DO NOTHING",
+        &[&user.user_uuid],
+    )
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(role)
+}
+
+/// Ищет пользователя по имени
+pub async fn find_user_by_username(username: &str) -> Result<User, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Finding user in database by username: {}", username);
+
+    let row = client
+        .query_one(&format!("SELECT {} FROM users WHERE username = $1", USER_COLUMNS), &[&username])
+        .await?;
+
+    user_from_row(&row)
+}
+
+/// Ищет пользователя по идентификатору действующей (не истекшей) сессии
+pub async fn find_user_by_session(session_id: Uuid) -> Result<Option<User>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Finding user by session: {}", session_id);
+
+    let row = client
+        .query_opt(
+            "SELECT u.username, u.password_hash, u.invitation_code, u.user_uuid, u.role FROM sessions s \
+             JOIN users u ON u.user_uuid = s.user_uuid \
+             WHERE s.session_id = $1 AND (s.expires_at IS NULL OR s.expires_at > now())",
+            &[&session_id],
+        )
+        .await?;
+
+    row.map(|row| user_from_row(&row)).transpose()
+}
+
+/// Меняет роль пользователя. Возвращает uuid пользователя и его прежнюю роль
+/// либо None, если пользователь не найден.
+pub async fn set_user_role(username: &str, role: Role) -> Result<Option<(Uuid, Role)>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Setting role {} for user: {}", role, username);
+
+    let row = client.query_opt(
+        "UPDATE users u SET role = $1 FROM users old \
+         WHERE u.user_uuid = old.user_uuid AND u.username = $2 \
+         RETURNING u.user_uuid, old.role",
+        &[&role.as_str(), &username],
+    )
+    .await?;
+
+    match row {
+        Some(row) => {
+            let previous: String = row.get(1);
+            Ok(Some((row.get(0), previous.parse::<Role>()?)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Обновляет хэш пароля пользователя
+pub async fn update_password_hash(user_uuid: Uuid, password_hash: &str) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Updating password hash for user: {}", user_uuid);
+
+    client.execute(
+        "UPDATE users SET password_hash = $1 WHERE user_uuid = $2",
+        &[&password_hash, &user_uuid],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Время окончания блокировки входа пользователя; None, если вход не заблокирован
+pub async fn find_login_lockout(user_uuid: Uuid) -> Result<Option<DateTime<Utc>>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let row = client.query_opt(
+        "SELECT locked_until FROM login_lockouts WHERE user_uuid = $1 AND locked_until > now()",
+        &[&user_uuid],
+    )
+    .await?;
+
+    Ok(row.map(|row| row.get(0)))
+}
+
+/// Учитывает неудачную попытку входа. Попытки старше lockout_secs и попытки до истекшей
+/// блокировки не считаются, попытки во время блокировки ее не продлевают. Возвращает время
+/// окончания блокировки, если именно эта попытка стала max_attempts-й и заблокировала вход.
+pub async fn record_login_failure(user_uuid: Uuid, max_attempts: i32, lockout_secs: f64) -> Result<Option<DateTime<Utc>>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let row = client.query_one(
+        "WITH previous AS (SELECT failed_attempts, last_failed_at, locked_until FROM login_lockouts WHERE user_uuid = $1), \
+              attempt AS ( \
+                  SELECT CASE WHEN p.locked_until > now() THEN p.failed_attempts \
+                              WHEN p.last_failed_at >= now() - make_interval(secs => $3) AND p.locked_until IS NULL \
+                                  THEN p.failed_attempts + 1 \
+                              ELSE 1 END AS failed_attempts, \
+                         p.locked_until > now() AS locked \
+                  FROM (SELECT 1) one LEFT JOIN previous p ON true) \
+         INSERT INTO login_lockouts (user_uuid, failed_attempts, last_failed_at, locked_until) \
+         SELECT $1, a.failed_attempts, now(), \
+                CASE WHEN a.locked THEN (SELECT locked_until FROM previous) \
+                     WHEN a.failed_attempts >= $2 THEN now() + make_interval(secs => $3) END \
+         FROM attempt a \
+         ON CONFLICT (user_uuid) DO UPDATE SET failed_attempts = EXCLUDED.failed_attempts, \
+             last_failed_at = EXCLUDED.last_failed_at, locked_until = EXCLUDED.locked_until \
+         RETURNING locked_until, locked_until = now() + make_interval(secs => $3)",
+        &[&user_uuid, &max_attempts, &lockout_secs],
+    )
+    .await?;
+
+    let locked_now: Option<bool> = row.get(1);
+    Ok(if locked_now == Some(true) { row.get(0) } else { None })
+}
+
+/// Сбрасывает счетчик неудачных попыток входа после успешного входа
+pub async fn clear_login_failures(user_uuid: Uuid) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    client.execute("DELETE FROM login_lockouts WHERE user_uuid = $1", &[&user_uuid]).await?;
+
+    Ok(())
+}
+
+/// Сохраняет устройство в базу данных
+pub async fn save_device_to_db(device: Device) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Saving device to database: {:?}", device);
+
+    // Преобразуем IP-адрес в тип IpAddr
+    let ip_address: IpAddr = device.ip_address.parse().map_err(|e| {
+        error!("Failed to parse IP address: {}", e);
+        e
+    })?;
+
+    client.execute(
+        "INSERT INTO devices (device_id, user_uuid, ip_address) VALUES ($1, $2, $3)",
+        &[&device.device_id, &device.user_uuid, &ip_address],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Ищет устройство по IP-адресу
+pub async fn find_device_by_ip_mac(ip_address: &str, _mac_address: Option<&str>) -> Result<Option<Device>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Finding device by IP: {}", ip_address);
+
+    // Преобразуем IP-адрес в тип IpAddr
+    let ip_addr: IpAddr = ip_address.parse().map_err(|e| {
+        error!("Failed to parse IP address: {}", e);
+        e
+    })?;
+
+
+    let row = client.query_opt(
+            "SELECT device_id, user_uuid, ip_address FROM devices WHERE ip_address = $1",
+            &[&ip_addr],
+        )
+        .await?;
+    
+
+    if let Some(row) = row {
+        let device = Device {
+            device_id: row.get(0),
+            user_uuid: row.get(1),
+            ip_address: row.get::<_, IpAddr>(2).to_string(),  // Преобразуем обратно в строку
+           
+        };
+        Ok(Some(device))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Сохраняет сессию в базу данных
+pub async fn save_session_to_db(session: Session) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Saving session to database: {:?}", session);
+
+    client.execute(
+        "INSERT INTO sessions (session_id, user_uuid, device_id, expires_at) VALUES ($1, $2, $3, $4)",
+        &[&session.session_id, &session.user_uuid, &session.device_id, &session.expires_at],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Удаляет все сессии пользователя. Возвращает число удаленных сессий.
+pub async fn delete_user_sessions(user_uuid: Uuid) -> Result<u64, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Deleting all sessions of user: {}", user_uuid);
+
+    Ok(client.execute("DELETE FROM sessions WHERE user_uuid = $1", &[&user_uuid]).await?)
+}
+
+/// Удаляет сессию (выход из системы). Возвращает uuid владельца, если сессия существовала.
+pub async fn delete_session(session_id: Uuid) -> Result<Option<Uuid>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Deleting session: {}", session_id);
+
+    let row = client
+        .query_opt("DELETE FROM sessions WHERE session_id = $1 RETURNING user_uuid", &[&session_id])
+        .await?;
+
+    Ok(row.map(|row| row.get(0)))
+}
+
+/// Добавляет запись в журнал аудита
+pub async fn save_audit_entry(entry: &AuditEntry) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Saving audit entry: {} (actor: {:?}, target: {:?})", entry.action, entry.actor_uuid, entry.target_uuid);
+
+    client.execute(
+        "INSERT INTO audit_log (actor_uuid, target_uuid, action, ip, details) VALUES ($1, $2, $3, $4::text::inet, $5)",
+        &[&entry.actor_uuid, &entry.target_uuid, &entry.action, &entry.ip, &entry.details],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Возвращает страницу журнала аудита (от новых записей к старым) и общее число подходящих записей.
+/// Фильтр action с завершающей точкой (например, "moderation.") выбирает все действия группы.
+pub async fn find_audit_entries(filter: &AuditFilter, limit: i64, offset: i64) -> Result<(Vec<AuditEntry>, i64), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Fetching audit log: {:?}, limit {}, offset {}", filter, limit, offset);
+
+    let action_pattern = filter.action.as_ref().map(|action| {
+        if action.ends_with('.') {
+            format!("{}%", action.replace('%', "\\%").replace('_', "\\_"))
+        } else {
+            action.replace('%', "\\%").replace('_', "\\_")
+        }
+    });
+
+    // Одинаковые условия для выборки страницы и для подсчета общего числа записей
+    const AUDIT_FROM_WHERE: &str = "FROM audit_log l \
+         LEFT JOIN users a ON a.user_uuid = l.actor_uuid \
+         LEFT JOIN users t ON t.user_uuid = l.target_uuid \
+         WHERE ($1::text IS NULL OR a.username = $1) \
+           AND ($2::text IS NULL OR t.username = $2) \
+           AND ($3::text IS NULL OR l.action LIKE $3) \
+           AND ($4::text IS NULL OR l.ip <<= $4::text::inet) \
+           AND ($5::timestamptz IS NULL OR l.created_at >= $5) \
+           AND ($6::timestamptz IS NULL OR l.created_at < $6)";
+
+    let params: [&(dyn ToSql + Sync); 6] = [&filter.actor, &filter.target, &action_pattern, &filter.ip, &filter.from, &filter.to];
+
+    let total: i64 = client
+        .query_one(&format!("SELECT count(*) {}", AUDIT_FROM_WHERE), &params)
+        .await?
+        .get(0);
+
+    let rows = client.query(
+        &format!(
+            "SELECT l.id, l.created_at, l.actor_uuid, a.username, l.target_uuid, t.username, l.action, abbrev(l.ip), l.details \
+             {} ORDER BY l.id DESC LIMIT $7 OFFSET $8",
+            AUDIT_FROM_WHERE
+        ),
+        &[params[0], params[1], params[2], params[3], params[4], params[5], &limit, &offset],
+    )
+    .await?;
+
+    let entries = rows.iter().map(|row| AuditEntry {
+        id: row.get(0),
+        created_at: row.get(1),
+        actor_uuid: row.get(2),
+        actor: row.get(3),
+        target_uuid: row.get(4),
+        target: row.get(5),
+        action: row.get(6),
+        ip: row.get(7),
+        details: row.get(8),
+    }).collect();
+
+    Ok((entries, total))
+}
+
+// Маркеры начала и конца совпадения в ts_headline. Символы из области частного
+// использования Unicode: их нет в обычном тексте, а из сообщений они вырезаются.
+const HIGHLIGHT_START: char = '\u{E000}';
+const HIGHLIGHT_END: char = '\u{E001}';
+
+/// Полнотекстовый поиск по сообщениям комнат, где пользователь filter.viewer — участник.
+/// Удаленные сообщения не ищутся. Возвращает страницу результатов и общее число найденных.
+pub async fn search_messages(filter: &SearchFilter, limit: i64, offset: i64) -> Result<(Vec<SearchResult>, i64), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    debug!("Searching messages: {:?}", filter);
+
+    // Одинаковые условия для выборки страницы и для подсчета общего числа результатов
+    const SEARCH_FROM_WHERE: &str = "FROM messages m \
+         LEFT JOIN users u ON u.user_uuid = m.user_uuid, \
+         websearch_to_tsquery('simple', $1) q \
+         WHERE m.deleted_at IS NULL \
+           AND to_tsvector('simple', m.message) @@ q \
+           AND ($2::text IS NULL OR u.username = $2) \
+           AND ($3::text IS NULL OR m.room = $3) \
+           AND ($4::timestamptz IS NULL OR m.timestamp >= $4) \
+           AND ($5::timestamptz IS NULL OR m.timestamp < $5) \
+           AND EXISTS (SELECT 1 FROM room_members rm WHERE rm.room = m.room AND rm.user_uuid = $6)";
+
+    let params: [&(dyn ToSql + Sync); 6] = [&filter.query, &filter.author, &filter.room, &filter.from, &filter.to, &filter.viewer];
+
+    let total: i64 = client
+        .query_one(&format!("SELECT count(*) {}", SEARCH_FROM_WHERE), &params)
+        .await?
+        .get(0);
+
+    let markers = format!("{}{}", HIGHLIGHT_START, HIGHLIGHT_END);
+    let headline_options = format!(
+        "StartSel={},StopSel={},MaxWords=35,MinWords=15",
+        HIGHLIGHT_START, HIGHLIGHT_END
+    );
+
+    let rows = client.query(
+        &format!(
+            "SELECT m.id, m.room, COALESCE(u.username, 'Unknown User'), m.timestamp, m.parent_id, \
+                    ts_headline('simple', translate(m.message, $7, ''), q, $8) \
+             {} ORDER BY ts_rank(to_tsvector('simple', m.message), q) DESC, m.timestamp DESC, m.id DESC \
+             LIMIT $9 OFFSET $10",
+            SEARCH_FROM_WHERE
+        ),
+        &[params[0], params[1], params[2], params[3], params[4], params[5], &markers, &headline_options, &limit, &offset],
+    )
+    .await?;
+
+    let results = rows.iter().map(|row| {
+        let headline: String = row.get(5);
+        SearchResult {
+            id: row.get(0),
+            room: row.get(1),
+            username: row.get(2),
+            timestamp: row.get(3),
+            parent_id: row.get(4),
+            snippet: escape_html(&headline)
+                .replace(HIGHLIGHT_START, "<mark>")
+                .replace(HIGHLIGHT_END, "</mark>"),
+        }
+    }).collect();
+
+    Ok((results, total))
+}
+
+/// Находит участников комнаты по именам. Несуществующие имена и пользователи, которые
+/// не состоят в комнате, пропускаются: им нельзя показывать ее сообщения.
+pub async fn find_room_members_by_usernames(room: &str, usernames: &[String]) -> Result<Vec<Uuid>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let rows = client.query(
+        "SELECT u.user_uuid FROM users u \
+         JOIN room_members rm ON rm.user_uuid = u.user_uuid AND rm.room = $1 \
+         WHERE u.username = ANY($2)",
+        &[&room, &usernames],
+    )
+    .await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Участники комнаты
+pub async fn find_room_members(room: &str) -> Result<Vec<Uuid>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let rows = client.query("SELECT user_uuid FROM room_members WHERE room = $1", &[&room]).await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+// Колонки уведомления; строка собирается в Notification через notification_from_row.
+// Последняя колонка — получатель уведомления.
+const NOTIFICATION_SELECT: &str =
+    "SELECT n.id, n.kind, n.message_id, m.room, COALESCE(u.username, 'Unknown User'), \
+            CASE WHEN m.deleted_at IS NULL THEN m.message END, n.created_at, n.read_at, n.user_uuid \
+     FROM notifications n \
+     JOIN messages m ON m.id = n.message_id \
+     LEFT JOIN users u ON u.user_uuid = m.user_uuid";
+
+fn notification_from_row(row: &Row) -> Notification {
+    Notification {
+        id: row.get(0),
+        kind: row.get(1),
+        message_id: row.get(2),
+        room: row.get(3),
+        author: row.get(4),
+        message: row.get(5),
+        created_at: row.get(6),
+        read_at: row.get(7),
+    }
+}
+
+/// Сохраняет уведомления о сообщении для указанных пользователей (пары пользователь — вид).
+/// Пользователь, у которого уже есть уведомление об этом сообщении, пропускается.
+/// Возвращает созданные уведомления с получателями.
+pub async fn save_notifications(message_id: i64, recipients: &[(Uuid, &str)]) -> Result<Vec<(Uuid, Notification)>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let users: Vec<Uuid> = recipients.iter().map(|(user_uuid, _)| *user_uuid).collect();
+    let kinds: Vec<&str> = recipients.iter().map(|(_, kind)| *kind).collect();
+
+    let ids: Vec<i64> = client.query(
+        "INSERT INTO notifications (user_uuid, message_id, kind) \
+         SELECT r.user_uuid, $1, r.kind FROM unnest($2::uuid[], $3::text[]) AS r(user_uuid, kind) \
+         ON CONFLICT (user_uuid, message_id) DO NOTHING \
+         RETURNING id",
+        &[&message_id, &users, &kinds],
+    )
+    .await?
+    .iter()
+    .map(|row| row.get(0))
+    .collect();
+
+    let rows = client.query(&format!("{} WHERE n.id = ANY($1) ORDER BY n.id", NOTIFICATION_SELECT), &[&ids]).await?;
+
+    Ok(rows.iter().map(|row| (row.get::<_, Uuid>(8), notification_from_row(row))).collect())
+}
+
+/// Возвращает страницу уведомлений пользователя (новые первыми), их общее число и число непрочитанных
+pub async fn find_notifications(user_uuid: Uuid, unread_only: bool, limit: i64, offset: i64) -> Result<(Vec<Notification>, i64, i64), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let counts = client.query_one(
+        "SELECT count(*) FILTER (WHERE $2 = false OR read_at IS NULL), count(*) FILTER (WHERE read_at IS NULL) \
+         FROM notifications WHERE user_uuid = $1",
+        &[&user_uuid, &unread_only],
+    )
+    .await?;
+
+    let rows = client.query(
+        &format!(
+            "{} WHERE n.user_uuid = $1 AND ($2 = false OR n.read_at IS NULL) ORDER BY n.id DESC LIMIT $3 OFFSET $4",
+            NOTIFICATION_SELECT
+        ),
+        &[&user_uuid, &unread_only, &limit, &offset],
+    )
+    .await?;
+
+    Ok((rows.iter().map(notification_from_row).collect(), counts.get(0), counts.get(1)))
+}
+
+/// Отмечает уведомления пользователя прочитанными: перечисленные или все, если ids = None.
+/// Возвращает число отмеченных.
+pub async fn mark_notifications_read(user_uuid: Uuid, ids: Option<&[i64]>) -> Result<u64, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let updated = client.execute(
+        "UPDATE notifications SET read_at = now() \
+         WHERE user_uuid = $1 AND read_at IS NULL AND ($2::bigint[] IS NULL OR id = ANY($2))",
+        &[&user_uuid, &ids],
+    )
+    .await?;
+
+    Ok(updated)
+}
+
+/// Возвращает превью ссылки из кэша, если оно загружено не раньше max_age назад.
+/// Пустое превью тоже возвращается: страница уже загружалась, и превью у нее нет.
+pub async fn find_link_preview(url: &str, max_age: chrono::Duration) -> Result<Option<LinkPreview>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let row = client.query_opt(
+        "SELECT url, title, description, image, site_name FROM link_previews WHERE url = $1 AND fetched_at > $2",
+        &[&url, &(Utc::now() - max_age)],
+    )
+    .await?;
+
+    Ok(row.map(|row| LinkPreview {
+        url: row.get(0),
+        title: row.get(1),
+        description: row.get(2),
+        image: row.get(3),
+        site_name: row.get(4),
+    }))
+}
+
+/// Сохраняет превью ссылки в кэш, заменяя устаревшее
+pub async fn save_link_preview(preview: &LinkPreview) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    client.execute(
+        "INSERT INTO link_previews (url, title, description, image, site_name) VALUES ($1, $2, $3, $4, $5) \
+         ON CONFLICT (url) DO UPDATE SET title = $2, description = $3, image = $4, site_name = $5, fetched_at = now()",
+        &[&preview.url, &preview.title, &preview.description, &preview.image, &preview.site_name],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Привязывает превью к сообщению. Возвращает false, если сообщение удалено или его текст
+/// изменился (после редактирования превью строится заново).
+pub async fn set_message_preview(message_id: i64, message: &str, url: &str) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let updated = client.execute(
+        "UPDATE messages SET preview_url = $3 WHERE id = $1 AND message = $2 AND deleted_at IS NULL",
+        &[&message_id, &message, &url],
+    )
+    .await?;
+
+    Ok(updated > 0)
+}
+
+/// Возвращает поток сообщений для выгрузки в порядке отправки. Строки читаются из базы
+/// по мере чтения потока, а соединение живет, пока жив поток. Удаленные сообщения пропускаются.
+pub async fn export_messages(scope: &ExportScope) -> Result<impl Stream<Item = Result<ExportedMessage, Box<dyn StdError + Send + Sync>>>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let (condition, param): (&str, Box<dyn ToSql + Send + Sync>) = match scope {
+        ExportScope::Room(room) => ("m.room = $1", Box::new(room.clone())),
+        ExportScope::User(user_uuid) => ("m.user_uuid = $1", Box::new(*user_uuid)),
+    };
+    let query = format!(
+        "SELECT m.id, m.room, COALESCE(u.username, 'Unknown User'), m.timestamp, m.parent_id, m.message, m.message_html, \
+                m.edited_at, e.edits, a.attachments \
+         FROM messages m \
+         LEFT JOIN users u ON u.user_uuid = m.user_uuid \
+         LEFT JOIN LATERAL ( \
+             SELECT COALESCE(jsonb_agg(jsonb_build_object('message', previous_message, 'replaced_at', edited_at) \
+                                       ORDER BY edited_at, id), '[]'::jsonb) AS edits \
+             FROM message_edits WHERE message_id = m.id \
+         ) e ON true \
+         LEFT JOIN LATERAL ( \
+             SELECT COALESCE(jsonb_agg(jsonb_build_object('file_name', file_name, 'content_type', content_type, \
+                                                          'size', size, 'path', path) \
+                                       ORDER BY id), '[]'::jsonb) AS attachments \
+             FROM attachments WHERE message_id = m.id \
+         ) a ON true \
+         WHERE {} AND m.deleted_at IS NULL \
+         ORDER BY m.timestamp ASC, m.id ASC",
+        condition
+    );
+
+    let rows = client.query_raw(query.as_str(), [param.as_ref() as &(dyn ToSql + Sync)]).await?;
+
+    Ok(rows.map(move |row| {
+        // Поток держит клиента, иначе соединение закроется до конца выгрузки
+        let _client = &client;
+        let row = row?;
+        let message: String = row.get(5);
+        let html: Option<String> = row.get(6);
+        let edits: serde_json::Value = row.get(8);
+        let attachments: Vec<AttachmentRow> = serde_json::from_value(row.get(9))?;
+        Ok(ExportedMessage {
+            id: row.get(0),
+            room: row.get(1),
+            username: row.get(2),
+            timestamp: row.get(3),
+            parent_id: row.get(4),
+            html: html.unwrap_or_else(|| markdown::render(&message)),
+            message,
+            edited_at: row.get(7),
+            edits: serde_json::from_value(edits)?,
+            attachments: attachments.into_iter().map(AttachmentRow::into_attachment).collect(),
+        })
+    }))
+}
+
+/// Вложение в том виде, в каком его собирают запросы сообщений (jsonb)
+#[derive(Deserialize)]
+struct AttachmentRow {
+    file_name: String,
+    content_type: Option<String>,
+    size: i64,
+    path: String,
+}
+
+impl AttachmentRow {
+    fn into_attachment(self) -> Attachment {
+        Attachment { file_name: self.file_name, content_type: self.content_type, size: self.size, url: uploaded_url(&self.path) }
+    }
+}
+
+/// Задает срок хранения сообщений комнаты. Возвращает false, если комнаты нет.
+pub async fn set_room_retention(retention: &RoomRetention) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let updated = client.execute(
+        "UPDATE rooms SET retention_days = $2, retention_messages = $3 WHERE name = $1",
+        &[&retention.room, &retention.days, &retention.messages],
+    )
+    .await?;
+
+    Ok(updated > 0)
+}
+
+/// Возвращает комнаты, для которых задан срок хранения
+pub async fn find_room_retentions() -> Result<Vec<RoomRetention>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let rows = client.query(
+        "SELECT name, retention_days, retention_messages FROM rooms \
+         WHERE retention_days IS NOT NULL OR retention_messages IS NOT NULL ORDER BY name",
+        &[],
+    )
+    .await?;
+
+    Ok(rows.iter().map(|row| RoomRetention { room: row.get(0), days: row.get(1), messages: row.get(2) }).collect())
+}
+
+/// Удаляет из комнаты не больше batch_size сообщений с истекшим сроком хранения вместе с записями
+/// их вложений и возвращает число удаленных сообщений, ответов, удаленных каскадно, и пути файлов
+/// удаленных вложений (сами файлы удаляет вызывающий).
+/// По сроку в днях корень ветки хранится, пока в ветке есть свежие ответы; старые ответы удаляются.
+/// По числу сообщений считаются только корневые сообщения, ветка удаляется вместе с корнем.
+pub async fn purge_expired_messages(retention: &RoomRetention, batch_size: i64) -> Result<PurgedBatch, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let expired = match (retention.days, retention.messages) {
+        (Some(days), _) => format!(
+            "SELECT m.id FROM messages m WHERE m.room = $1 AND m.timestamp < now() - make_interval(days => {}) \
+             AND NOT EXISTS (SELECT 1 FROM messages r WHERE r.parent_id = m.id AND r.timestamp >= now() - make_interval(days => {})) \
+             ORDER BY m.id LIMIT $2",
+            days, days
+        ),
+        (None, Some(messages)) => format!(
+            "SELECT id FROM messages WHERE room = $1 AND parent_id IS NULL \
+             ORDER BY timestamp DESC, id DESC OFFSET {} LIMIT $2",
+            messages
+        ),
+        (None, None) => return Ok(PurgedBatch::default()),
+    };
+
+    // Записи вложений удаляются тем же запросом, что и сообщения, иначе каскад удалил бы их
+    // раньше, чем мы узнаем пути файлов
+    let query = format!(
+        "WITH expired AS ({}), \
+              replies AS (SELECT id FROM messages WHERE parent_id IN (SELECT id FROM expired) AND id NOT IN (SELECT id FROM expired)), \
+              files AS (DELETE FROM attachments WHERE message_id IN (SELECT id FROM expired UNION ALL SELECT id FROM replies) RETURNING path), \
+              deleted AS (DELETE FROM messages WHERE id IN (SELECT id FROM expired) RETURNING id) \
+         SELECT (SELECT count(*) FROM deleted), (SELECT count(*) FROM replies), ARRAY(SELECT path FROM files)",
+        expired
+    );
+
+    let row = client.query_one(query.as_str(), &[&retention.room, &batch_size]).await?;
+
+    Ok(PurgedBatch { messages: row.get(0), replies: row.get(1), files: row.get(2) })
+}
+
+/// Удаляет не больше batch_size загрузок, которые так и не прикрепили к сообщению за max_age_secs
+/// секунд, и возвращает пути их файлов (сами файлы удаляет вызывающий)
+pub async fn purge_unattached_uploads(max_age_secs: f64, batch_size: i64) -> Result<Vec<String>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let rows = client.query(
+        "DELETE FROM attachments WHERE id IN ( \
+             SELECT id FROM attachments WHERE message_id IS NULL AND created_at < now() - make_interval(secs => $1) \
+             ORDER BY created_at LIMIT $2 \
+         ) RETURNING path",
+        &[&max_age_secs, &batch_size],
+    )
+    .await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Возвращает тему комнаты; None, если комнаты нет
+pub async fn find_room_topic(room: &str) -> Result<Option<RoomTopic>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let row = client.query_opt(
+        "SELECT r.name, r.topic, r.description, u.username, r.topic_updated_at \
+         FROM rooms r LEFT JOIN users u ON u.user_uuid = r.topic_updated_by WHERE r.name = $1",
+        &[&room],
+    )
+    .await?;
+
+    Ok(row.map(|row| RoomTopic {
+        room: row.get(0),
+        topic: row.get(1),
+        description: row.get(2),
+        updated_by: row.get(3),
+        updated_at: row.get(4),
+    }))
+}
+
+/// Задает тему и описание комнаты. Возвращает false, если комнаты нет.
+pub async fn set_room_topic(room: &str, topic: Option<&str>, description: Option<&str>, updated_by: Uuid) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let updated = client.execute(
+        "UPDATE rooms SET topic = $2, description = $3, topic_updated_by = $4, topic_updated_at = now() WHERE name = $1",
+        &[&room, &topic, &description, &updated_by],
+    )
+    .await?;
+
+    Ok(updated > 0)
+}
+
+/// Закрепляет сообщение в его комнате, если там закреплено меньше max_pins сообщений
+pub async fn save_pin(message_id: i64, pinned_by: Uuid, max_pins: i64) -> Result<PinOutcome, Box<dyn StdError + Send + Sync>> {
+    let mut client = connect().await?;
+    let transaction = client.transaction().await?;
+
+    let room: String = match transaction.query_opt(
+        "SELECT room FROM messages WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+        &[&message_id],
+    )
+    .await?
+    {
+        Some(row) => row.get(0),
+        None => return Ok(PinOutcome::NotFound),
+    };
+
+    // Блокировка комнаты, чтобы одновременные закрепления не превысили лимит
+    transaction.execute("SELECT 1 FROM rooms WHERE name = $1 FOR UPDATE", &[&room]).await?;
+
+    let row = transaction.query_one(
+        "SELECT EXISTS (SELECT 1 FROM pins WHERE message_id = $1), (SELECT count(*) FROM pins WHERE room = $2)",
+        &[&message_id, &room],
+    )
+    .await?;
+    let (pinned, count): (bool, i64) = (row.get(0), row.get(1));
+    if pinned {
+        return Ok(PinOutcome::AlreadyPinned);
+    }
+    if count >= max_pins {
+        return Ok(PinOutcome::LimitReached);
+    }
+
+    transaction.execute(
+        "INSERT INTO pins (message_id, room, pinned_by) VALUES ($1, $2, $3)",
+        &[&message_id, &room, &pinned_by],
+    )
+    .await?;
+    transaction.commit().await?;
+
+    Ok(PinOutcome::Pinned { room })
+}
+
+/// Снимает закрепление и возвращает комнату сообщения; None, если сообщение не было закреплено
+pub async fn delete_pin(message_id: i64) -> Result<Option<String>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let row = client.query_opt("DELETE FROM pins WHERE message_id = $1 RETURNING room", &[&message_id]).await?;
+
+    Ok(row.map(|row| row.get(0)))
+}
+
+/// Загружает закрепленные сообщения комнаты (или одно сообщение, если задан message_id)
+/// в порядке закрепления. Реакции считаются с точки зрения viewer.
+pub async fn find_pins(room: &str, message_id: Option<i64>, viewer: Uuid) -> Result<Vec<PinnedMessage>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let pins = client.query(
+        "SELECT p.message_id, COALESCE(u.username, 'Unknown User'), p.pinned_at FROM pins p \
+         LEFT JOIN users u ON u.user_uuid = p.pinned_by \
+         WHERE p.room = $1 AND ($2::BIGINT IS NULL OR p.message_id = $2) ORDER BY p.pinned_at, p.message_id",
+        &[&room, &message_id],
+    )
+    .await?;
+    let ids: Vec<i64> = pins.iter().map(|row| row.get(0)).collect();
+
+    let query = format!("{} WHERE m.deleted_at IS NULL AND m.id = ANY($2)", MESSAGE_SELECT);
+    let mut messages: std::collections::HashMap<i64, ChatMessage> = client.query(query.as_str(), &[&viewer, &ids])
+        .await?
+        .iter()
+        .map(|row| {
+            let message = message_from_row(row);
+            (message.id, message)
+        })
+        .collect();
+
+    Ok(pins
+        .iter()
+        .filter_map(|row| {
+            Some(PinnedMessage {
+                message: messages.remove(&row.get::<_, i64>(0))?,
+                pinned_by: row.get(1),
+                pinned_at: row.get(2),
+            })
+        })
+        .collect())
+}
+
+/// Публикует уведомление в канал по уже открытому соединению
+pub async fn notify(client: &Client, channel: &str, payload: &str) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    client.execute("SELECT pg_notify($1, $2)", &[&channel, &payload]).await?;
+
+    Ok(())
+}
+
+/// Сохраняет событие, не помещающееся в уведомление, и возвращает его id
+pub async fn save_fanout_event(client: &Client, payload: &str) -> Result<i64, Box<dyn StdError + Send + Sync>> {
+    let row = client.query_one("INSERT INTO fanout_events (payload) VALUES ($1) RETURNING id", &[&payload]).await?;
+
+    Ok(row.get(0))
+}
+
+pub async fn find_fanout_event(id: i64) -> Result<Option<String>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let row = client.query_opt("SELECT payload FROM fanout_events WHERE id = $1", &[&id]).await?;
+
+    Ok(row.map(|row| row.get(0)))
+}
+
+/// Записывает, что соединение client_id экземпляра instance открыто в комнате
+pub async fn save_presence(client_id: &str, instance: Uuid, user_uuid: Uuid, room: &str) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    client.execute(
+        "INSERT INTO presence (client_id, instance_id, user_uuid, room) VALUES ($1, $2, $3, $4) \
+         ON CONFLICT (client_id) DO UPDATE SET instance_id = $2, user_uuid = $3, room = $4, seen_at = now()",
+        &[&client_id, &instance, &user_uuid, &room],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_presence(client_id: &str) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    client.execute("DELETE FROM presence WHERE client_id = $1", &[&client_id]).await?;
+
+    Ok(())
+}
+
+/// Удаляет присутствие всех клиентов экземпляра (при остановке)
+pub async fn delete_instance_presence(instance: Uuid) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    client.execute("DELETE FROM presence WHERE instance_id = $1", &[&instance]).await?;
+
+    Ok(())
+}
+
+/// Подтверждает присутствие клиентов экземпляра. Заодно удаляет записи экземпляров, которые
+/// давно не подтверждали свое (упавших), и старые события из fanout_events.
+pub async fn refresh_presence(instance: Uuid) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    client.execute("UPDATE presence SET seen_at = now() WHERE instance_id = $1", &[&instance]).await?;
+    client.execute("DELETE FROM presence WHERE seen_at < now() - INTERVAL '1 minute'", &[]).await?;
+    client.execute("DELETE FROM fanout_events WHERE created_at < now() - INTERVAL '1 minute'", &[]).await?;
+
+    Ok(())
+}
+
+/// Пользователи, подключенные к комнате на любом экземпляре, без повторов
+pub async fn find_room_presence(room: &str) -> Result<Vec<Uuid>, Box<dyn StdError + Send + Sync>> {
+    let client = connect().await?;
+
+    let rows = client.query(
+        "SELECT DISTINCT user_uuid FROM presence WHERE room = $1 AND seen_at > now() - INTERVAL '30 seconds'",
+        &[&room],
+    )
+    .await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn test_user(prefix: &str) -> Uuid {
+        let user_uuid = Uuid::new_v4();
+        let user = User {
+            username: format!("{}_{}", prefix, &user_uuid.simple().to_string()[..8]),
+            password_hash: String::new(),
+            invitation_code: "test".to_string(),
+            user_uuid,
+            role: Role::Member,
+        };
+        save_user_to_db(user).await.unwrap();
+        user_uuid
+    }
+
+    #[tokio::test]
+    async fn search_is_limited_to_member_rooms() {
+        if config::init_for_tests().is_none() {
+            eprintln!("DATABASE_URL is not set, skipping");
+            return;
+        }
+        init_schema().await.unwrap();
+
+        let member = test_user("member").await;
+        let outsider = test_user("outsider").await;
+        let room = format!("search-test-{}", Uuid::new_v4().simple());
+        save_room_to_db(&room).await.unwrap();
+        join_room(&room, member).await.unwrap();
+
+        let word = format!("needle{}", Uuid::new_v4().simple());
+        save_message_to_db(&word, &word, member, &room, None, &[]).await.unwrap();
+
+        let search = |viewer: Uuid, room: Option<String>| {
+            let filter = SearchFilter { query: word.clone(), viewer, author: None, room, from: None, to: None };
+            async move { search_messages(&filter, 10, 0).await.unwrap() }
+        };
+        let (results, total) = search(member, None).await;
+        assert_eq!((results.len(), total), (1, 1));
+
+        // Ни общий поиск, ни фильтр по чужой комнате не показывают ее сообщения
+        assert_eq!(search(outsider, None).await.1, 0);
+        assert_eq!(search(outsider, Some(room.clone())).await.1, 0);
+
+        let client = connect().await.unwrap();
+        client.execute("DELETE FROM messages WHERE room = $1", &[&room]).await.unwrap();
+        client.execute("DELETE FROM rooms WHERE name = $1", &[&room]).await.unwrap();
+        client.execute("DELETE FROM users WHERE user_uuid = ANY($1)", &[&vec![member, outsider]]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rooms_are_open_only_to_members() {
+        if config::init_for_tests().is_none() {
+            eprintln!("DATABASE_URL is not set, skipping");
+            return;
+        }
+        init_schema().await.unwrap();
+
+        let user = test_user("member").await;
+        let room = format!("members-test-{}", Uuid::new_v4().simple());
+        save_room_to_db(&room).await.unwrap();
+
+        // В general пользователь попадает при регистрации, в остальные комнаты — только по приглашению
+        assert!(is_room_member("general", user).await.unwrap());
+        assert!(!is_room_member(&room, user).await.unwrap());
+        let unread_rooms = |counts: Vec<RoomUnread>| counts.into_iter().map(|count| count.room).collect::<Vec<_>>();
+        assert!(!unread_rooms(find_unread_counts(user).await.unwrap()).contains(&room));
+
+        assert!(join_room(&room, user).await.unwrap());
+        assert!(!join_room(&room, user).await.unwrap());
+        assert!(is_room_member(&room, user).await.unwrap());
+        assert!(unread_rooms(find_unread_counts(user).await.unwrap()).contains(&room));
+
+        let (id, _, _) = save_message_to_db("hello", "hello", user, &room, None, &[]).await.unwrap();
+        assert!(save_read_marker(user, &room, id).await.unwrap());
+
+        // Вместе с участием удаляется и отметка прочтения, иначе schema.sql вернул бы участника
+        assert!(leave_room(&room, user).await.unwrap());
+        assert!(!leave_room(&room, user).await.unwrap());
+        assert!(!is_room_member(&room, user).await.unwrap());
+        init_schema().await.unwrap();
+        assert!(!is_room_member(&room, user).await.unwrap());
+
+        let client = connect().await.unwrap();
+        client.execute("DELETE FROM messages WHERE room = $1", &[&room]).await.unwrap();
+        client.execute("DELETE FROM rooms WHERE name = $1", &[&room]).await.unwrap();
+        client.execute("DELETE FROM users WHERE user_uuid = $1", &[&user]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn mentions_reach_only_room_members() {
+        if config::init_for_tests().is_none() {
+            eprintln!("DATABASE_URL is not set, skipping");
+            return;
+        }
+        init_schema().await.unwrap();
+
+        let member = test_user("member").await;
+        let outsider = test_user("outsider").await;
+        let room = format!("mentions-test-{}", Uuid::new_v4().simple());
+        save_room_to_db(&room).await.unwrap();
+        join_room(&room, member).await.unwrap();
+
+        // Сообщения в комнате не делают автора ее участником
+        save_message_to_db("hi", "hi", outsider, &room, None, &[]).await.unwrap();
+        let client = connect().await.unwrap();
+
+        assert_eq!(find_room_members(&room).await.unwrap(), vec![member]);
+
+        let usernames: Vec<String> = client
+            .query("SELECT username FROM users WHERE user_uuid = ANY($1)", &[&vec![member, outsider]])
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(find_room_members_by_usernames(&room, &usernames).await.unwrap(), vec![member]);
+
+        client.execute("DELETE FROM messages WHERE room = $1", &[&room]).await.unwrap();
+        client.execute("DELETE FROM rooms WHERE name = $1", &[&room]).await.unwrap();
+        client.execute("DELETE FROM users WHERE user_uuid = ANY($1)", &[&vec![member, outsider]]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn message_commands_stay_inside_their_room() {
+        if config::init_for_tests().is_none() {
+            eprintln!("DATABASE_URL is not set, skipping");
+            return;
+        }
+        init_schema().await.unwrap();
+
+        let author = test_user("author").await;
+        let room = format!("room-test-{}", Uuid::new_v4().simple());
+        let other = format!("room-test-{}", Uuid::new_v4().simple());
+        save_room_to_db(&room).await.unwrap();
+        save_room_to_db(&other).await.unwrap();
+
+        let (root, _, _) = save_message_to_db("root", "root", author, &room, None, &[]).await.unwrap();
+        let (reply, _, _) = save_message_to_db("reply", "reply", author, &room, Some(root), &[]).await.unwrap();
+
+        assert_eq!(find_thread_root(reply, &room).await.unwrap(), Some(root));
+        assert!(message_in_room(root, &room).await.unwrap());
+
+        // Из соединения с другой комнатой сообщение не найти: ни ветку, ни реакцию, ни правку
+        assert_eq!(find_thread_root(root, &other).await.unwrap(), None);
+        assert_eq!(find_thread_root(reply, &other).await.unwrap(), None);
+        assert!(!message_in_room(root, &other).await.unwrap());
+        let outcome = edit_message(root, &other, author, "changed", "changed", chrono::Duration::minutes(15)).await.unwrap();
+        assert!(matches!(outcome, EditOutcome::NotFound));
+
+        let client = connect().await.unwrap();
+        client.execute("DELETE FROM messages WHERE room = ANY($1)", &[&vec![room.clone(), other.clone()]]).await.unwrap();
+        client.execute("DELETE FROM rooms WHERE name = ANY($1)", &[&vec![room, other]]).await.unwrap();
+        client.execute("DELETE FROM users WHERE user_uuid = $1", &[&author]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_own_unattached_uploads_are_attached() {
+        if config::init_for_tests().is_none() {
+            eprintln!("DATABASE_URL is not set, skipping");
+            return;
+        }
+        init_schema().await.unwrap();
+
+        let author = test_user("author").await;
+        let stranger = test_user("stranger").await;
+        let room = format!("uploads-test-{}", Uuid::new_v4().simple());
+        save_room_to_db(&room).await.unwrap();
+
+        let path = |name: &str| format!("test/{}/{}", Uuid::new_v4().simple(), name);
+        let own = save_upload(author, &path("a.txt"), "a.txt", Some("text/plain"), 3).await.unwrap();
+        let foreign = save_upload(stranger, &path("b.txt"), "b.txt", None, 5).await.unwrap();
+
+        let (id, _, attached) = save_message_to_db("files", "files", author, &room, None, &[own, foreign]).await.unwrap();
+        assert_eq!(attached.len(), 1);
+        assert_eq!(attached[0].file_name, "a.txt");
+        assert_eq!(attached[0].size, 3);
+
+        // Уже прикрепленный файл второй раз не прикрепить
+        let (_, _, again) = save_message_to_db("again", "again", author, &room, None, &[own]).await.unwrap();
+        assert!(again.is_empty());
+
+        let history = find_message_history(&room, author).await.unwrap();
+        let message = history.iter().find(|message| message.id == id).unwrap();
+        assert_eq!(message.attachments.len(), 1);
+
+        let client = connect().await.unwrap();
+        client.execute("DELETE FROM attachments WHERE id = ANY($1)", &[&vec![own, foreign]]).await.unwrap();
+        client.execute("DELETE FROM messages WHERE room = $1", &[&room]).await.unwrap();
+        client.execute("DELETE FROM rooms WHERE name = $1", &[&room]).await.unwrap();
+        client.execute("DELETE FROM users WHERE user_uuid = ANY($1)", &[&vec![author, stranger]]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn login_is_locked_after_repeated_failures() {
+        if config::init_for_tests().is_none() {
+            eprintln!("DATABASE_URL is not set, skipping");
+            return;
+        }
+        init_schema().await.unwrap();
+
+        let user = test_user("lockout").await;
+        for _ in 0..2 {
+            assert_eq!(record_login_failure(user, 3, 60.0).await.unwrap(), None);
+            assert_eq!(find_login_lockout(user).await.unwrap(), None);
+        }
+        let locked_until = record_login_failure(user, 3, 60.0).await.unwrap().expect("third failure must lock the account");
+        assert!(locked_until > Utc::now());
+        assert_eq!(find_login_lockout(user).await.unwrap(), Some(locked_until));
+
+        // Блокировку снимает только время или успешный вход, дальнейшие попытки ее не продлевают
+        assert_eq!(record_login_failure(user, 3, 60.0).await.unwrap(), None);
+        assert_eq!(find_login_lockout(user).await.unwrap(), Some(locked_until));
+        clear_login_failures(user).await.unwrap();
+        assert_eq!(find_login_lockout(user).await.unwrap(), None);
+
+        // Попытки старше окна не считаются
+        record_login_failure(user, 2, 0.001).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert_eq!(record_login_failure(user, 2, 0.001).await.unwrap(), None);
+
+        let client = connect().await.unwrap();
+        client.execute("DELETE FROM users WHERE user_uuid = $1", &[&user]).await.unwrap();
+    }
+}
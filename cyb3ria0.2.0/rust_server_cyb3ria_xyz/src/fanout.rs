@@ -0,0 +1,387 @@
+//! Рассылка событий клиентам всех экземпляров сервера.
+//!
+//! Каждое соединение подписано на локальный broadcast-канал своего экземпляра и само выбирает
+//! адресованные ему события (см. Audience). Бэкенд (fanout.backend) определяет, как события
+//! попадают на другие экземпляры:
+//!
+//! - local — никак: сервер запущен в одном экземпляре (по умолчанию);
+//! - postgres — через LISTEN/NOTIFY. Событие сразу доставляется своим клиентам и публикуется
+//!   в канал cyb3ria_events, остальные экземпляры доставляют его своим. Нагрузка больше лимита
+//!   NOTIFY сохраняется в fanout_events, а в уведомлении передается только ее id. Присутствие
+//!   (кто подключен к комнате) хранится в таблице presence.
+
+use crate::db;
+use crate::handlers::chat::{Client, Clients};
+use crate::metrics;
+use crate::models::Role;
+use crate::protocol::ServerEvent;
+use async_trait::async_trait;
+use ipnetwork::IpNetwork;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep, Duration};
+use uuid::Uuid;
+
+pub type SharedFanOut = Arc<dyn FanOut>;
+
+/// Канал LISTEN/NOTIFY
+const CHANNEL: &str = "cyb3ria_events";
+
+/// Лимит полезной нагрузки NOTIFY в PostgreSQL — 8000 байт; берем с запасом
+const MAX_NOTIFY_PAYLOAD: usize = 7900;
+
+/// Префикс уведомления, нагрузка которого лежит в fanout_events
+const STORED_PREFIX: &str = "stored:";
+
+/// Как часто экземпляр подтверждает присутствие своих клиентов и чистит устаревшие записи
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Пауза перед повторным подключением LISTEN; удваивается до MAX_RECONNECT_DELAY
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Сколько последних идентификаторов событий помнить для отбрасывания повторов
+const RECENT_EVENTS: usize = 4096;
+
+/// Кому адресовано событие
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Audience {
+    All,
+    Room(String),
+    /// Все соединения этих пользователей
+    Users(Vec<Uuid>),
+    /// Клиенты, открывшие ветку, и ее участники
+    Thread { root: i64, participants: Vec<Uuid> },
+    /// Соединения с адресов из подсети пользователей с ролью ниже below (создателя бана)
+    Network { network: IpNetwork, below: Role },
+    /// Соединения пользователя с одной комнатой
+    RoomMember { room: String, user_uuid: Uuid },
+}
+
+impl Audience {
+    pub fn matches(&self, client: &Client) -> bool {
+        match self {
+            Audience::All => true,
+            Audience::Room(room) => client.room == *room,
+            Audience::Users(users) => users.contains(&client.user_uuid),
+            Audience::Thread { root, participants } => client.open_threads.contains(root) || participants.contains(&client.user_uuid),
+            Audience::Network { network, below } => network.contains(client.ip) && client.role < *below,
+            Audience::RoomMember { room, user_uuid } => client.room == *room && client.user_uuid == *user_uuid,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    /// Событие, сериализованное один раз для всех получателей
+    Event(Arc<str>),
+    /// Отключить соединение, сообщив причину (kick, ban)
+    Disconnect(String),
+}
+
+/// Событие для рассылки через общий канал
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Broadcast {
+    pub audience: Audience,
+    pub payload: Payload,
+}
+
+impl Broadcast {
+    pub fn event(audience: Audience, event: &ServerEvent) -> Self {
+        Broadcast { audience, payload: Payload::Event(event.to_json().into()) }
+    }
+}
+
+/// Событие между экземплярами. origin и seq вместе — идентификатор события.
+#[derive(Serialize, Deserialize, Debug)]
+struct Envelope {
+    origin: Uuid,
+    seq: u64,
+    message: Broadcast,
+}
+
+#[async_trait]
+pub trait FanOut: Send + Sync {
+    /// Доставляет событие клиентам этого и остальных экземпляров
+    fn publish(&self, message: Broadcast);
+
+    /// Подписка соединения на события для клиентов этого экземпляра
+    fn subscribe(&self) -> broadcast::Receiver<Broadcast>;
+
+    /// Пользователи, подключенные к комнате на любом экземпляре, без повторов
+    async fn room_presence(&self, room: &str) -> Vec<Uuid>;
+
+    /// Соединение открыто
+    async fn connected(&self, client_id: &str, user_uuid: Uuid, room: &str);
+
+    /// Соединение закрыто
+    async fn disconnected(&self, client_id: &str);
+
+    /// Остановка сервера: убирает присутствие экземпляра и закрывает свои соединения
+    async fn shutdown(&self);
+}
+
+fn send_local(local: &broadcast::Sender<Broadcast>, message: Broadcast) {
+    if let Err(e) = local.send(message) {
+        debug!("No clients to receive broadcast: {}", e);
+    }
+}
+
+/// Рассылка внутри одного процесса
+pub struct LocalFanOut {
+    local: broadcast::Sender<Broadcast>,
+    clients: Clients,
+}
+
+impl LocalFanOut {
+    pub fn new(capacity: usize, clients: Clients) -> Self {
+        LocalFanOut { local: broadcast::channel(capacity).0, clients }
+    }
+}
+
+#[async_trait]
+impl FanOut for LocalFanOut {
+    fn publish(&self, message: Broadcast) {
+        metrics::event_broadcast();
+        send_local(&self.local, message);
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Broadcast> {
+        self.local.subscribe()
+    }
+
+    async fn room_presence(&self, room: &str) -> Vec<Uuid> {
+        let clients = self.clients.lock().unwrap();
+        let users: HashSet<Uuid> = clients.values().filter(|client| client.room == room).map(|client| client.user_uuid).collect();
+        users.into_iter().collect()
+    }
+
+    async fn connected(&self, _client_id: &str, _user_uuid: Uuid, _room: &str) {}
+
+    async fn disconnected(&self, _client_id: &str) {}
+
+    async fn shutdown(&self) {}
+}
+
+/// Рассылка между экземплярами через PostgreSQL LISTEN/NOTIFY
+pub struct PostgresFanOut {
+    instance: Uuid,
+    seq: AtomicU64,
+    local: broadcast::Sender<Broadcast>,
+    outgoing: mpsc::UnboundedSender<String>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl PostgresFanOut {
+    /// Запускает публикацию, прослушивание канала и обновление присутствия
+    pub fn start(capacity: usize) -> Self {
+        let instance = Uuid::new_v4();
+        let local = broadcast::channel(capacity).0;
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+
+        let tasks = vec![
+            tokio::spawn(run_publisher(outgoing_rx)),
+            tokio::spawn(run_listener(instance, local.clone())),
+            tokio::spawn(run_heartbeat(instance)),
+        ];
+
+        info!("Fan-out through PostgreSQL, instance {}", instance);
+        PostgresFanOut { instance, seq: AtomicU64::new(0), local, outgoing, tasks: Mutex::new(tasks) }
+    }
+}
+
+#[async_trait]
+impl FanOut for PostgresFanOut {
+    fn publish(&self, message: Broadcast) {
+        metrics::event_broadcast();
+        let envelope = Envelope { origin: self.instance, seq: self.seq.fetch_add(1, Ordering::Relaxed), message };
+        match serde_json::to_string(&envelope) {
+            Ok(payload) => {
+                if self.outgoing.send(payload).is_err() {
+                    error!("Fan-out publisher is not running");
+                }
+            }
+            Err(e) => error!("Failed to serialize broadcast: {}", e),
+        }
+        send_local(&self.local, envelope.message);
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Broadcast> {
+        self.local.subscribe()
+    }
+
+    async fn room_presence(&self, room: &str) -> Vec<Uuid> {
+        match db::find_room_presence(room).await {
+            Ok(users) => users,
+            Err(e) => {
+                error!("Failed to load room presence: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    async fn connected(&self, client_id: &str, user_uuid: Uuid, room: &str) {
+        if let Err(e) = db::save_presence(client_id, self.instance, user_uuid, room).await {
+            error!("Failed to save presence: {}", e);
+        }
+    }
+
+    async fn disconnected(&self, client_id: &str) {
+        if let Err(e) = db::delete_presence(client_id).await {
+            error!("Failed to delete presence: {}", e);
+        }
+    }
+
+    async fn shutdown(&self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        if let Err(e) = db::delete_instance_presence(self.instance).await {
+            error!("Failed to delete presence of instance {}: {}", self.instance, e);
+        }
+    }
+}
+
+/// Публикует события в канал по одному долгоживущему соединению. Если базы нет, событие
+/// получат только клиенты этого экземпляра.
+async fn run_publisher(mut outgoing: mpsc::UnboundedReceiver<String>) {
+    let mut connection = None;
+
+    while let Some(payload) = outgoing.recv().await {
+        if connection.as_ref().is_none_or(tokio_postgres::Client::is_closed) {
+            connection = match db::connect_dedicated().await {
+                Ok(client) => Some(client),
+                Err(e) => {
+                    error!("Failed to connect fan-out publisher: {}", e);
+                    None
+                }
+            };
+        }
+        let Some(client) = &connection else {
+            warn!("Event was not published to other instances");
+            continue;
+        };
+
+        let result = if payload.len() <= MAX_NOTIFY_PAYLOAD {
+            db::notify(client, CHANNEL, &payload).await
+        } else {
+            match db::save_fanout_event(client, &payload).await {
+                Ok(id) => db::notify(client, CHANNEL, &format!("{}{}", STORED_PREFIX, id)).await,
+                Err(e) => Err(e),
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to publish event to other instances: {}", e);
+            connection = None;
+        }
+    }
+}
+
+/// Получает события других экземпляров и доставляет их своим клиентам
+async fn run_listener(instance: Uuid, local: broadcast::Sender<Broadcast>) {
+    let mut recent = RecentEvents::default();
+    let mut delay = RECONNECT_DELAY;
+    let mut reconnecting = false;
+
+    loop {
+        match db::listen(CHANNEL).await {
+            Ok((client, mut notifications)) => {
+                delay = RECONNECT_DELAY;
+                if reconnecting {
+                    // Пока канала не было, события других экземпляров терялись
+                    send_local(&local, Broadcast::event(Audience::All, &ServerEvent::Resync { missed: 0 }));
+                }
+
+                while let Some(payload) = notifications.recv().await {
+                    let Some(envelope) = read_envelope(&payload).await else {
+                        continue;
+                    };
+                    // Свои события уже доставлены при публикации
+                    if envelope.origin == instance || !recent.insert(envelope.origin, envelope.seq) {
+                        continue;
+                    }
+                    send_local(&local, envelope.message);
+                }
+
+                drop(client);
+                warn!("Fan-out listener lost its connection");
+            }
+            Err(e) => error!("Failed to listen for fan-out events: {}", e),
+        }
+
+        reconnecting = true;
+        sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+async fn read_envelope(payload: &str) -> Option<Envelope> {
+    let stored;
+    let text = match payload.strip_prefix(STORED_PREFIX) {
+        Some(id) => {
+            let id: i64 = id.parse().ok()?;
+            stored = match db::find_fanout_event(id).await {
+                Ok(Some(stored)) => stored,
+                Ok(None) => {
+                    warn!("Fan-out event {} is already purged", id);
+                    return None;
+                }
+                Err(e) => {
+                    error!("Failed to load fan-out event {}: {}", id, e);
+                    return None;
+                }
+            };
+            stored.as_str()
+        }
+        None => payload,
+    };
+
+    match serde_json::from_str(text) {
+        Ok(envelope) => Some(envelope),
+        Err(e) => {
+            error!("Malformed fan-out event: {}", e);
+            None
+        }
+    }
+}
+
+/// Подтверждает присутствие клиентов экземпляра и удаляет устаревшие записи
+async fn run_heartbeat(instance: Uuid) {
+    let mut timer = interval(HEARTBEAT_INTERVAL);
+    loop {
+        timer.tick().await;
+        if let Err(e) = db::refresh_presence(instance).await {
+            error!("Failed to refresh presence: {}", e);
+        }
+    }
+}
+
+/// Недавно полученные события, чтобы одно и то же не доставлялось дважды
+#[derive(Default)]
+struct RecentEvents {
+    order: VecDeque<(Uuid, u64)>,
+    seen: HashSet<(Uuid, u64)>,
+}
+
+impl RecentEvents {
+    /// false, если событие уже было
+    fn insert(&mut self, origin: Uuid, seq: u64) -> bool {
+        if !self.seen.insert((origin, seq)) {
+            return false;
+        }
+        self.order.push_back((origin, seq));
+        if self.order.len() > RECENT_EVENTS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
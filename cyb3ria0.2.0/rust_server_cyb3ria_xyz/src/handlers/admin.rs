@@ -0,0 +1,252 @@
+use warp::{Filter, Rejection, Reply, http::StatusCode};
+use crate::models::{User, Role, AuditEntry, AuditFilter, RoomRetention};
+use crate::db::{set_user_role, find_audit_entries, save_room_to_db, set_room_retention, join_room};
+use crate::permissions::require_role;
+use crate::utils::real_ip;
+use crate::audit;
+use chrono::{DateTime, Utc};
+use ipnetwork::IpNetwork;
+use log::{info, error};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::net::IpAddr;
+
+/// Размер страницы журнала аудита по умолчанию и максимальный
+const DEFAULT_AUDIT_PAGE_SIZE: i64 = 50;
+const MAX_AUDIT_PAGE_SIZE: i64 = 200;
+
+/// Максимальная длина имени комнаты
+const MAX_ROOM_NAME_LEN: usize = 32;
+
+/// Верхние границы срока хранения: 100 лет или миллион сообщений
+const MAX_RETENTION_DAYS: i32 = 36500;
+const MAX_RETENTION_MESSAGES: i32 = 1_000_000;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RoleChangeData {
+    pub role: Role,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RoomData {
+    pub name: String,
+}
+
+/// Срок хранения сообщений комнаты: days или messages, либо ничего (хранить всегда)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RetentionData {
+    pub days: Option<i32>,
+    pub messages: Option<i32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AdminResponse {
+    pub message: String,
+}
+
+/// Параметры запроса GET /api/admin/audit
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub action: Option<String>,
+    pub ip: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuditResponse {
+    pub entries: Vec<AuditEntry>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
+
+pub async fn set_role_handler(username: String, admin: User, ip: IpAddr, data: RoleChangeData) -> Result<impl warp::Reply, Rejection> {
+    // Администратор не может понизить сам себя, иначе можно остаться без администраторов
+    if admin.username == username {
+        let response = AdminResponse { message: "You cannot change your own role.".to_string() };
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&response),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    match set_user_role(&username, data.role).await {
+        Ok(Some((user_uuid, previous))) => {
+            info!("User {} set role of {} to {}", admin.username, username, data.role);
+            audit::record(audit::ROLE_CHANGED, Some(admin.user_uuid), Some(user_uuid), Some(ip), json!({ "from": previous, "to": data.role })).await;
+            let response = AdminResponse { message: format!("Role of {} set to {}.", username, data.role) };
+            Ok(warp::reply::with_status(
+                warp::reply::json(&response),
+                StatusCode::OK,
+            ))
+        },
+        Ok(None) => {
+            let response = AdminResponse { message: "User not found.".to_string() };
+            Ok(warp::reply::with_status(
+                warp::reply::json(&response),
+                StatusCode::NOT_FOUND,
+            ))
+        },
+        Err(e) => {
+            error!("Failed to set user role: {}", e);
+            let response = AdminResponse { message: "Failed to set user role.".to_string() };
+            Ok(warp::reply::with_status(
+                warp::reply::json(&response),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+pub async fn create_room_handler(admin: User, ip: IpAddr, data: RoomData) -> Result<impl warp::Reply, Rejection> {
+    // Имя комнаты попадает в адрес чата, поэтому допускаем только строчные латинские буквы, цифры, '-' и '_'
+    let valid = !data.name.is_empty()
+        && data.name.len() <= MAX_ROOM_NAME_LEN
+        && data.name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid {
+        let response = AdminResponse { message: format!("Room name must be 1-{} characters: a-z, 0-9, '-' or '_'.", MAX_ROOM_NAME_LEN) };
+        return Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::BAD_REQUEST));
+    }
+
+    let (status, message) = match save_room_to_db(&data.name).await {
+        Ok(true) => {
+            info!("User {} created room {}", admin.username, data.name);
+            audit::record(audit::ROOM_CREATED, Some(admin.user_uuid), None, Some(ip), json!({ "room": data.name })).await;
+            // Создатель становится первым участником комнаты; остальных добавляют модераторы
+            match join_room(&data.name, admin.user_uuid).await {
+                Ok(_) => (StatusCode::CREATED, format!("Room {} created.", data.name)),
+                Err(e) => {
+                    error!("Failed to add {} to room {}: {}", admin.username, data.name, e);
+                    (StatusCode::CREATED, format!("Room {} created, but you could not be added to it.", data.name))
+                }
+            }
+        }
+        Ok(false) => (StatusCode::CONFLICT, "Room already exists.".to_string()),
+        Err(e) => {
+            error!("Failed to create room: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to create room.".to_string())
+        }
+    };
+
+    Ok(warp::reply::with_status(warp::reply::json(&AdminResponse { message }), status))
+}
+
+pub async fn set_retention_handler(room: String, admin: User, ip: IpAddr, data: RetentionData) -> Result<impl warp::Reply, Rejection> {
+    let valid = match (data.days, data.messages) {
+        (Some(_), Some(_)) => false,
+        (Some(days), None) => (1..=MAX_RETENTION_DAYS).contains(&days),
+        (None, Some(messages)) => (1..=MAX_RETENTION_MESSAGES).contains(&messages),
+        (None, None) => true,
+    };
+    if !valid {
+        let message = format!(
+            "Set either days (1-{}) or messages (1-{}), or neither to keep messages forever.",
+            MAX_RETENTION_DAYS, MAX_RETENTION_MESSAGES
+        );
+        return Ok(warp::reply::with_status(warp::reply::json(&AdminResponse { message }), StatusCode::BAD_REQUEST));
+    }
+
+    let retention = RoomRetention { room, days: data.days, messages: data.messages };
+    let (status, message) = match set_room_retention(&retention).await {
+        Ok(true) => {
+            info!("User {} set retention of room {}: {:?}", admin.username, retention.room, data);
+            audit::record(
+                audit::ROOM_RETENTION_CHANGED,
+                Some(admin.user_uuid),
+                None,
+                Some(ip),
+                json!({ "room": retention.room, "days": retention.days, "messages": retention.messages }),
+            )
+            .await;
+            let policy = match (retention.days, retention.messages) {
+                (Some(days), _) => format!("messages older than {} days are deleted", days),
+                (None, Some(messages)) => format!("only the last {} messages are kept", messages),
+                (None, None) => "messages are kept forever".to_string(),
+            };
+            (StatusCode::OK, format!("Room {}: {}.", retention.room, policy))
+        }
+        Ok(false) => (StatusCode::NOT_FOUND, "Room not found.".to_string()),
+        Err(e) => {
+            error!("Failed to set room retention: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to set room retention.".to_string())
+        }
+    };
+
+    Ok(warp::reply::with_status(warp::reply::json(&AdminResponse { message }), status))
+}
+
+pub async fn audit_handler(_admin: User, query: AuditQuery) -> Result<warp::reply::Response, Rejection> {
+    if let Some(ip) = &query.ip {
+        if ip.parse::<IpNetwork>().is_err() {
+            let response = AdminResponse { message: "Invalid IP address.".to_string() };
+            return Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::BAD_REQUEST).into_response());
+        }
+    }
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_AUDIT_PAGE_SIZE).clamp(1, MAX_AUDIT_PAGE_SIZE);
+    let filter = AuditFilter {
+        actor: query.actor,
+        target: query.target,
+        action: query.action,
+        ip: query.ip,
+        from: query.from,
+        to: query.to,
+    };
+
+    match find_audit_entries(&filter, per_page, (page - 1) * per_page).await {
+        Ok((entries, total)) => {
+            let response = AuditResponse { entries, page, per_page, total };
+            Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::OK).into_response())
+        }
+        Err(e) => {
+            error!("Failed to fetch audit log: {}", e);
+            let response = AdminResponse { message: "Failed to fetch audit log.".to_string() };
+            Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::INTERNAL_SERVER_ERROR).into_response())
+        }
+    }
+}
+
+/// PUT /api/admin/users/{username}/role — смена роли пользователя (только для администраторов)
+pub fn set_role_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "admin" / "users" / String / "role")
+        .and(warp::put())
+        .and(require_role(Role::Admin))
+        .and(real_ip())
+        .and(warp::body::json())
+        .and_then(set_role_handler)
+}
+
+/// GET /api/admin/audit — журнал аудита с фильтрами и постраничным выводом (только для администраторов)
+pub fn audit_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "admin" / "audit")
+        .and(warp::get())
+        .and(require_role(Role::Admin))
+        .and(warp::query::<AuditQuery>())
+        .and_then(audit_handler)
+}
+
+/// POST /api/admin/rooms — создание комнаты (только для администраторов)
+pub fn create_room_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "admin" / "rooms")
+        .and(warp::post())
+        .and(require_role(Role::Admin))
+        .and(real_ip())
+        .and(warp::body::json())
+        .and_then(create_room_handler)
+}
+
+/// PUT /api/admin/rooms/{room}/retention — срок хранения сообщений комнаты (только для администраторов)
+pub fn set_retention_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "admin" / "rooms" / String / "retention")
+        .and(warp::put())
+        .and(require_role(Role::Admin))
+        .and(real_ip())
+        .and(warp::body::json())
+        .and_then(set_retention_handler)
+}
@@ -1,13 +1,20 @@
-use warp::{Filter, Rejection, http::StatusCode};
-use crate::models::{User, Device, Session};
-use bcrypt::{hash, DEFAULT_COST, verify};
+use warp::{Filter, Rejection, Reply, http::{StatusCode, header::SET_COOKIE}};
+use crate::models::{User, Device, Session, Role};
 use uuid::Uuid;
-use std::net::SocketAddr;
+use std::net::IpAddr;
 use chrono::{Utc, Duration};
 use validator::{Validate, ValidationErrors, ValidationError};
 use log::{info, error, debug};
 use serde::{Deserialize, Serialize};
-use crate::db::{save_user_to_db, find_user_by_username, save_device_to_db, save_session_to_db, find_device_by_ip_mac};
+use crate::db::{save_user_to_db, find_user_by_username, save_device_to_db, save_session_to_db, find_device_by_ip_mac, update_password_hash, delete_session, find_active_ban,
+    find_login_lockout, record_login_failure, clear_login_failures};
+use crate::permissions::SESSION_COOKIE;
+use crate::utils::real_ip;
+use crate::audit;
+use crate::config;
+use crate::metrics;
+use serde_json::json;
+use crate::password::{hash_password, verify_password, upgraded_hash, check_password_policy, MAX_PASSWORD_CHARS};
 use std::borrow::Cow;
 
 
@@ -36,6 +43,12 @@ pub struct LoginData {
 pub struct LoginResponse {
     pub message: String,
     pub username: String,
+    pub role: Option<Role>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LogoutResponse {
+    pub message: String,
 }
 
 impl Validate for RegistrationData {
@@ -47,14 +60,14 @@ impl Validate for RegistrationData {
             error.message = Some("Username must be between 3 and 16 characters".to_string().into());
             errors.add("username", error);
         }
-        if self.password.len() < 6 || self.password.len() > 16 {
-             let mut error = ValidationError::new("length");
-            error.message = Some("Password must be between 6 and 16 characters".to_string().into());
+        if let Err(message) = check_password_policy(&self.password) {
+             let mut error = ValidationError::new("password_policy");
+            error.message = Some(message.into());
             errors.add("password", error);
         }
-        if self.repeat_password.len() < 6 || self.repeat_password.len() > 16 {
+        if self.repeat_password.chars().count() > MAX_PASSWORD_CHARS {
             let mut error = ValidationError::new("length");
-            error.message = Some("Repeat password must be between 6 and 16 characters".to_string().into());
+            error.message = Some(format!("Repeat password must be at most {} characters", MAX_PASSWORD_CHARS).into());
              errors.add("repeat_password", error);
         }
          if self.invitation_code.len() < 3 || self.invitation_code.len() > 16 {
@@ -80,9 +93,10 @@ impl Validate for LoginData {
             error.message = Some("Username must be between 3 and 16 characters".to_string().into());
             errors.add("username", error);
         }
-        if self.password.len() < 6 || self.password.len() > 16 {
+        // Минимальную длину при входе не проверяем: старые пароли могли быть короче текущего минимума
+        if self.password.is_empty() || self.password.chars().count() > MAX_PASSWORD_CHARS {
              let mut error = ValidationError::new("length");
-            error.message = Some("Password must be between 6 and 16 characters".to_string().into());
+            error.message = Some(format!("Password must be at most {} characters", MAX_PASSWORD_CHARS).into());
             errors.add("password", error);
         }
 
@@ -98,14 +112,34 @@ fn map_validation_errors(errors: ValidationErrors) -> String {
     let mut result = String::new();
     for (_, field_errors) in errors.field_errors() {
         for error in field_errors {
-           result.push_str(&format!("{} ", error.message.as_ref().unwrap_or(&Cow::from("Invalid value")).to_string()));
+           result.push_str(&format!("{} ", error.message.as_ref().unwrap_or(&Cow::from("Invalid value"))));
         }
     }
     result.trim().to_string()
 }
 
-pub async fn register_handler(registration: RegistrationData, _peer_addr: SocketAddr) -> Result<impl warp::Reply, Rejection> {
-    debug!("Received registration request: {:?}", registration);
+pub async fn register_handler(registration: RegistrationData, client_ip: IpAddr) -> Result<impl warp::Reply, Rejection> {
+    debug!("Received registration request for username: {}", registration.username);
+
+    match find_active_ban(None, Some(client_ip)).await {
+        Ok(Some(ban)) => {
+            info!("Rejected registration from banned address {} (ban {})", client_ip, ban.id);
+            let response = RegistrationResponse { message: "Registration from this address is banned.".to_string() };
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&response),
+                StatusCode::FORBIDDEN,
+            ));
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("Failed to check bans: {}", e);
+            let response = RegistrationResponse { message: "Failed to check bans.".to_string() };
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&response),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    }
 
     // Валидация данных
    if let Err(errors) = registration.validate() {
@@ -128,7 +162,7 @@ pub async fn register_handler(registration: RegistrationData, _peer_addr: Socket
         ));
     }
 
-    let password_hash = match hash(registration.password, DEFAULT_COST) {
+    let password_hash = match hash_password(registration.password).await {
         Ok(hash) => hash,
         Err(e) => {
             error!("Failed to hash password: {}", e);
@@ -144,14 +178,15 @@ pub async fn register_handler(registration: RegistrationData, _peer_addr: Socket
 
     let user = User {
         username: registration.username,
-        password_hash: password_hash,
+        password_hash,
         invitation_code: registration.invitation_code,
         user_uuid,
+        role: Role::Member,
     };
 
     match save_user_to_db(user).await {
-        Ok(_) => {
-            info!("User registered successfully.");
+        Ok(role) => {
+            info!("User registered successfully with role: {}", role);
 
             let device = Device {
                 device_id: Uuid::new_v4(),
@@ -180,48 +215,119 @@ pub async fn register_handler(registration: RegistrationData, _peer_addr: Socket
     }
 }
 
-pub async fn login_handler(login: LoginData, peer_addr: SocketAddr) -> Result<impl warp::Reply, Rejection> {
-    debug!("Received login request: {:?}", login);
+pub async fn login_handler(login: LoginData, client_ip: IpAddr) -> Result<warp::reply::Response, Rejection> {
+    debug!("Received login request for username: {}", login.username);
 
     // Валидация данных
    if let Err(errors) = login.validate() {
         error!("Validation errors: {:?}", errors);
         let error_message = map_validation_errors(errors);
-        let response = LoginResponse { message: error_message , username: "".to_string()};
+        let response = LoginResponse { message: error_message , username: "".to_string(), role: None };
          return Ok(warp::reply::with_status(
             warp::reply::json(&response),
            StatusCode::BAD_REQUEST,
-        ));
+        ).into_response());
     }
 
     let user = match find_user_by_username(&login.username).await {
         Ok(user) => user,
         Err(e) => {
             error!("Failed to find user: {}", e);
-            let response = LoginResponse { message: "Failed to find user.".to_string(), username: "".to_string() };
+            metrics::login_failed();
+            audit::record(audit::LOGIN_FAILURE, None, None, Some(client_ip), json!({ "username": login.username, "reason": "unknown_user" })).await;
+            let response = LoginResponse { message: "Failed to find user.".to_string(), username: "".to_string(), role: None };
             return Ok(warp::reply::with_status(
                 warp::reply::json(&response),
                 StatusCode::UNAUTHORIZED,
-            ));
+            ).into_response());
+        }
+    };
+
+    // Пароль заблокированной учетной записи не проверяется, чтобы перебор не продолжался
+    match find_login_lockout(user.user_uuid).await {
+        Ok(Some(locked_until)) => {
+            info!("Rejected login of locked out user {} until {}", login.username, locked_until);
+            metrics::login_failed();
+            audit::record(audit::LOGIN_FAILURE, None, Some(user.user_uuid), Some(client_ip), json!({ "username": login.username, "reason": "locked_out" })).await;
+            let response = LoginResponse { message: "Too many failed login attempts. Try again later.".to_string(), username: "".to_string(), role: None };
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&response),
+                StatusCode::TOO_MANY_REQUESTS,
+            ).into_response());
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("Failed to check login lockout: {}", e);
+            let response = LoginResponse { message: "Failed to check login lockout.".to_string(), username: "".to_string(), role: None };
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&response),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ).into_response());
+        }
+    }
+
+    let password_valid = match verify_password(login.password.clone(), user.password_hash.clone()).await {
+        Ok(valid) => valid,
+        Err(e) => {
+            error!("Failed to verify password: {}", e);
+            false
         }
     };
 
-    if !verify(&login.password, &user.password_hash).unwrap_or(false) {
+    if !password_valid {
         error!("Invalid password.");
-        let response = LoginResponse { message: "Invalid password.".to_string(), username: "".to_string() };
+        metrics::login_failed();
+        audit::record(audit::LOGIN_FAILURE, None, Some(user.user_uuid), Some(client_ip), json!({ "username": login.username, "reason": "invalid_password" })).await;
+        lock_out_after_failure(&user, client_ip).await;
+        let response = LoginResponse { message: "Invalid password.".to_string(), username: "".to_string(), role: None };
         return Ok(warp::reply::with_status(
             warp::reply::json(&response),
             StatusCode::UNAUTHORIZED,
-        ));
+        ).into_response());
     }
 
-    let device = match find_device_by_ip_mac(&peer_addr.ip().to_string(), None).await {
+    match find_active_ban(Some(user.user_uuid), Some(client_ip)).await {
+        Ok(Some(ban)) => {
+            info!("Rejected login of banned user {} from {} (ban {})", login.username, client_ip, ban.id);
+            metrics::login_failed();
+            audit::record(audit::LOGIN_FAILURE, None, Some(user.user_uuid), Some(client_ip), json!({ "username": login.username, "reason": "banned", "ban_id": ban.id })).await;
+            let response = LoginResponse { message: "You are banned.".to_string(), username: "".to_string(), role: None };
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&response),
+                StatusCode::FORBIDDEN,
+            ).into_response());
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("Failed to check bans: {}", e);
+            let response = LoginResponse { message: "Failed to check bans.".to_string(), username: "".to_string(), role: None };
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&response),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ).into_response());
+        }
+    }
+
+    // Прозрачно переводим старые хэши (bcrypt) на Argon2id
+    match upgraded_hash(login.password.clone(), &user.password_hash).await {
+        Ok(Some(new_hash)) => {
+            if let Err(e) = update_password_hash(user.user_uuid, &new_hash).await {
+                error!("Failed to update password hash: {}", e);
+            } else {
+                info!("Password hash upgraded for user: {}", login.username);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => error!("Failed to rehash password: {}", e),
+    }
+
+    let device = match find_device_by_ip_mac(&client_ip.to_string(), None).await {
         Ok(Some(device)) => device,
         Ok(None) => {
             let device = Device {
                 device_id: Uuid::new_v4(),
                 user_uuid: user.user_uuid,
-                ip_address: peer_addr.ip().to_string(),
+                ip_address: client_ip.to_string(),
             };
             if let Err(e) = save_device_to_db(device.clone()).await {
                 error!("Failed to save device to database: {}", e);
@@ -230,30 +336,100 @@ pub async fn login_handler(login: LoginData, peer_addr: SocketAddr) -> Result<im
         },
         Err(e) => {
             error!("Failed to find device: {}", e);
-            let response = LoginResponse { message: "Failed to find device.".to_string(), username: "".to_string() };
+            let response = LoginResponse { message: "Failed to find device.".to_string(), username: "".to_string(), role: None };
             return Ok(warp::reply::with_status(
                 warp::reply::json(&response),
                 StatusCode::INTERNAL_SERVER_ERROR,
-            ));
+            ).into_response());
         }
     };
     
+    let lifetime_hours = config::get().sessions.lifetime_hours;
     let session = Session {
         session_id: Uuid::new_v4(),
         user_uuid: user.user_uuid,
         device_id: device.device_id,
-        expires_at: Some(Utc::now() + Duration::hours(1)),
+        expires_at: Some(Utc::now() + Duration::hours(lifetime_hours)),
     };
+    let session_id = session.session_id;
 
+    // Без сохраненной сессии cookie бесполезна, поэтому вход считаем неудавшимся
     if let Err(e) = save_session_to_db(session).await {
         error!("Failed to save session to database: {}", e);
+        let response = LoginResponse { message: "Failed to create session.".to_string(), username: "".to_string(), role: None };
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&response),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).into_response());
+    }
+
+    if let Err(e) = clear_login_failures(user.user_uuid).await {
+        error!("Failed to reset failed login attempts: {}", e);
     }
 
     info!("User logged in successfully: {}", login.username);
-    let response = LoginResponse { message: "User logged in successfully.".to_string(), username: login.username.to_string() };
-    Ok(warp::reply::with_status(
-        warp::reply::json(&response),
-        StatusCode::OK,
+    metrics::login_succeeded();
+    audit::record(audit::LOGIN_SUCCESS, Some(user.user_uuid), None, Some(client_ip), json!({ "session_id": session_id })).await;
+    let response = LoginResponse { message: "User logged in successfully.".to_string(), username: login.username.to_string(), role: Some(user.role) };
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly; Secure; SameSite=Strict; Max-Age={}",
+        SESSION_COOKIE, session_id, lifetime_hours * 3600
+    );
+    Ok(warp::reply::with_header(
+        warp::reply::with_status(warp::reply::json(&response), StatusCode::OK),
+        SET_COOKIE,
+        cookie,
+    ).into_response())
+}
+
+/// Учитывает неверный пароль и, если попыток стало rate_limits.max_failed_logins,
+/// блокирует вход и записывает блокировку в журнал аудита
+async fn lock_out_after_failure(user: &User, client_ip: IpAddr) {
+    let limits = &config::get().rate_limits;
+    if limits.max_failed_logins == 0 {
+        return;
+    }
+
+    match record_login_failure(user.user_uuid, limits.max_failed_logins as i32, limits.login_lockout_secs as f64).await {
+        Ok(Some(locked_until)) => {
+            info!("Locked out user {} after {} failed logins until {}", user.username, limits.max_failed_logins, locked_until);
+            audit::record(
+                audit::LOGIN_LOCKOUT,
+                None,
+                Some(user.user_uuid),
+                Some(client_ip),
+                json!({ "username": user.username, "failed_attempts": limits.max_failed_logins, "locked_until": locked_until }),
+            )
+            .await;
+        }
+        Ok(None) => {}
+        Err(e) => error!("Failed to record failed login: {}", e),
+    }
+}
+
+pub async fn logout_handler(session_cookie: Option<String>, client_ip: IpAddr) -> Result<impl warp::Reply, Rejection> {
+    let mut response = LogoutResponse { message: "Logged out.".to_string() };
+    let mut status = StatusCode::OK;
+
+    if let Some(session_id) = session_cookie.and_then(|value| Uuid::parse_str(&value).ok()) {
+        match delete_session(session_id).await {
+            Ok(Some(user_uuid)) => {
+                audit::record(audit::SESSION_REVOKED, Some(user_uuid), Some(user_uuid), Some(client_ip), json!({ "session_id": session_id, "reason": "logout" })).await;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to delete session: {}", e);
+                response.message = "Failed to log out.".to_string();
+                status = StatusCode::INTERNAL_SERVER_ERROR;
+            }
+        }
+    }
+
+    // Cookie удаляем в любом случае
+    Ok(warp::reply::with_header(
+        warp::reply::with_status(warp::reply::json(&response), status),
+        SET_COOKIE,
+        format!("{}=; Path=/; HttpOnly; Secure; SameSite=Strict; Max-Age=0", SESSION_COOKIE),
     ))
 }
 
@@ -262,11 +438,8 @@ pub fn register_route() -> impl Filter<Extract = impl warp::Reply, Error = warp:
     warp::path("api")
         .and(warp::path("register"))
         .and(warp::body::json())
-        .and(warp::addr::remote())
-        .and_then(|registration: RegistrationData, addr: Option<SocketAddr>| async move {
-            let peer_addr = addr.expect("Failed to get peer address");
-            register_handler(registration, peer_addr).await
-        })
+        .and(real_ip())
+        .and_then(register_handler)
 }
 
 
@@ -274,9 +447,16 @@ pub fn login_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Re
     warp::path("api")
         .and(warp::path("login"))
         .and(warp::body::json())
-        .and(warp::addr::remote())
-        .and_then(|login: LoginData, addr: Option<SocketAddr>| async move {
-            let peer_addr = addr.expect("Failed to get peer address");
-            login_handler(login, peer_addr).await
-        })
+        .and(real_ip())
+        .and_then(login_handler)
+}
+
+
+pub fn logout_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("logout"))
+        .and(warp::post())
+        .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+        .and(real_ip())
+        .and_then(logout_handler)
 }
@@ -1,134 +1,1005 @@
 use warp::ws::{WebSocket, Message};
-use futures_util::stream::{StreamExt};
+use warp::Filter;
+use futures_util::stream::{SplitSink, StreamExt};
 use futures_util::SinkExt;
 use std::sync::{Arc, Mutex};
-use tokio::sync::broadcast;
-use tokio::sync::Mutex as TokioMutex;
-use log::{info, error, debug};
-use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::Notify;
+use tokio::sync::mpsc::error::SendTimeoutError;
+use tokio::task::JoinHandle;
+use log::{info, error, debug, warn};
 use crate::db::{
-    save_message_to_db, send_message_history, find_user_by_username
+    save_message_to_db, find_message_history, find_user_by_username, find_active_mute, find_active_ban, edit_message,
+    find_thread_root, find_thread_replies, find_thread_summary, add_reaction, remove_reaction, count_reactions,
+    message_in_room, is_room_member, find_unread_counts, save_read_marker, find_room_members_by_usernames, find_room_members,
+    save_notifications, set_message_preview, find_room_topic, find_pins
 };
-use crate::utils::generate_client_id;
-use std::net::SocketAddr;
-use tokio::time::{Duration as TokioDuration, interval};
+use crate::handlers::moderation::{self, BanData, TopicData, ModerationResult};
+use crate::markdown;
+use crate::config;
+use crate::metrics;
+use crate::shutdown;
+use crate::mentions::{parse_mentions, Mentions};
+use crate::models::{User, Role, EditOutcome, Attachment};
+use crate::fanout::{Audience, Broadcast, Payload, SharedFanOut};
+use crate::previews::{self, SharedFetcher};
+use crate::permissions::{authenticated, Banned, InternalError};
+use crate::protocol::{ChatMessage, ClientCommand, ServerEvent};
+use crate::security::allowed_origin;
+use crate::utils::{generate_client_id, real_ip, is_emoji};
+use std::collections::{HashSet, VecDeque};
+use std::net::IpAddr;
+use serde::Deserialize;
+use tokio::time::{Duration as TokioDuration, Instant, interval, timeout};
+use uuid::Uuid;
 
+/// Комната, в которую попадает клиент без параметра room
+pub const DEFAULT_ROOM: &str = "general";
 
-type Clients = Arc<Mutex<std::collections::HashMap<String, usize>>>;
-type Sender = Arc<Mutex<broadcast::Sender<String>>>;
+/// Как часто рассылается typing_started, пока пользователь продолжает печатать
+const TYPING_THROTTLE: TokioDuration = TokioDuration::from_secs(3);
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-struct ClientMessage {
-    username: String,
-    message: String,
-    ip: String,
-    mac: String
+/// Сколько исходящих сообщений может ждать отправки клиенту
+const OUTBOUND_QUEUE_SIZE: usize = 256;
+
+/// Сколько ждать места в полной очереди, прежде чем отключить клиента
+const OUTBOUND_STALL_TIMEOUT: TokioDuration = TokioDuration::from_secs(10);
+
+/// Сколько при отключении ждать, пока писатель отправит остаток очереди
+const WRITER_DRAIN_TIMEOUT: TokioDuration = TokioDuration::from_secs(5);
+
+/// Код закрытия WebSocket при остановке сервера (Going Away)
+const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// Окно, за которое считается rate_limits.messages_per_minute
+const RATE_LIMIT_WINDOW: TokioDuration = TokioDuration::from_secs(60);
+
+/// Сколько файлов можно прикрепить к одному сообщению
+const MAX_ATTACHMENTS: usize = 10;
+
+pub type Clients = Arc<Mutex<std::collections::HashMap<String, Client>>>;
+/// Рассылка событий клиентам всех экземпляров сервера (см. fanout)
+pub type Sender = SharedFanOut;
+
+/// Параметры подключения к /api/ws
+#[derive(Deserialize, Debug)]
+pub struct ChatQuery {
+    pub room: Option<String>,
 }
 
-pub async fn client_connection(ws: WebSocket, clients: Clients, sender: Sender, peer_addr: SocketAddr, username_from_url: Option<String>) {
-    let (client_ws_sender, mut client_ws_rcv) = ws.split();
-    let client_ws_sender = Arc::new(TokioMutex::new(client_ws_sender));
-    let username = username_from_url.unwrap_or_else(|| peer_addr.ip().to_string());
+/// Очередь исходящих сообщений соединения. В сокет пишет только задача spawn_writer, поэтому
+/// история, рассылка и ответы на команды не ждут друг друга на блокировке.
+#[derive(Clone)]
+struct WsSender {
+    queue: mpsc::Sender<Message>,
+    /// Срабатывает, когда очередь слишком долго остается полной и клиента пора отключить
+    overflow: Arc<Notify>,
+}
+
+impl WsSender {
+    /// Ставит сообщение в очередь. false, если соединение закрывается или клиент не успевает читать.
+    async fn send(&self, message: Message) -> bool {
+        match self.queue.send_timeout(message, OUTBOUND_STALL_TIMEOUT).await {
+            Ok(()) => true,
+            Err(SendTimeoutError::Timeout(_)) => {
+                self.overflow.notify_one();
+                false
+            }
+            Err(SendTimeoutError::Closed(_)) => false,
+        }
+    }
+}
+
+/// Живое WebSocket-соединение в реестре клиентов
+pub struct Client {
+    pub user_uuid: Uuid,
+    pub role: Role,
+    pub ip: IpAddr,
+    pub room: String,
+    pub control: mpsc::UnboundedSender<Control>,
+    /// Ветки, которые клиент сейчас открыл
+    pub open_threads: HashSet<i64>,
+}
+
+/// Управляющие команды, которые можно передать соединению через реестр клиентов
+#[derive(Debug, Clone)]
+pub enum Control {
+    /// Отключить клиента, сообщив ему причину
+    Kick(String),
+    /// Сервер останавливается: дописать события, сообщить клиенту и закрыть соединение
+    Shutdown,
+}
+
+pub fn with_clients(clients: Clients) -> impl Filter<Extract = (Clients,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || clients.clone())
+}
+
+pub fn with_sender(sender: Sender) -> impl Filter<Extract = (Sender,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || sender.clone())
+}
+
+pub fn with_fetcher(fetcher: SharedFetcher) -> impl Filter<Extract = (SharedFetcher,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || fetcher.clone())
+}
+
+/// Рассылает событие клиентам комнаты (или всем, если room = None)
+pub fn broadcast(sender: &Sender, room: Option<&str>, event: &ServerEvent) {
+    let audience = match room {
+        Some(room) => Audience::Room(room.to_string()),
+        None => Audience::All,
+    };
+    sender.publish(Broadcast::event(audience, event));
+}
+
+/// GET /api/ws?room={room} — WebSocket чата. Требует действующую сессию; забаненные пользователи
+/// и адреса отклоняются. Без параметра room клиент попадает в комнату general.
+pub fn chat_route(clients: Clients, sender: Sender, fetcher: SharedFetcher) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("ws"))
+        .and(warp::ws())
+        .and(allowed_origin())
+        .and(warp::query::<ChatQuery>())
+        .and(real_ip())
+        .and(authenticated())
+        .and(with_clients(clients))
+        .and(with_sender(sender))
+        .and(with_fetcher(fetcher))
+        .and_then(|ws: warp::ws::Ws, query: ChatQuery, ip: IpAddr, user: User, clients: Clients, sender: Sender, fetcher: SharedFetcher| async move {
+            match find_active_ban(Some(user.user_uuid), Some(ip)).await {
+                Ok(Some(ban)) => {
+                    info!("Rejected WebSocket connection of banned user {} from {} (ban {})", user.username, ip, ban.id);
+                    return Err(warp::reject::custom(Banned));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Failed to check bans: {}", e);
+                    return Err(warp::reject::custom(InternalError));
+                }
+            }
+
+            // Чужая комната неотличима от несуществующей
+            let room = query.room.unwrap_or_else(|| DEFAULT_ROOM.to_string());
+            match is_room_member(&room, user.user_uuid).await {
+                Ok(true) => {}
+                Ok(false) => return Err(warp::reject::not_found()),
+                Err(e) => {
+                    error!("Failed to check room membership: {}", e);
+                    return Err(warp::reject::custom(InternalError));
+                }
+            }
+
+            let max_frame = max_frame_bytes();
+            Ok(ws
+                .max_message_size(max_frame)
+                .max_frame_size(max_frame)
+                .on_upgrade(move |socket| client_connection(socket, clients, sender, fetcher, user, ip, room)))
+        })
+}
+
+async fn send_event(client_ws_sender: &WsSender, event: &ServerEvent) {
+    client_ws_sender.send(Message::text(event.to_json())).await;
+}
+
+/// Состояние одного WebSocket-соединения, общее для обработчиков команд
+struct Connection {
+    client_id: String,
+    user: User,
+    ip: IpAddr,
+    room: String,
+    clients: Clients,
+    sender: Sender,
+    fetcher: SharedFetcher,
+    ws: WsSender,
+    /// Когда последний раз разослано событие typing_started; None, если пользователь не печатает
+    typing_since: Option<Instant>,
+    /// Время отправки сообщений за последнюю минуту (rate_limits.messages_per_minute)
+    recent_messages: VecDeque<Instant>,
+    /// Когда клиент последний раз ответил на ping (pong) или прислал heartbeat
+    last_pong: Instant,
+}
+
+impl Connection {
+    /// Рассылает комнате начало набора текста, не чаще раза в TYPING_THROTTLE
+    fn typing_started(&mut self) {
+        if self.typing_since.is_some_and(|since| since.elapsed() < TYPING_THROTTLE) {
+            return;
+        }
+        self.typing_since = Some(Instant::now());
+        broadcast(&self.sender, Some(&self.room), &ServerEvent::TypingStarted { username: self.user.username.clone() });
+    }
+
+    /// Рассылает комнате окончание набора текста, если начало было разослано
+    fn typing_stopped(&mut self) {
+        if self.typing_since.take().is_some() {
+            broadcast(&self.sender, Some(&self.room), &ServerEvent::TypingStopped { username: self.user.username.clone() });
+        }
+    }
+
+    /// Учитывает новое сообщение; false, если за последнюю минуту отправлено слишком много
+    fn allow_message(&mut self) -> bool {
+        let limit = config::get().rate_limits.messages_per_minute as usize;
+        if limit == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        while self.recent_messages.front().is_some_and(|sent| now.duration_since(*sent) >= RATE_LIMIT_WINDOW) {
+            self.recent_messages.pop_front();
+        }
+        if self.recent_messages.len() >= limit {
+            return false;
+        }
+        self.recent_messages.push_back(now);
+        true
+    }
+}
+
+pub async fn client_connection(ws: WebSocket, clients: Clients, sender: Sender, fetcher: SharedFetcher, user: User, ip: IpAddr, room: String) {
+    let (sink, mut client_ws_rcv) = ws.split();
+    let (queue_tx, queue_rx) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+    let overflow = Arc::new(Notify::new());
+    let client_ws_sender = WsSender { queue: queue_tx, overflow: Arc::clone(&overflow) };
+    let writer = spawn_writer(sink, queue_rx);
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+    let username = user.username.clone();
 
     let client_id = {
         let mut clients = clients.lock().unwrap();
         let client_id = generate_client_id();
-        clients.insert(client_id.clone(), 0);
+        clients.insert(client_id.clone(), Client {
+            user_uuid: user.user_uuid,
+            role: user.role,
+            ip,
+            room: room.clone(),
+            control: control_tx,
+            open_threads: HashSet::new(),
+        });
         client_id
     };
 
-    info!("New client connected with ID: {}, username: {}", client_id, username);
+    info!("New client connected with ID: {}, username: {}, room: {}", client_id, username, room);
 
-    if let Err(e) = send_message_history(client_ws_sender.clone()).await {
-        error!("Failed to send message history: {}", e);
-    }
-    
-    let mut rx = sender.lock().unwrap().subscribe();
-    let username_clone = username.clone();
-    let clients_clone = Arc::clone(&clients);
-    let client_id_clone = client_id.clone();
+    // Подписываемся до загрузки истории, чтобы не потерять события между ними; повторы клиент отбрасывает
+    let rx = sender.subscribe();
+    sender.connected(&client_id, user.user_uuid, &room).await;
+
+    send_room_state(&client_ws_sender, &room, user.user_uuid).await;
+
+    // Сигнал пересылке остановиться, дописав уже полученные события
+    let (flush_tx, flush_rx) = oneshot::channel::<()>();
+    let forwarder = tokio::spawn(forward_broadcasts(rx, flush_rx, client_id.clone(), room.clone(), Arc::clone(&clients), client_ws_sender.clone()));
 
-    let ping_interval = TokioDuration::from_secs(30);
+    let mut connection = Connection {
+        client_id,
+        user,
+        ip,
+        room,
+        clients,
+        sender,
+        fetcher,
+        ws: client_ws_sender,
+        typing_since: None,
+        recent_messages: VecDeque::new(),
+        last_pong: Instant::now(),
+    };
+
+    let heartbeat = &config::get().chat;
+    let ping_interval = TokioDuration::from_secs(heartbeat.ping_interval_secs);
+    let idle_timeout = ping_interval * heartbeat.max_missed_pongs;
     let mut ping_timer = interval(ping_interval);
 
-    // Клонируем Arc для использования в асинхронной задаче
-    let client_ws_sender_task = Arc::clone(&client_ws_sender);
+    let mut shutting_down = false;
+    // Писателя не ждем: клиент либо не читает, либо уже не отвечает
+    let mut abandoned = false;
 
-    tokio::spawn(async move {
-        loop {
-            tokio::select! {
-                _ = ping_timer.tick() => {
-                    if let Err(e) = client_ws_sender_task.lock().await.send(Message::ping(vec![])).await {
-                        error!("Failed to send ping message: {}", e);
-                        let mut clients = clients_clone.lock().unwrap();
-                        clients.remove(&client_id_clone);
-                        info!("Client disconnected with ID: {}, username: {}", client_id_clone, username_clone);
-                        break;
-                    }
+    loop {
+        tokio::select! {
+            result = client_ws_rcv.next() => {
+                let msg = match result {
+                    Some(Ok(msg)) => msg,
+                    _ => break,
+                };
+                if msg.is_close() {
+                    break;
                 }
-                Ok(message) = rx.recv() => {
-                    debug!("Broadcasting message: {}", message);
-                    if let Err(e) = client_ws_sender_task.lock().await.send(Message::text(message)).await {
-                        error!("Failed to send message: {}", e);
-                        let mut clients = clients_clone.lock().unwrap();
-                        clients.remove(&client_id_clone);
-                        info!("Client disconnected with ID: {}, username: {}", client_id_clone, username_clone);
-                        break;
-                    }
+                if msg.is_pong() {
+                    connection.last_pong = Instant::now();
+                    continue;
                 }
-            }
-        }
-    });
-    
-    while let Some(result) = client_ws_rcv.next().await {
-        let msg = if let Ok(msg) = result {
-            if msg.is_text() {
-                let msg_str = msg.to_str().unwrap().to_owned();
+                if !msg.is_text() {
+                    continue;
+                }
+
+                let msg_str = msg.to_str().unwrap_or_default();
                 debug!("Received raw message: {}", msg_str);
-                let client_message: ClientMessage = match serde_json::from_str(&msg_str) {
-                    Ok(msg) => msg,
+                let command: ClientCommand = match serde_json::from_str(msg_str) {
+                    Ok(command) => command,
                     Err(e) => {
                         error!("Failed to deserialize message: {}", e);
+                        send_event(&connection.ws, &ServerEvent::Error { message: "Malformed command.".to_string() }).await;
                         continue;
                     }
                 };
-                debug!("Received message from client {}: {}", client_message.username, client_message.message);
-                let user = match find_user_by_username(&client_message.username).await {
-                    Ok(user) => user,
-                    Err(e) => {
-                        error!("Failed to find user: {}", e);
+
+                handle_command(command, &mut connection).await;
+            }
+            Some(control) = control_rx.recv() => match control {
+                Control::Kick(reason) => {
+                    info!("Kicking client with ID: {}, username: {}", connection.client_id, username);
+                    send_event(&connection.ws, &ServerEvent::Kicked { reason }).await;
+                    break;
+                }
+                Control::Shutdown => {
+                    shutting_down = true;
+                    break;
+                }
+            },
+            _ = overflow.notified() => {
+                warn!("Outbound queue of client {} stayed full, disconnecting {}", connection.client_id, username);
+                metrics::outbound_overflow();
+                abandoned = true;
+                break;
+            }
+            _ = ping_timer.tick() => {
+                if connection.last_pong.elapsed() >= idle_timeout {
+                    info!("Client {} missed {} pongs, disconnecting {}", connection.client_id, heartbeat.max_missed_pongs, username);
+                    metrics::idle_timeout();
+                    abandoned = true;
+                    break;
+                }
+                connection.ws.send(Message::ping(vec![])).await;
+            }
+        }
+    }
+
+    connection.typing_stopped();
+
+    if shutting_down {
+        // Команда, которую клиент успел прислать, уже обработана; дописываем рассылку и прощаемся
+        let _ = flush_tx.send(());
+        if let Err(e) = forwarder.await {
+            error!("Forwarder task failed: {}", e);
+        }
+        send_event(&connection.ws, &ServerEvent::ServerShutdown { reconnect_after_secs: shutdown::RECONNECT_AFTER_SECS }).await;
+        connection.ws.send(Message::close_with(CLOSE_GOING_AWAY, "Server shutting down")).await;
+    } else {
+        forwarder.abort();
+    }
+
+    // Писатель дописывает очередь и закрывает сокет, когда освобождается последний WsSender.
+    // Клиенту, который не читает, ждать нечего.
+    let Connection { client_id, clients, sender, ws, .. } = connection;
+    drop(ws);
+    if abandoned {
+        writer.abort();
+    } else if timeout(WRITER_DRAIN_TIMEOUT, writer).await.is_err() {
+        warn!("Timed out flushing messages to client {}", client_id);
+    }
+
+    sender.disconnected(&client_id).await;
+    let mut clients = clients.lock().unwrap();
+    clients.remove(&client_id);
+    info!("Client disconnected with ID: {}, username: {}", client_id, username);
+}
+
+/// Единственная задача, которая пишет в сокет клиента. Все, что накопилось в очереди,
+/// отправляется одной пачкой. Когда очередь закрыта, закрывает соединение.
+fn spawn_writer(mut sink: SplitSink<WebSocket, Message>, mut queue: mpsc::Receiver<Message>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some(message) = queue.recv().await {
+            let mut result = sink.feed(message).await;
+            while result.is_ok() {
+                match queue.try_recv() {
+                    Ok(message) => result = sink.feed(message).await,
+                    Err(_) => break,
+                }
+            }
+            if let Err(e) = result.and(sink.flush().await) {
+                debug!("Failed to write to client: {}", e);
+                return;
+            }
+        }
+
+        if let Err(e) = sink.close().await {
+            debug!("Failed to close client connection: {}", e);
+        }
+    })
+}
+
+/// Пересылает клиенту адресованные ему события из общего канала. По сигналу flush дописывает
+/// уже полученные события и завершается.
+async fn forward_broadcasts(mut rx: broadcast::Receiver<Broadcast>, mut flush: oneshot::Receiver<()>, client_id: String, room: String, clients: Clients, ws: WsSender) {
+    loop {
+        tokio::select! {
+            _ = &mut flush => {
+                loop {
+                    let message = match rx.try_recv() {
+                        Ok(message) => message,
+                        Err(TryRecvError::Lagged(_)) => continue,
+                        Err(_) => break,
+                    };
+                    if !deliver(message, &client_id, &room, &clients, &ws).await {
+                        break;
+                    }
+                }
+                break;
+            }
+            result = rx.recv() => {
+                let message = match result {
+                    Ok(message) => message,
+                    Err(RecvError::Lagged(skipped)) => {
+                        // Пропущенные события не восстановить из канала: клиент заново загрузит комнату (sync)
+                        warn!("Client {} lagged behind and missed {} event(s)", client_id, skipped);
+                        metrics::broadcast_lagged(skipped);
+                        send_event(&ws, &ServerEvent::Resync { missed: skipped }).await;
                         continue;
                     }
+                    Err(RecvError::Closed) => break,
                 };
+                if !deliver(message, &client_id, &room, &clients, &ws).await {
+                    break;
+                }
+            }
+        }
+    }
+}
 
-                if let Err(e) = save_message_to_db(&client_message.message, user.user_uuid).await {
-                    error!("Failed to save message to database: {}", e);
+/// Доставляет событие клиенту, если оно ему адресовано. false, если соединение закрывается.
+async fn deliver(message: Broadcast, client_id: &str, room: &str, clients: &Clients, ws: &WsSender) -> bool {
+    let matches = match &message.audience {
+        Audience::All => true,
+        Audience::Room(target) => target == room,
+        // Остальным адресатам нужны данные из реестра: пользователь, адрес, открытые ветки
+        audience => clients.lock().unwrap().get(client_id).is_some_and(|client| audience.matches(client)),
+    };
+    if !matches {
+        return true;
+    }
+
+    match message.payload {
+        Payload::Event(event) => {
+            debug!("Broadcasting message: {}", event);
+            ws.send(Message::text(&*event)).await
+        }
+        Payload::Disconnect(reason) => {
+            if let Some(client) = clients.lock().unwrap().get(client_id) {
+                let _ = client.control.send(Control::Kick(reason));
+            }
+            true
+        }
+    }
+}
+
+/// Отправляет клиенту состояние комнаты: историю, тему, закрепленные сообщения и счетчики
+/// непрочитанного. Так начинается каждое подключение, и то же самое клиент получает по команде sync.
+async fn send_room_state(client_ws_sender: &WsSender, room: &str, viewer: Uuid) {
+    match find_message_history(room, viewer).await {
+        Ok(history) => {
+            for message in history {
+                if !client_ws_sender.send(Message::text(ServerEvent::Message(message).to_json())).await {
+                    return;
                 }
+            }
+        }
+        Err(e) => error!("Failed to load message history: {}", e),
+    }
 
-                let formatted_message = format!("{}: {}", client_message.username, client_message.message);
-                formatted_message
-            } else if msg.is_close() {
-                info!("Client disconnected with ID: {}, username: {}", client_id, username);
-                let mut clients = clients.lock().unwrap();
-                clients.remove(&client_id);
-                break;
-            } else {
-                continue;
+    // Сразу после истории — тема и закрепленные сообщения комнаты
+    match find_room_topic(room).await {
+        Ok(Some(topic)) => send_event(client_ws_sender, &ServerEvent::Topic(topic)).await,
+        Ok(None) => {}
+        Err(e) => error!("Failed to load room topic: {}", e),
+    }
+    match find_pins(room, None, viewer).await {
+        Ok(pins) => send_event(client_ws_sender, &ServerEvent::Pins { pins }).await,
+        Err(e) => error!("Failed to load pinned messages: {}", e),
+    }
+
+    match find_unread_counts(viewer).await {
+        Ok(rooms) => send_event(client_ws_sender, &ServerEvent::Unread { rooms }).await,
+        Err(e) => error!("Failed to count unread messages: {}", e),
+    }
+}
+
+/// Обрабатывает одну команду клиента
+async fn handle_command(command: ClientCommand, connection: &mut Connection) {
+    // Набор текста меняет состояние соединения; отправка сообщения завершает набор
+    match command {
+        ClientCommand::TypingStart => return connection.typing_started(),
+        ClientCommand::TypingStop => return connection.typing_stopped(),
+        ClientCommand::Heartbeat => connection.last_pong = Instant::now(),
+        ClientCommand::Message { .. } => {
+            metrics::message_received();
+            connection.typing_stopped();
+            if !connection.allow_message() {
+                send_event(&connection.ws, &ServerEvent::Error { message: "You are sending messages too fast.".to_string() }).await;
+                return;
             }
-        } else {
-            break;
+        }
+        _ => {}
+    }
+
+    let Connection { client_id, user, ip, room, clients, sender, fetcher, ws: client_ws_sender, .. } = &*connection;
+    let ip = *ip;
+
+    match command {
+        ClientCommand::Message { message, parent_id: None, attachments } => {
+            handle_chat_message(message, attachments, user, room, sender, fetcher, client_ws_sender).await
+        }
+        ClientCommand::Message { message, parent_id: Some(parent_id), attachments } => {
+            handle_reply(message, attachments, parent_id, user, room, sender, fetcher, client_ws_sender).await
+        }
+        ClientCommand::TypingStart | ClientCommand::TypingStop => {}
+        ClientCommand::Sync => send_room_state(client_ws_sender, room, user.user_uuid).await,
+        ClientCommand::Heartbeat => send_event(client_ws_sender, &ServerEvent::Heartbeat).await,
+        ClientCommand::Read { id } => handle_read(id, user, room, sender, client_ws_sender).await,
+        ClientCommand::Thread { id } => open_thread(id, client_id, user, room, clients, client_ws_sender).await,
+        ClientCommand::CloseThread { id } => {
+            if let Some(client) = clients.lock().unwrap().get_mut(client_id.as_str()) {
+                client.open_threads.remove(&id);
+            }
+        }
+        ClientCommand::React { id, emoji } => handle_reaction(id, emoji, true, user, room, sender, client_ws_sender).await,
+        ClientCommand::Unreact { id, emoji } => handle_reaction(id, emoji, false, user, room, sender, client_ws_sender).await,
+        ClientCommand::Edit { id, message } => handle_edit_message(id, message, user, room, sender, fetcher, client_ws_sender).await,
+        ClientCommand::Delete { id } => {
+            if let Some(moderator) = current_moderator(user, client_ws_sender).await {
+                reply_moderation(client_ws_sender, moderation::delete_message(&moderator, ip, id, sender).await).await;
+            }
+        }
+        ClientCommand::Pin { id } => {
+            if let Some(moderator) = current_moderator(user, client_ws_sender).await {
+                reply_moderation(client_ws_sender, moderation::pin_message(&moderator, ip, id, sender).await).await;
+            }
+        }
+        ClientCommand::Unpin { id } => {
+            if let Some(moderator) = current_moderator(user, client_ws_sender).await {
+                reply_moderation(client_ws_sender, moderation::unpin_message(&moderator, ip, id, sender).await).await;
+            }
+        }
+        ClientCommand::SetTopic { topic, description } => {
+            if let Some(moderator) = current_moderator(user, client_ws_sender).await {
+                let data = TopicData { topic, description };
+                reply_moderation(client_ws_sender, moderation::set_topic(&moderator, ip, room, data, sender).await).await;
+            }
+        }
+        ClientCommand::Mute { username, minutes } => {
+            if let Some(moderator) = current_moderator(user, client_ws_sender).await {
+                reply_moderation(client_ws_sender, moderation::mute_user(&moderator, ip, &username, minutes).await).await;
+            }
+        }
+        ClientCommand::Kick { username, reason } => {
+            if let Some(moderator) = current_moderator(user, client_ws_sender).await {
+                reply_moderation(client_ws_sender, moderation::kick_user(&moderator, ip, &username, reason, clients, sender).await).await;
+            }
+        }
+        ClientCommand::Ban { username, ip: banned_ip, minutes, reason } => {
+            if let Some(moderator) = current_moderator(user, client_ws_sender).await {
+                let data = BanData { username, ip: banned_ip, minutes, reason };
+                reply_moderation(client_ws_sender, moderation::ban(&moderator, ip, data, clients, sender).await).await;
+            }
+        }
+    }
+}
+
+/// Возвращает пользователя, если он сейчас модератор или администратор. Роль перечитывается
+/// из базы, потому что она могла измениться с момента подключения.
+async fn current_moderator(user: &User, client_ws_sender: &WsSender) -> Option<User> {
+    match find_user_by_username(&user.username).await {
+        Ok(current) if current.role >= Role::Moderator => Some(current),
+        Ok(_) => {
+            send_event(client_ws_sender, &ServerEvent::Error { message: "Insufficient permissions.".to_string() }).await;
+            None
+        }
+        Err(e) => {
+            error!("Failed to find user: {}", e);
+            None
+        }
+    }
+}
+
+async fn reply_moderation(client_ws_sender: &WsSender, result: ModerationResult) {
+    let event = match result {
+        Ok(message) => ServerEvent::Notice { message },
+        Err((_, message)) => ServerEvent::Error { message },
+    };
+    send_event(client_ws_sender, &event).await;
+}
+
+/// Проверяет, может ли пользователь сейчас писать в чат. Заглушенному пользователю отправляется ошибка.
+async fn can_post(user: &User, client_ws_sender: &WsSender) -> bool {
+    match find_active_mute(user.user_uuid).await {
+        Ok(Some(muted_until)) => {
+            let message = format!("You are muted until {}.", muted_until.to_rfc3339());
+            send_event(client_ws_sender, &ServerEvent::Error { message }).await;
+            false
+        }
+        Ok(None) => true,
+        Err(e) => {
+            error!("Failed to check mute: {}", e);
+            false
+        }
+    }
+}
+
+/// Проверяет длину сообщения (chat.max_message_chars) до разбора Markdown; о слишком
+/// длинном сообщении сообщает клиенту
+async fn check_message_length(message: &str, client_ws_sender: &WsSender) -> bool {
+    let max_chars = config::get().chat.max_message_chars;
+    if message.chars().count() > max_chars {
+        send_event(client_ws_sender, &ServerEvent::Error { message: format!("Message must be at most {} characters.", max_chars) }).await;
+        return false;
+    }
+    true
+}
+
+/// Убирает повторы из id вложений и проверяет их число (MAX_ATTACHMENTS); о превышении
+/// сообщает клиенту
+async fn check_attachments(attachments: &mut Vec<i64>, client_ws_sender: &WsSender) -> bool {
+    attachments.sort_unstable();
+    attachments.dedup();
+    if attachments.len() > MAX_ATTACHMENTS {
+        send_event(client_ws_sender, &ServerEvent::Error { message: format!("A message can have at most {} attachments.", MAX_ATTACHMENTS) }).await;
+        return false;
+    }
+    true
+}
+
+/// Сообщает клиенту, если часть загрузок не прикрепилась: чужие, уже прикрепленные или удаленные
+async fn report_missing_attachments(requested: &[i64], attached: &[Attachment], client_ws_sender: &WsSender) {
+    if attached.len() < requested.len() {
+        send_event(client_ws_sender, &ServerEvent::Error { message: "Some files could not be attached.".to_string() }).await;
+    }
+}
+
+/// Наибольший кадр WebSocket: команда с сообщением максимальной длины, где каждый символ
+/// в JSON может занимать до 6 байт (\uXXXX), и запас на остальные поля команды
+fn max_frame_bytes() -> usize {
+    config::get().chat.max_message_chars * 6 + 4096
+}
+
+/// Окно, в течение которого автор может редактировать сообщение (chat.edit_window_secs)
+fn edit_window() -> chrono::Duration {
+    chrono::Duration::seconds(config::get().chat.edit_window_secs)
+}
+
+/// Сохраняет сообщение пользователя вместе с вложениями и рассылает его клиентам комнаты
+async fn handle_chat_message(message: String, mut attachments: Vec<i64>, user: &User, room: &str, sender: &Sender, fetcher: &SharedFetcher, client_ws_sender: &WsSender) {
+    if message.trim().is_empty() || !check_message_length(&message, client_ws_sender).await {
+        return;
+    }
+
+    if !check_attachments(&mut attachments, client_ws_sender).await {
+        return;
+    }
+
+    if !can_post(user, client_ws_sender).await {
+        return;
+    }
+
+    let mentions = parse_mentions(&message);
+
+    debug!("Received message from client {}: {}", user.username, message);
+
+    let html = markdown::render(&message);
+    let (id, timestamp, attached) = match save_message_to_db(&message, &html, user.user_uuid, room, None, &attachments).await {
+        Ok(saved) => saved,
+        Err(e) => {
+            error!("Failed to save message to database: {}", e);
+            send_event(client_ws_sender, &ServerEvent::Error { message: "Failed to save message.".to_string() }).await;
+            return;
+        }
+    };
+    report_missing_attachments(&attachments, &attached, client_ws_sender).await;
+
+    let event = ServerEvent::Message(ChatMessage {
+        id,
+        username: user.username.clone(),
+        message: message.clone(),
+        html,
+        timestamp,
+        edited_at: None,
+        parent_id: None,
+        reply_count: 0,
+        last_reply_at: None,
+        reactions: Vec::new(),
+        preview: None,
+        attachments: attached,
+    });
+
+    broadcast(sender, Some(room), &event);
+    spawn_link_preview(id, &message, room, fetcher, sender);
+
+    if !mentions.is_empty() {
+        notify_mentions(&mentions, id, room, user, sender, client_ws_sender).await;
+    }
+}
+
+/// Редактирует собственное сообщение пользователя в текущей комнате и рассылает новую версию
+/// клиентам комнаты
+async fn handle_edit_message(id: i64, message: String, user: &User, room: &str, sender: &Sender, fetcher: &SharedFetcher, client_ws_sender: &WsSender) {
+    if message.trim().is_empty() {
+        send_event(client_ws_sender, &ServerEvent::Error { message: "Message cannot be empty.".to_string() }).await;
+        return;
+    }
+
+    if !check_message_length(&message, client_ws_sender).await {
+        return;
+    }
+
+    if !can_post(user, client_ws_sender).await {
+        return;
+    }
+
+    let html = markdown::render(&message);
+    let failure = match edit_message(id, room, user.user_uuid, &message, &html, edit_window()).await {
+        Ok(EditOutcome::Edited { edited_at }) => {
+            info!("Message {} edited by {}", id, user.username);
+            broadcast(sender, Some(room), &ServerEvent::Edited { id, message: message.clone(), html, edited_at });
+            spawn_link_preview(id, &message, room, fetcher, sender);
+            return;
+        }
+        Ok(EditOutcome::NotFound) => "Message not found.",
+        Ok(EditOutcome::NotAuthor) => "You can only edit your own messages.",
+        Ok(EditOutcome::WindowExpired) => "This message can no longer be edited.",
+        Err(e) => {
+            error!("Failed to edit message: {}", e);
+            "Failed to edit message."
+        }
+    };
+
+    send_event(client_ws_sender, &ServerEvent::Error { message: failure.to_string() }).await;
+}
+
+/// Отправляет клиенту ответы ветки текущей комнаты и подписывает его на ее обновления
+async fn open_thread(id: i64, client_id: &str, user: &User, room: &str, clients: &Clients, client_ws_sender: &WsSender) {
+    // Сообщение из другой комнаты неотличимо от несуществующего
+    let root_id = match find_thread_root(id, room).await {
+        Ok(Some(root_id)) => root_id,
+        Ok(None) => {
+            send_event(client_ws_sender, &ServerEvent::Error { message: "Message not found.".to_string() }).await;
+            return;
+        }
+        Err(e) => {
+            error!("Failed to find thread: {}", e);
+            return;
+        }
+    };
+
+    let replies = match find_thread_replies(root_id, user.user_uuid).await {
+        Ok(replies) => replies,
+        Err(e) => {
+            error!("Failed to load thread {}: {}", root_id, e);
+            send_event(client_ws_sender, &ServerEvent::Error { message: "Failed to load thread.".to_string() }).await;
+            return;
+        }
+    };
+
+    if let Some(client) = clients.lock().unwrap().get_mut(client_id) {
+        client.open_threads.insert(root_id);
+    }
+
+    send_event(client_ws_sender, &ServerEvent::Thread { id: root_id, replies }).await;
+}
+
+/// Сохраняет ответ в ветке и рассылает его вместе с новой сводкой ветки только тем,
+/// у кого ветка открыта, и участникам ветки. Отвечать можно только в ветках текущей комнаты.
+#[allow(clippy::too_many_arguments)]
+async fn handle_reply(message: String, mut attachments: Vec<i64>, parent_id: i64, user: &User, room: &str, sender: &Sender, fetcher: &SharedFetcher, client_ws_sender: &WsSender) {
+    if message.trim().is_empty() || !check_message_length(&message, client_ws_sender).await {
+        return;
+    }
+
+    if !check_attachments(&mut attachments, client_ws_sender).await {
+        return;
+    }
+
+    if !can_post(user, client_ws_sender).await {
+        return;
+    }
+
+    let mentions = parse_mentions(&message);
+
+    let root_id = match find_thread_root(parent_id, room).await {
+        Ok(Some(root_id)) => root_id,
+        Ok(None) => {
+            send_event(client_ws_sender, &ServerEvent::Error { message: "Message not found.".to_string() }).await;
+            return;
+        }
+        Err(e) => {
+            error!("Failed to find thread: {}", e);
+            return;
+        }
+    };
+
+    let html = markdown::render(&message);
+    let (id, timestamp, attached) = match save_message_to_db(&message, &html, user.user_uuid, room, Some(root_id), &attachments).await {
+        Ok(saved) => saved,
+        Err(e) => {
+            error!("Failed to save reply to database: {}", e);
+            send_event(client_ws_sender, &ServerEvent::Error { message: "Failed to save message.".to_string() }).await;
+            return;
+        }
+    };
+    report_missing_attachments(&attachments, &attached, client_ws_sender).await;
+
+    let (reply_count, last_reply_at, participants) = match find_thread_summary(root_id).await {
+        Ok(summary) => summary,
+        Err(e) => {
+            error!("Failed to load thread summary: {}", e);
+            return;
+        }
+    };
+
+    let reply = ServerEvent::Message(ChatMessage {
+        id,
+        username: user.username.clone(),
+        message: message.clone(),
+        html,
+        timestamp,
+        edited_at: None,
+        parent_id: Some(root_id),
+        reply_count: 0,
+        last_reply_at: None,
+        reactions: Vec::new(),
+        preview: None,
+        attachments: attached,
+    });
+    let update = ServerEvent::ThreadUpdated { id: root_id, reply_count, last_reply_at };
+
+    if !mentions.is_empty() {
+        notify_mentions(&mentions, id, room, user, sender, client_ws_sender).await;
+    }
+
+    let audience = Audience::Thread { root: root_id, participants };
+    sender.publish(Broadcast::event(audience.clone(), &reply));
+    sender.publish(Broadcast::event(audience, &update));
+
+    spawn_link_preview(id, &message, room, fetcher, sender);
+}
+
+/// Загружает в фоне превью первой ссылки сообщения и рассылает его клиентам комнаты.
+/// Превью не привязывается, если сообщение успели удалить или отредактировать.
+fn spawn_link_preview(id: i64, message: &str, room: &str, fetcher: &SharedFetcher, sender: &Sender) {
+    let Some(url) = markdown::first_link(message) else {
+        return;
+    };
+    let (message, room, fetcher, sender) = (message.to_string(), room.to_string(), Arc::clone(fetcher), Arc::clone(sender));
+
+    tokio::spawn(async move {
+        let Some(preview) = previews::load_preview(fetcher.as_ref(), &url).await else {
+            return;
         };
+        match set_message_preview(id, &message, &url).await {
+            Ok(true) => broadcast(&sender, Some(&room), &ServerEvent::Preview { id, preview }),
+            Ok(false) => debug!("Message {} changed before its preview was loaded", id),
+            Err(e) => error!("Failed to attach preview to message {}: {}", id, e),
+        }
+    });
+}
+
+/// Ставит или снимает реакцию на сообщение текущей комнаты и рассылает изменение клиентам комнаты
+async fn handle_reaction(id: i64, emoji: String, add: bool, user: &User, room: &str, sender: &Sender, client_ws_sender: &WsSender) {
+    // Снять можно и реакцию, сохраненную до ужесточения проверки
+    if add && !is_emoji(&emoji) {
+        send_event(client_ws_sender, &ServerEvent::Error { message: "Reaction must be a single emoji.".to_string() }).await;
+        return;
+    }
+
+    if add && !can_post(user, client_ws_sender).await {
+        return;
+    }
 
-        if let Err(e) = sender.lock().unwrap().send(msg) {
-            error!("Failed to send message to broadcast: {}", e);
+    match message_in_room(id, room).await {
+        Ok(true) => {}
+        Ok(false) => {
+            send_event(client_ws_sender, &ServerEvent::Error { message: "Message not found.".to_string() }).await;
+            return;
+        }
+        Err(e) => {
+            error!("Failed to find message: {}", e);
+            send_event(client_ws_sender, &ServerEvent::Error { message: "Failed to update reaction.".to_string() }).await;
+            return;
         }
     }
 
-    if let Err(e) = client_ws_sender.lock().await.close().await {
-        error!("Failed to close client connection: {}", e);
+    let changed = if add {
+        add_reaction(id, user.user_uuid, &emoji).await
+    } else {
+        remove_reaction(id, user.user_uuid, &emoji).await
+    };
+
+    // Повторная реакция или снятие несуществующей ничего не меняют
+    match changed {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            error!("Failed to update reaction: {}", e);
+            send_event(client_ws_sender, &ServerEvent::Error { message: "Failed to update reaction.".to_string() }).await;
+            return;
+        }
     }
 
-    let mut clients = clients.lock().unwrap();
-    clients.remove(&client_id);
-    info!("Client disconnected with ID: {}, username: {}", client_id, username);
+    let count = match count_reactions(id, &emoji).await {
+        Ok(count) => count,
+        Err(e) => {
+            error!("Failed to count reactions: {}", e);
+            return;
+        }
+    };
+
+    let username = user.username.clone();
+    let event = if add {
+        ServerEvent::ReactionAdded { id, emoji, username, count }
+    } else {
+        ServerEvent::ReactionRemoved { id, emoji, username, count }
+    };
+
+    broadcast(sender, Some(room), &event);
+}
+
+/// Сдвигает отметку прочтения в текущей комнате и рассылает квитанцию о прочтении
+async fn handle_read(id: i64, user: &User, room: &str, sender: &Sender, client_ws_sender: &WsSender) {
+    match save_read_marker(user.user_uuid, room, id).await {
+        Ok(true) => broadcast(sender, Some(room), &ServerEvent::ReadReceipt { username: user.username.clone(), id }),
+        // Сообщение из другой комнаты или отметка уже дальше
+        Ok(false) => {}
+        Err(e) => {
+            error!("Failed to save read marker: {}", e);
+            send_event(client_ws_sender, &ServerEvent::Error { message: "Failed to save read marker.".to_string() }).await;
+        }
+    }
+}
+
+/// Создает уведомления для упомянутых в сообщении участников комнаты и отправляет их
+/// живым соединениям получателей. @here и @room доступны только модераторам.
+async fn notify_mentions(mentions: &Mentions, message_id: i64, room: &str, author: &User, sender: &Sender, client_ws_sender: &WsSender) {
+    let mut recipients: Vec<(Uuid, &str)> = Vec::new();
+
+    if !mentions.usernames.is_empty() {
+        match find_room_members_by_usernames(room, &mentions.usernames).await {
+            Ok(users) => recipients.extend(users.into_iter().map(|user_uuid| (user_uuid, "mention"))),
+            Err(e) => error!("Failed to resolve mentions: {}", e),
+        }
+    }
+
+    if mentions.here || mentions.room {
+        // Роль перечитывается из базы, как и для команд модерации
+        let is_moderator = matches!(find_user_by_username(&author.username).await, Ok(current) if current.role >= Role::Moderator);
+        if !is_moderator {
+            send_event(client_ws_sender, &ServerEvent::Error { message: "Only moderators can use @here and @room.".to_string() }).await;
+        } else {
+            if mentions.here {
+                let present = sender.room_presence(room).await;
+                recipients.extend(present.into_iter().map(|user_uuid| (user_uuid, "here")));
+            }
+            if mentions.room {
+                match find_room_members(room).await {
+                    Ok(members) => recipients.extend(members.into_iter().map(|user_uuid| (user_uuid, "room"))),
+                    Err(e) => error!("Failed to find room members: {}", e),
+                }
+            }
+        }
+    }
+
+    // Себя не уведомляем; при нескольких видах упоминания остается первый (прямое упоминание важнее)
+    let mut seen = HashSet::new();
+    recipients.retain(|(user_uuid, _)| *user_uuid != author.user_uuid && seen.insert(*user_uuid));
+    if recipients.is_empty() {
+        return;
+    }
+
+    let notifications = match save_notifications(message_id, &recipients).await {
+        Ok(notifications) => notifications,
+        Err(e) => {
+            error!("Failed to save notifications: {}", e);
+            return;
+        }
+    };
+
+    debug!("Message {} notified {} users", message_id, notifications.len());
+
+    for (user_uuid, notification) in notifications {
+        sender.publish(Broadcast::event(Audience::Users(vec![user_uuid]), &ServerEvent::Notification(notification)));
+    }
 }
@@ -0,0 +1,195 @@
+use warp::{Filter, Rejection, Reply, http::StatusCode};
+use warp::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use warp::hyper::Body;
+use bytes::Bytes;
+use futures_util::{stream, StreamExt};
+use crate::audit;
+use crate::db::{export_messages, find_user_by_username, is_room_member};
+use crate::models::{User, Role, ExportScope, ExportedMessage};
+use crate::permissions::authenticated;
+use crate::utils::{real_ip, escape_html};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::net::IpAddr;
+
+/// Параметры запроса GET /api/export. Нужен ровно один из room и user.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ExportQuery {
+    pub room: Option<String>,
+    /// Имя пользователя; чужие сообщения может выгрузить только администратор
+    pub user: Option<String>,
+    /// jsonl (по умолчанию) или html
+    pub format: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ExportErrorResponse {
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExportFormat {
+    JsonLines,
+    Html,
+}
+
+fn error_response(message: &str, status: StatusCode) -> warp::reply::Response {
+    let response = ExportErrorResponse { message: message.to_string() };
+    warp::reply::with_status(warp::reply::json(&response), status).into_response()
+}
+
+pub async fn export_handler(ip: IpAddr, user: User, query: ExportQuery) -> Result<warp::reply::Response, Rejection> {
+    let format = match query.format.as_deref() {
+        None | Some("jsonl") => ExportFormat::JsonLines,
+        Some("html") => ExportFormat::Html,
+        Some(_) => return Ok(error_response("Format must be jsonl or html.", StatusCode::BAD_REQUEST)),
+    };
+
+    let (scope, name) = match (query.room, query.user) {
+        // Комнату выгружают только ее участники; чужая комната неотличима от несуществующей
+        (Some(room), None) => {
+            match is_room_member(&room, user.user_uuid).await {
+                Ok(true) => {}
+                Ok(false) => return Ok(error_response("Room not found.", StatusCode::NOT_FOUND)),
+                Err(e) => {
+                    error!("Failed to check room: {}", e);
+                    return Ok(error_response("Failed to export messages.", StatusCode::INTERNAL_SERVER_ERROR));
+                }
+            }
+            (ExportScope::Room(room.clone()), format!("room-{}", room))
+        }
+        (None, Some(username)) if username == user.username => (ExportScope::User(user.user_uuid), format!("user-{}", username)),
+        (None, Some(username)) => {
+            if user.role < Role::Admin {
+                return Ok(error_response("You can only export your own messages.", StatusCode::FORBIDDEN));
+            }
+            let target = match find_user_by_username(&username).await {
+                Ok(target) => target,
+                Err(_) => return Ok(error_response("User not found.", StatusCode::NOT_FOUND)),
+            };
+            audit::record(audit::USER_EXPORTED, Some(user.user_uuid), Some(target.user_uuid), Some(ip), json!({ "format": query.format })).await;
+            (ExportScope::User(target.user_uuid), format!("user-{}", target.username))
+        }
+        _ => return Ok(error_response("Specify either room or user.", StatusCode::BAD_REQUEST)),
+    };
+
+    let messages = match export_messages(&scope).await {
+        Ok(messages) => messages,
+        Err(e) => {
+            error!("Failed to export messages: {}", e);
+            return Ok(error_response("Failed to export messages.", StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+
+    info!("User {} exports {:?} as {:?}", user.username, scope, format);
+
+    // Ошибка посреди выгрузки обрывает ответ: клиент получит неполный файл, а не ложный успех
+    let file_name = safe_file_name(&name);
+    let title = name.clone();
+    let lines = messages.map(move |message| match message {
+        Ok(message) => Ok(Bytes::from(match format {
+            ExportFormat::JsonLines => export_json_line(&message),
+            ExportFormat::Html => export_html_entry(&message),
+        })),
+        Err(e) => {
+            error!("Export of {} interrupted: {}", title, e);
+            Err(e)
+        }
+    });
+
+    let (body, content_type, extension) = match format {
+        ExportFormat::JsonLines => (Body::wrap_stream(lines), "application/x-ndjson; charset=utf-8", "jsonl"),
+        ExportFormat::Html => {
+            let header = stream::once(async move { Ok(Bytes::from(export_html_header(&name))) });
+            let footer = stream::once(async { Ok(Bytes::from_static(b"</body>\n</html>\n")) });
+            (Body::wrap_stream(header.chain(lines).chain(footer)), "text/html; charset=utf-8", "html")
+        }
+    };
+
+    let mut response = warp::reply::Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, content_type.parse().unwrap());
+    headers.insert(CONTENT_DISPOSITION, format!("attachment; filename=\"cyb3ria-{}.{}\"", file_name, extension).parse().unwrap());
+    Ok(response)
+}
+
+// В заголовок Content-Disposition попадают только безопасные символы имени
+fn safe_file_name(name: &str) -> String {
+    name.chars().filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_')).collect()
+}
+
+fn export_json_line(message: &ExportedMessage) -> String {
+    let mut line = serde_json::to_string(message).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+fn export_html_header(title: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>cyb3ria: {}</title>\n</head>\n<body>\n<h1>{}</h1>\n",
+        escape_html(title),
+        escape_html(title)
+    )
+}
+
+/// Сообщение в HTML-выгрузке. Текст сообщения — уже очищенный HTML, все остальное, включая
+/// имена и ссылки вложений, экранируется.
+fn export_html_entry(message: &ExportedMessage) -> String {
+    let mut html = format!(
+        "<article id=\"m{}\">\n<header><b>{}</b> in #{} <time datetime=\"{}\">{}</time>",
+        message.id,
+        escape_html(&message.username),
+        escape_html(&message.room),
+        message.timestamp.to_rfc3339(),
+        message.timestamp.format("%Y-%m-%d %H:%M:%S UTC"),
+    );
+    if let Some(parent_id) = message.parent_id {
+        html.push_str(&format!(" reply to <a href=\"#m{}\">#{}</a>", parent_id, parent_id));
+    }
+    if let Some(edited_at) = message.edited_at {
+        html.push_str(&format!(" <small>(edited {})</small>", edited_at.format("%Y-%m-%d %H:%M:%S UTC")));
+    }
+    html.push_str("</header>\n<div>");
+    html.push_str(&message.html);
+    html.push_str("</div>\n");
+
+    if !message.attachments.is_empty() {
+        html.push_str("<ul>\n");
+        for attachment in &message.attachments {
+            html.push_str(&format!(
+                "<li><a href=\"{}\">{}</a> <small>({} bytes)</small></li>\n",
+                escape_html(&attachment.url),
+                escape_html(&attachment.file_name),
+                attachment.size
+            ));
+        }
+        html.push_str("</ul>\n");
+    }
+
+    if !message.edits.is_empty() {
+        html.push_str(&format!("<details><summary>{} earlier version(s)</summary>\n<ol>\n", message.edits.len()));
+        for edit in &message.edits {
+            html.push_str(&format!(
+                "<li>{} <small>(replaced <time datetime=\"{}\">{}</time>)</small></li>\n",
+                escape_html(&edit.message),
+                edit.replaced_at.to_rfc3339(),
+                edit.replaced_at.format("%Y-%m-%d %H:%M:%S UTC")
+            ));
+        }
+        html.push_str("</ol></details>\n");
+    }
+
+    html.push_str("</article>\n");
+    html
+}
+
+/// GET /api/export?room={room}|user={username}&format=jsonl|html — потоковая выгрузка истории
+pub fn export_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "export")
+        .and(warp::get())
+        .and(real_ip())
+        .and(authenticated())
+        .and(warp::query::<ExportQuery>())
+        .and_then(export_handler)
+}
@@ -0,0 +1,263 @@
+//! Фронтенд (static/) и загруженные файлы (uploaded/) без nginx (static_files.enabled).
+//!
+//! Файлы отдаются с ETag и Last-Modified, на условные запросы сервер отвечает 304. Если рядом
+//! с файлом лежит его сжатая копия (styles.css.br, styles.css.gz), она отдается клиентам,
+//! которые ее принимают. Загруженные файлы видят только вошедшие пользователи.
+
+use warp::{Filter, Rejection, Reply, http::StatusCode};
+use warp::filters::path::Tail;
+use warp::http::Uri;
+use warp::http::header::{
+    CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_SECURITY_POLICY, CONTENT_TYPE, ETAG, LAST_MODIFIED, VARY,
+    X_CONTENT_TYPE_OPTIONS,
+};
+use warp::hyper::Body;
+use warp::reply::Response;
+use crate::config;
+use crate::models::User;
+use crate::permissions::authenticated;
+use log::error;
+use percent_encoding::percent_decode_str;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+
+/// Страница, которую получает клиент на любой несуществующий адрес
+const NOT_FOUND_PAGE: &str = "404.html";
+
+/// Куда ведет корень сайта
+const INDEX_PAGE: &str = "/static/choice.html";
+
+/// HTML проверяется при каждом открытии, чтобы новая версия фронтенда подхватывалась сразу.
+/// Имена CSS и JS не содержат хеша, поэтому и их кешируем ненадолго.
+const HTML_CACHE_CONTROL: &str = "no-cache";
+const ASSET_CACHE_CONTROL: &str = "public, max-age=3600";
+/// Загрузки закрыты сессией: их не должны хранить общие кеши, а доступ проверяется каждый раз
+const UPLOAD_CACHE_CONTROL: &str = "private, no-cache";
+
+/// Сжатые копии в порядке предпочтения: расширение файла и Content-Encoding
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gz", "gzip")];
+
+/// Заголовки условного запроса
+struct Conditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    accept_encoding: Option<String>,
+}
+
+fn conditions() -> impl Filter<Extract = (Conditions,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("if-none-match")
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .map(|if_none_match, if_modified_since, accept_encoding| Conditions { if_none_match, if_modified_since, accept_encoding })
+}
+
+/// GET или HEAD; true для HEAD
+fn get_or_head() -> impl Filter<Extract = (bool,), Error = Rejection> + Clone {
+    warp::get().map(|| false).or(warp::head().map(|| true)).unify()
+}
+
+/// Маршруты работают, только если включен static_files.enabled
+fn enabled() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(|| async {
+            if config::get().static_files.enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// Файл внутри root по хвосту URL. None, если путь выходит за root, ведет к скрытому файлу
+/// или не указывает на обычный файл.
+async fn resolve(root: &Path, tail: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(tail).decode_utf8().ok()?;
+    let mut path = root.to_path_buf();
+    for segment in decoded.split('/') {
+        if segment.is_empty() || segment.starts_with('.') || segment.contains(['\\', '\0']) {
+            return None;
+        }
+        path.push(segment);
+    }
+    file_within(root, &path).await
+}
+
+/// Обычный файл path, если после разрешения символических ссылок он лежит внутри root
+async fn file_within(root: &Path, path: &Path) -> Option<PathBuf> {
+    let path = tokio::fs::canonicalize(path).await.ok()?;
+    let root = tokio::fs::canonicalize(root).await.ok()?;
+    if !path.starts_with(&root) {
+        return None;
+    }
+
+    let metadata = tokio::fs::metadata(&path).await.ok()?;
+    metadata.is_file().then_some(path)
+}
+
+/// Принимает ли клиент кодировку (Accept-Encoding, q=0 — отказ)
+fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|item| {
+        let mut parts = item.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let refused = parts.any(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+        name.eq_ignore_ascii_case(encoding) && !refused
+    })
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default()
+}
+
+/// ETag из времени изменения и размера, как у nginx; у сжатой копии — свой
+fn etag(metadata: &Metadata, encoding: Option<&str>) -> String {
+    let modified = metadata.modified().map(unix_secs).unwrap_or_default();
+    match encoding {
+        Some(encoding) => format!("\"{:x}-{:x}-{}\"", modified, metadata.len(), encoding),
+        None => format!("\"{:x}-{:x}\"", modified, metadata.len()),
+    }
+}
+
+fn is_not_modified(conditions: &Conditions, etag: &str, modified: Option<SystemTime>) -> bool {
+    // If-None-Match важнее If-Modified-Since
+    if let Some(tags) = &conditions.if_none_match {
+        return tags.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    match (conditions.if_modified_since.as_deref().and_then(|since| httpdate::parse_http_date(since).ok()), modified) {
+        (Some(since), Some(modified)) => unix_secs(modified) <= unix_secs(since),
+        _ => false,
+    }
+}
+
+/// Отдает файл из root (или его сжатую копию) с заголовками кеширования
+async fn serve_file(root: &Path, path: PathBuf, head: bool, cache_control: &'static str, conditions: Conditions) -> Response {
+    let content_type = mime_guess::from_path(&path).first_or_octet_stream();
+
+    let mut file_path = path;
+    let mut encoding = None;
+    if let Some(accept_encoding) = &conditions.accept_encoding {
+        for (extension, name) in PRECOMPRESSED {
+            let mut compressed = file_path.clone().into_os_string();
+            compressed.push(".");
+            compressed.push(extension);
+            if !accepts_encoding(accept_encoding, name) {
+                continue;
+            }
+            // Сжатая копия может оказаться символической ссылкой за пределы root
+            if let Some(compressed) = file_within(root, Path::new(&compressed)).await {
+                file_path = compressed;
+                encoding = Some(name);
+                break;
+            }
+        }
+    }
+
+    let file = match File::open(&file_path).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Failed to open {}: {}", file_path.display(), e);
+            return not_found_page().await;
+        }
+    };
+    let metadata = match file.metadata().await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            error!("Failed to read metadata of {}: {}", file_path.display(), e);
+            return not_found_page().await;
+        }
+    };
+
+    let etag = etag(&metadata, encoding);
+    let modified = metadata.modified().ok();
+    let not_modified = is_not_modified(&conditions, &etag, modified);
+
+    let mut response = if not_modified || head {
+        Response::new(Body::empty())
+    } else {
+        Response::new(Body::wrap_stream(ReaderStream::new(file)))
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(ETAG, etag.parse().unwrap());
+    if let Some(modified) = modified {
+        headers.insert(LAST_MODIFIED, httpdate::fmt_http_date(modified).parse().unwrap());
+    }
+    headers.insert(CACHE_CONTROL, cache_control.parse().unwrap());
+    headers.insert(VARY, "accept-encoding".parse().unwrap());
+    if not_modified {
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        return response;
+    }
+
+    // Фронтенд и текстовые файлы — в UTF-8
+    let content_type = match content_type.type_() {
+        mime_guess::mime::TEXT => format!("{}; charset=utf-8", content_type),
+        _ if content_type.subtype() == mime_guess::mime::JAVASCRIPT => format!("{}; charset=utf-8", content_type),
+        _ => content_type.to_string(),
+    };
+    headers.insert(CONTENT_TYPE, content_type.parse().unwrap());
+    headers.insert(CONTENT_LENGTH, metadata.len().into());
+    headers.insert(X_CONTENT_TYPE_OPTIONS, "nosniff".parse().unwrap());
+    if let Some(encoding) = encoding {
+        headers.insert(CONTENT_ENCODING, encoding.parse().unwrap());
+    }
+    response
+}
+
+/// Страница 404.html со статусом 404; если ее нет, пустой ответ 404
+pub async fn not_found_page() -> Response {
+    let path = config::get().static_files.root.join(NOT_FOUND_PAGE);
+    match tokio::fs::read(&path).await {
+        Ok(page) => warp::reply::with_status(warp::reply::html(page), StatusCode::NOT_FOUND).into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn static_handler(tail: Tail, head: bool, conditions: Conditions) -> Response {
+    let root = &config::get().static_files.root;
+    let Some(path) = resolve(root, tail.as_str()).await else {
+        return not_found_page().await;
+    };
+    let is_html = path.extension().is_some_and(|extension| extension == "html");
+    serve_file(root, path, head, if is_html { HTML_CACHE_CONTROL } else { ASSET_CACHE_CONTROL }, conditions).await
+}
+
+async fn upload_handler(tail: Tail, head: bool, _user: User, conditions: Conditions) -> Response {
+    let root = &config::get().uploads.root;
+    let Some(path) = resolve(root, tail.as_str()).await else {
+        return not_found_page().await;
+    };
+    let mut response = serve_file(root, path, head, UPLOAD_CACHE_CONTROL, conditions).await;
+    // Загруженный HTML или SVG не должен выполнять скрипты от имени сайта
+    response.headers_mut().insert(CONTENT_SECURITY_POLICY, "sandbox".parse().unwrap());
+    response
+}
+
+/// Раздача файлов вместо nginx (только при static_files.enabled):
+///
+/// - GET / — перенаправление на страницу выбора
+/// - GET, HEAD /static/{path} — фронтенд
+/// - GET, HEAD /uploaded/{path} — загруженные файлы; требует сессию
+pub fn files_routes() -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let index = warp::path::end()
+        .and(warp::get())
+        .map(|| warp::redirect(Uri::from_static(INDEX_PAGE)).into_response());
+
+    let static_files = warp::path("static")
+        .and(warp::path::tail())
+        .and(get_or_head())
+        .and(conditions())
+        .then(static_handler);
+
+    let uploads = warp::path("uploaded")
+        .and(warp::path::tail())
+        .and(get_or_head())
+        .and(authenticated())
+        .and(conditions())
+        .then(upload_handler);
+
+    enabled().and(index.or(static_files).unify().or(uploads).unify())
+}
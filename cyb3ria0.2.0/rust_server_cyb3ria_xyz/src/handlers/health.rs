@@ -0,0 +1,64 @@
+use warp::{Filter, Rejection, Reply, http::StatusCode};
+use warp::http::header::CONTENT_TYPE;
+use crate::db::ping;
+use crate::handlers::chat::{Clients, with_clients};
+use crate::metrics;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Сколько /readyz ждет ответа базы данных
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HealthResponse {
+    pub status: String,
+}
+
+fn health_response(status: &str, code: StatusCode) -> warp::reply::Response {
+    let response = HealthResponse { status: status.to_string() };
+    warp::reply::with_status(warp::reply::json(&response), code).into_response()
+}
+
+pub async fn readyz_handler() -> Result<warp::reply::Response, Rejection> {
+    match tokio::time::timeout(READINESS_TIMEOUT, ping()).await {
+        Ok(Ok(())) => Ok(health_response("ok", StatusCode::OK)),
+        Ok(Err(e)) => {
+            error!("Readiness check failed: {}", e);
+            Ok(health_response("database unavailable", StatusCode::SERVICE_UNAVAILABLE))
+        }
+        Err(_) => {
+            error!("Readiness check timed out");
+            Ok(health_response("database unavailable", StatusCode::SERVICE_UNAVAILABLE))
+        }
+    }
+}
+
+pub async fn metrics_handler(clients: Clients) -> Result<warp::reply::Response, Rejection> {
+    let connected = clients.lock().unwrap().len();
+    let mut response = warp::reply::Response::new(metrics::render(connected).into());
+    response.headers_mut().insert(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8".parse().unwrap());
+    Ok(response)
+}
+
+/// Служебные маршруты для мониторинга:
+///
+/// - GET /healthz — процесс жив и отвечает; базу данных не проверяет
+/// - GET /readyz — сервер готов обслуживать запросы: база данных отвечает
+/// - GET /metrics — метрики в формате Prometheus; наружу nginx его не отдает
+pub fn health_routes(clients: Clients) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let healthz = warp::path!("healthz")
+        .and(warp::get())
+        .map(|| health_response("ok", StatusCode::OK));
+
+    let readyz = warp::path!("readyz")
+        .and(warp::get())
+        .and_then(readyz_handler);
+
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_clients(clients))
+        .and_then(metrics_handler);
+
+    healthz.or(readyz).or(metrics)
+}
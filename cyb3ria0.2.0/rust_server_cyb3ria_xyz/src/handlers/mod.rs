@@ -1,2 +1,11 @@
+pub mod admin;
 pub mod auth;
 pub mod chat;
+pub mod export;
+pub mod files;
+pub mod health;
+pub mod moderation;
+pub mod notifications;
+pub mod rooms;
+pub mod search;
+pub mod uploads;
@@ -0,0 +1,500 @@
+use warp::{Filter, Rejection, http::StatusCode};
+use crate::models::{User, Role, Ban, PinOutcome};
+use crate::db::{
+    find_user_by_username, soft_delete_message, save_mute_to_db, save_ban_to_db, find_active_ban_by_id, delete_ban, delete_user_sessions,
+    save_pin, delete_pin, find_pins, set_room_topic, find_room_topic, room_exists, join_room, leave_room
+};
+use crate::handlers::chat::{Clients, Sender, with_clients, with_sender, broadcast, DEFAULT_ROOM};
+use crate::fanout::{Audience, Broadcast, Payload};
+use crate::permissions::require_role;
+use crate::protocol::ServerEvent;
+use crate::audit;
+use crate::utils::real_ip;
+use chrono::{Utc, Duration};
+use ipnetwork::IpNetwork;
+use log::{info, error};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+/// Максимальный срок заглушения и временного бана — один год
+const MAX_PUNISHMENT_MINUTES: i64 = 60 * 24 * 365;
+
+/// Самые широкие подсети, которые может забанить модератор; более широкие — только администратор
+const MIN_MODERATOR_PREFIX_V4: u8 = 24;
+const MIN_MODERATOR_PREFIX_V6: u8 = 48;
+
+/// Сколько сообщений можно закрепить в одной комнате
+const MAX_PINS_PER_ROOM: i64 = 50;
+
+/// Максимальная длина темы и описания комнаты (в символах)
+const MAX_TOPIC_CHARS: usize = 200;
+const MAX_DESCRIPTION_CHARS: usize = 1000;
+
+/// Результат действия модератора: текст для модератора либо HTTP-статус и текст ошибки.
+/// Используется и REST-эндпоинтами, и командами по WebSocket.
+pub type ModerationResult = Result<String, (StatusCode, String)>;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MuteData {
+    pub username: String,
+    pub minutes: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct KickData {
+    pub username: String,
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BanData {
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    /// Срок бана в минутах; без срока бан бессрочный
+    pub minutes: Option<i64>,
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TopicData {
+    pub topic: Option<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ModerationResponse {
+    pub message: String,
+}
+
+fn internal_error(context: &str, e: Box<dyn std::error::Error + Send + Sync>) -> (StatusCode, String) {
+    error!("{}: {}", context, e);
+    (StatusCode::INTERNAL_SERVER_ERROR, format!("{}.", context))
+}
+
+/// Ищет пользователя, над которым выполняется действие. Модератор может действовать
+/// только над пользователями с ролью ниже своей.
+async fn find_target(moderator: &User, username: &str) -> Result<User, (StatusCode, String)> {
+    let target = find_user_by_username(username)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, "User not found.".to_string()))?;
+
+    if target.role >= moderator.role {
+        return Err((StatusCode::FORBIDDEN, "You cannot moderate a user with an equal or higher role.".to_string()));
+    }
+
+    Ok(target)
+}
+
+fn validate_minutes(minutes: i64) -> Result<Duration, (StatusCode, String)> {
+    if !(1..=MAX_PUNISHMENT_MINUTES).contains(&minutes) {
+        return Err((StatusCode::BAD_REQUEST, format!("Duration must be between 1 and {} minutes.", MAX_PUNISHMENT_MINUTES)));
+    }
+    Ok(Duration::minutes(minutes))
+}
+
+/// Отключает живые соединения всех адресатов на всех экземплярах. Возвращает число
+/// отключенных соединений этого экземпляра: о чужих он не знает.
+pub fn disconnect_clients(clients: &Clients, sender: &Sender, reason: &str, audiences: &[Audience]) -> usize {
+    let local = clients
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|client| audiences.iter().any(|audience| audience.matches(client)))
+        .count();
+
+    for audience in audiences {
+        sender.publish(Broadcast { audience: audience.clone(), payload: Payload::Disconnect(reason.to_string()) });
+    }
+
+    local
+}
+
+/// Мягко удаляет сообщение и рассылает событие удаления клиентам комнаты
+pub async fn delete_message(moderator: &User, ip: IpAddr, message_id: i64, sender: &Sender) -> ModerationResult {
+    match soft_delete_message(message_id, moderator.user_uuid).await {
+        Ok(Some(room)) => {
+            info!("Message {} deleted by {}", message_id, moderator.username);
+            audit::record(audit::MESSAGE_DELETED, Some(moderator.user_uuid), None, Some(ip), json!({ "message_id": message_id, "room": room })).await;
+            broadcast(sender, Some(&room), &ServerEvent::Deleted { id: message_id });
+            Ok("Message deleted.".to_string())
+        },
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Message not found.".to_string())),
+        Err(e) => Err(internal_error("Failed to delete message", e)),
+    }
+}
+
+/// Запрещает пользователю писать в чат на указанное число минут
+pub async fn mute_user(moderator: &User, ip: IpAddr, username: &str, minutes: i64) -> ModerationResult {
+    let duration = validate_minutes(minutes)?;
+    let target = find_target(moderator, username).await?;
+    let muted_until = Utc::now() + duration;
+
+    save_mute_to_db(target.user_uuid, muted_until, moderator.user_uuid)
+        .await
+        .map_err(|e| internal_error("Failed to mute user", e))?;
+
+    info!("User {} muted by {} until {}", target.username, moderator.username, muted_until);
+    audit::record(audit::USER_MUTED, Some(moderator.user_uuid), Some(target.user_uuid), Some(ip), json!({ "minutes": minutes, "muted_until": muted_until })).await;
+    Ok(format!("{} is muted until {}.", target.username, muted_until.to_rfc3339()))
+}
+
+/// Разрывает все текущие соединения пользователя
+pub async fn kick_user(moderator: &User, ip: IpAddr, username: &str, reason: Option<String>, clients: &Clients, sender: &Sender) -> ModerationResult {
+    let target = find_target(moderator, username).await?;
+    let reason = reason.unwrap_or_else(|| "Kicked by a moderator.".to_string());
+
+    let kicked = disconnect_clients(clients, sender, &reason, &[Audience::Users(vec![target.user_uuid])]);
+
+    info!("User {} kicked by {} ({} connections)", target.username, moderator.username, kicked);
+    audit::record(audit::USER_KICKED, Some(moderator.user_uuid), Some(target.user_uuid), Some(ip), json!({ "reason": reason, "connections": kicked })).await;
+    Ok(format!("{} kicked ({} connections).", target.username, kicked))
+}
+
+/// Слишком широкий бан подсети может закрыть сайт для всех, поэтому модератору доступны
+/// только узкие подсети
+fn check_network_scope(moderator: &User, network: IpNetwork) -> Result<(), (StatusCode, String)> {
+    if moderator.role >= Role::Admin {
+        return Ok(());
+    }
+    let min_prefix = match network {
+        IpNetwork::V4(_) => MIN_MODERATOR_PREFIX_V4,
+        IpNetwork::V6(_) => MIN_MODERATOR_PREFIX_V6,
+    };
+    if network.prefix() < min_prefix {
+        return Err((StatusCode::FORBIDDEN, format!("Only admins can ban networks wider than /{}.", min_prefix)));
+    }
+    Ok(())
+}
+
+/// Банит пользователя и/или IP-адрес (подсеть), отзывает сессии и отключает соединения
+pub async fn ban(moderator: &User, ip: IpAddr, data: BanData, clients: &Clients, sender: &Sender) -> ModerationResult {
+    if data.username.is_none() && data.ip.is_none() {
+        return Err((StatusCode::BAD_REQUEST, "Specify a username or an IP address to ban.".to_string()));
+    }
+
+    let expires_at = match data.minutes {
+        Some(minutes) => Some(Utc::now() + validate_minutes(minutes)?),
+        None => None,
+    };
+
+    let network = match &data.ip {
+        Some(ip) => Some(ip.parse::<IpNetwork>().map_err(|_| (StatusCode::BAD_REQUEST, "Invalid IP address.".to_string()))?),
+        None => None,
+    };
+    if let Some(network) = network {
+        check_network_scope(moderator, network)?;
+    }
+
+    let target = match &data.username {
+        Some(username) => Some(find_target(moderator, username).await?),
+        None => None,
+    };
+    let target_uuid: Option<Uuid> = target.as_ref().map(|user| user.user_uuid);
+
+    let reason = data.reason.unwrap_or_default();
+    let ban = Ban {
+        id: 0,
+        user_uuid: target_uuid,
+        ip: network.map(|network| network.to_string()),
+        reason: reason.clone(),
+        created_by: moderator.user_uuid,
+        expires_at,
+    };
+
+    let ban_id = save_ban_to_db(&ban).await.map_err(|e| internal_error("Failed to save ban", e))?;
+
+    if let Some(user_uuid) = target_uuid {
+        match delete_user_sessions(user_uuid).await {
+            Ok(revoked) => {
+                audit::record(audit::SESSION_REVOKED, Some(moderator.user_uuid), Some(user_uuid), Some(ip), json!({ "reason": "ban", "ban_id": ban_id, "sessions": revoked })).await;
+            }
+            Err(e) => error!("Failed to revoke sessions of banned user: {}", e),
+        }
+    }
+
+    let kick_reason = if reason.is_empty() { "You are banned.".to_string() } else { format!("You are banned: {}", reason) };
+    let audiences: Vec<Audience> = target_uuid
+        .map(|user_uuid| Audience::Users(vec![user_uuid]))
+        .into_iter()
+        .chain(network.map(|network| Audience::Network { network, below: moderator.role }))
+        .collect();
+    let kicked = disconnect_clients(clients, sender, &kick_reason, &audiences);
+
+    info!("Ban {} created by {} (user: {:?}, ip: {:?}, {} connections closed)", ban_id, moderator.username, data.username, ban.ip, kicked);
+    audit::record(audit::BAN_CREATED, Some(moderator.user_uuid), target_uuid, Some(ip), json!({
+        "ban_id": ban_id,
+        "ip": ban.ip,
+        "reason": ban.reason,
+        "expires_at": ban.expires_at,
+        "connections": kicked,
+    })).await;
+    Ok(format!("Ban {} created.", ban_id))
+}
+
+/// Досрочно снимает бан. Бан, созданный пользователем с ролью выше, снять нельзя.
+pub async fn lift_ban(moderator: &User, ip: IpAddr, ban_id: i64) -> ModerationResult {
+    match find_active_ban_by_id(ban_id).await {
+        Ok(Some((_, creator_role))) if creator_role > moderator.role => {
+            return Err((StatusCode::FORBIDDEN, "You cannot lift a ban created by a user with a higher role.".to_string()));
+        }
+        Ok(Some(_)) => {}
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Active ban not found.".to_string())),
+        Err(e) => return Err(internal_error("Failed to lift ban", e)),
+    }
+
+    match delete_ban(ban_id).await {
+        Ok(true) => {
+            info!("Ban {} lifted by {}", ban_id, moderator.username);
+            audit::record(audit::BAN_LIFTED, Some(moderator.user_uuid), None, Some(ip), json!({ "ban_id": ban_id })).await;
+            Ok("Ban lifted.".to_string())
+        },
+        Ok(false) => Err((StatusCode::NOT_FOUND, "Active ban not found.".to_string())),
+        Err(e) => Err(internal_error("Failed to lift ban", e)),
+    }
+}
+
+/// Закрепляет сообщение и рассылает его клиентам комнаты
+pub async fn pin_message(moderator: &User, ip: IpAddr, message_id: i64, sender: &Sender) -> ModerationResult {
+    let room = match save_pin(message_id, moderator.user_uuid, MAX_PINS_PER_ROOM).await {
+        Ok(PinOutcome::Pinned { room }) => room,
+        Ok(PinOutcome::AlreadyPinned) => return Err((StatusCode::CONFLICT, "Message is already pinned.".to_string())),
+        Ok(PinOutcome::LimitReached) => {
+            return Err((StatusCode::CONFLICT, format!("A room can have at most {} pinned messages.", MAX_PINS_PER_ROOM)));
+        }
+        Ok(PinOutcome::NotFound) => return Err((StatusCode::NOT_FOUND, "Message not found.".to_string())),
+        Err(e) => return Err(internal_error("Failed to pin message", e)),
+    };
+
+    info!("Message {} pinned by {}", message_id, moderator.username);
+    audit::record(audit::MESSAGE_PINNED, Some(moderator.user_uuid), None, Some(ip), json!({ "message_id": message_id, "room": room })).await;
+
+    // Событие общее для всей комнаты, поэтому реакции считаются без учета получателя
+    match find_pins(&room, Some(message_id), Uuid::nil()).await {
+        Ok(pins) => {
+            if let Some(pin) = pins.into_iter().next() {
+                broadcast(sender, Some(&room), &ServerEvent::Pinned(pin));
+            }
+        }
+        Err(e) => error!("Failed to load pinned message {}: {}", message_id, e),
+    }
+    Ok("Message pinned.".to_string())
+}
+
+/// Снимает закрепление и рассылает событие клиентам комнаты
+pub async fn unpin_message(moderator: &User, ip: IpAddr, message_id: i64, sender: &Sender) -> ModerationResult {
+    match delete_pin(message_id).await {
+        Ok(Some(room)) => {
+            info!("Message {} unpinned by {}", message_id, moderator.username);
+            audit::record(audit::MESSAGE_UNPINNED, Some(moderator.user_uuid), None, Some(ip), json!({ "message_id": message_id, "room": room })).await;
+            broadcast(sender, Some(&room), &ServerEvent::Unpinned { id: message_id });
+            Ok("Message unpinned.".to_string())
+        },
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Pinned message not found.".to_string())),
+        Err(e) => Err(internal_error("Failed to unpin message", e)),
+    }
+}
+
+/// Пустая строка убирает тему или описание; длинные значения отклоняются
+fn normalize_topic_field(value: Option<String>, max_chars: usize, name: &str) -> Result<Option<String>, (StatusCode, String)> {
+    let value = value.map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+    if value.as_ref().is_some_and(|value| value.chars().count() > max_chars || value.chars().any(|c| c.is_control())) {
+        return Err((StatusCode::BAD_REQUEST, format!("{} must be at most {} characters without control characters.", name, max_chars)));
+    }
+    Ok(value)
+}
+
+/// Задает тему и описание комнаты и рассылает их клиентам комнаты
+pub async fn set_topic(moderator: &User, ip: IpAddr, room: &str, data: TopicData, sender: &Sender) -> ModerationResult {
+    let topic = normalize_topic_field(data.topic, MAX_TOPIC_CHARS, "Topic")?;
+    let description = normalize_topic_field(data.description, MAX_DESCRIPTION_CHARS, "Description")?;
+
+    match set_room_topic(room, topic.as_deref(), description.as_deref(), moderator.user_uuid).await {
+        Ok(true) => {}
+        Ok(false) => return Err((StatusCode::NOT_FOUND, "Room not found.".to_string())),
+        Err(e) => return Err(internal_error("Failed to set topic", e)),
+    }
+
+    info!("Topic of room {} set by {}", room, moderator.username);
+    audit::record(audit::TOPIC_CHANGED, Some(moderator.user_uuid), None, Some(ip), json!({ "room": room, "topic": topic, "description": description })).await;
+
+    match find_room_topic(room).await {
+        Ok(Some(topic)) => broadcast(sender, Some(room), &ServerEvent::Topic(topic)),
+        Ok(None) => {}
+        Err(e) => error!("Failed to load topic of room {}: {}", room, e),
+    }
+    Ok("Topic updated.".to_string())
+}
+
+async fn check_room(room: &str) -> Result<(), (StatusCode, String)> {
+    match room_exists(room).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((StatusCode::NOT_FOUND, "Room not found.".to_string())),
+        Err(e) => Err(internal_error("Failed to check room", e)),
+    }
+}
+
+/// Делает пользователя участником комнаты, после чего он может к ней подключиться
+pub async fn add_room_member(moderator: &User, ip: IpAddr, room: &str, username: &str) -> ModerationResult {
+    check_room(room).await?;
+    let target = find_user_by_username(username)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, "User not found.".to_string()))?;
+
+    let added = join_room(room, target.user_uuid)
+        .await
+        .map_err(|e| internal_error("Failed to add room member", e))?;
+    if !added {
+        return Ok(format!("{} is already a member of {}.", target.username, room));
+    }
+
+    info!("User {} added to room {} by {}", target.username, room, moderator.username);
+    audit::record(audit::ROOM_MEMBER_ADDED, Some(moderator.user_uuid), Some(target.user_uuid), Some(ip), json!({ "room": room })).await;
+    Ok(format!("{} added to {}.", target.username, room))
+}
+
+/// Исключает пользователя из комнаты и закрывает его соединения с ней. Из general, где
+/// состоят все, исключить нельзя: для этого есть бан.
+pub async fn remove_room_member(moderator: &User, ip: IpAddr, room: &str, username: &str, clients: &Clients, sender: &Sender) -> ModerationResult {
+    if room == DEFAULT_ROOM {
+        return Err((StatusCode::BAD_REQUEST, format!("Everyone is a member of {}.", DEFAULT_ROOM)));
+    }
+    check_room(room).await?;
+    let target = find_target(moderator, username).await?;
+
+    let removed = leave_room(room, target.user_uuid)
+        .await
+        .map_err(|e| internal_error("Failed to remove room member", e))?;
+    if !removed {
+        return Err((StatusCode::NOT_FOUND, format!("{} is not a member of {}.", target.username, room)));
+    }
+
+    let audience = Audience::RoomMember { room: room.to_string(), user_uuid: target.user_uuid };
+    let kicked = disconnect_clients(clients, sender, &format!("You were removed from {}.", room), &[audience]);
+
+    info!("User {} removed from room {} by {} ({} connections)", target.username, room, moderator.username, kicked);
+    audit::record(audit::ROOM_MEMBER_REMOVED, Some(moderator.user_uuid), Some(target.user_uuid), Some(ip), json!({ "room": room, "connections": kicked })).await;
+    Ok(format!("{} removed from {}.", target.username, room))
+}
+
+fn moderation_reply(result: ModerationResult) -> Result<impl warp::Reply, Rejection> {
+    let (status, message) = match result {
+        Ok(message) => (StatusCode::OK, message),
+        Err((status, message)) => (status, message),
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ModerationResponse { message }),
+        status,
+    ))
+}
+
+/// REST-эндпоинты модерации. Доступны модераторам и администраторам.
+///
+/// - DELETE /api/messages/{id}
+/// - POST /api/moderation/mute
+/// - POST /api/moderation/kick
+/// - POST /api/moderation/ban
+/// - DELETE /api/moderation/bans/{id}
+/// - POST /api/messages/{id}/pin
+/// - DELETE /api/messages/{id}/pin
+/// - PUT /api/rooms/{room}/topic
+/// - PUT /api/rooms/{room}/members/{username}
+/// - DELETE /api/rooms/{room}/members/{username}
+pub fn moderation_routes(clients: Clients, sender: Sender) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let delete = warp::path!("api" / "messages" / i64)
+        .and(warp::delete())
+        .and(require_role(Role::Moderator))
+        .and(real_ip())
+        .and(with_sender(sender.clone()))
+        .and_then(|message_id: i64, moderator: User, ip: IpAddr, sender: Sender| async move {
+            moderation_reply(delete_message(&moderator, ip, message_id, &sender).await)
+        });
+
+    let mute = warp::path!("api" / "moderation" / "mute")
+        .and(warp::post())
+        .and(require_role(Role::Moderator))
+        .and(real_ip())
+        .and(warp::body::json())
+        .and_then(|moderator: User, ip: IpAddr, data: MuteData| async move {
+            moderation_reply(mute_user(&moderator, ip, &data.username, data.minutes).await)
+        });
+
+    let kick = warp::path!("api" / "moderation" / "kick")
+        .and(warp::post())
+        .and(require_role(Role::Moderator))
+        .and(real_ip())
+        .and(warp::body::json())
+        .and(with_clients(clients.clone()))
+        .and(with_sender(sender.clone()))
+        .and_then(|moderator: User, ip: IpAddr, data: KickData, clients: Clients, sender: Sender| async move {
+            moderation_reply(kick_user(&moderator, ip, &data.username, data.reason, &clients, &sender).await)
+        });
+
+    let ban_route = warp::path!("api" / "moderation" / "ban")
+        .and(warp::post())
+        .and(require_role(Role::Moderator))
+        .and(real_ip())
+        .and(warp::body::json())
+        .and(with_clients(clients.clone()))
+        .and(with_sender(sender.clone()))
+        .and_then(|moderator: User, ip: IpAddr, data: BanData, clients: Clients, sender: Sender| async move {
+            moderation_reply(ban(&moderator, ip, data, &clients, &sender).await)
+        });
+
+    let unban = warp::path!("api" / "moderation" / "bans" / i64)
+        .and(warp::delete())
+        .and(require_role(Role::Moderator))
+        .and(real_ip())
+        .and_then(|ban_id: i64, moderator: User, ip: IpAddr| async move {
+            moderation_reply(lift_ban(&moderator, ip, ban_id).await)
+        });
+
+    let pin = warp::path!("api" / "messages" / i64 / "pin")
+        .and(warp::post())
+        .and(require_role(Role::Moderator))
+        .and(real_ip())
+        .and(with_sender(sender.clone()))
+        .and_then(|message_id: i64, moderator: User, ip: IpAddr, sender: Sender| async move {
+            moderation_reply(pin_message(&moderator, ip, message_id, &sender).await)
+        });
+
+    let unpin = warp::path!("api" / "messages" / i64 / "pin")
+        .and(warp::delete())
+        .and(require_role(Role::Moderator))
+        .and(real_ip())
+        .and(with_sender(sender.clone()))
+        .and_then(|message_id: i64, moderator: User, ip: IpAddr, sender: Sender| async move {
+            moderation_reply(unpin_message(&moderator, ip, message_id, &sender).await)
+        });
+
+    let topic = warp::path!("api" / "rooms" / String / "topic")
+        .and(warp::put())
+        .and(require_role(Role::Moderator))
+        .and(real_ip())
+        .and(warp::body::json())
+        .and(with_sender(sender.clone()))
+        .and_then(|room: String, moderator: User, ip: IpAddr, data: TopicData, sender: Sender| async move {
+            moderation_reply(set_topic(&moderator, ip, &room, data, &sender).await)
+        });
+
+    let add_member = warp::path!("api" / "rooms" / String / "members" / String)
+        .and(warp::put())
+        .and(require_role(Role::Moderator))
+        .and(real_ip())
+        .and_then(|room: String, username: String, moderator: User, ip: IpAddr| async move {
+            moderation_reply(add_room_member(&moderator, ip, &room, &username).await)
+        });
+
+    let remove_member = warp::path!("api" / "rooms" / String / "members" / String)
+        .and(warp::delete())
+        .and(require_role(Role::Moderator))
+        .and(real_ip())
+        .and(with_clients(clients))
+        .and(with_sender(sender))
+        .and_then(|room: String, username: String, moderator: User, ip: IpAddr, clients: Clients, sender: Sender| async move {
+            moderation_reply(remove_room_member(&moderator, ip, &room, &username, &clients, &sender).await)
+        });
+
+    delete.or(mute).or(kick).or(ban_route).or(unban).or(pin).or(unpin).or(topic).or(add_member).or(remove_member)
+}
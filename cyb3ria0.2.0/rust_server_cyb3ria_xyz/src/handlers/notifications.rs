@@ -0,0 +1,92 @@
+use warp::{Filter, Rejection, Reply, http::StatusCode};
+use crate::models::{User, Notification};
+use crate::db::{find_notifications, mark_notifications_read};
+use crate::permissions::authenticated;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+/// Размер страницы уведомлений по умолчанию и максимальный
+const DEFAULT_NOTIFICATIONS_PAGE_SIZE: i64 = 50;
+const MAX_NOTIFICATIONS_PAGE_SIZE: i64 = 200;
+
+/// Параметры запроса GET /api/notifications
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NotificationsQuery {
+    /// Только непрочитанные
+    #[serde(default)]
+    pub unread: bool,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NotificationsResponse {
+    pub notifications: Vec<Notification>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+    pub unread: i64,
+}
+
+/// Тело POST /api/notifications/read. Без ids отмечаются все уведомления.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MarkReadData {
+    pub ids: Option<Vec<i64>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MarkReadResponse {
+    pub message: String,
+    pub marked: u64,
+}
+
+pub async fn notifications_handler(user: User, query: NotificationsQuery) -> Result<warp::reply::Response, Rejection> {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_NOTIFICATIONS_PAGE_SIZE).clamp(1, MAX_NOTIFICATIONS_PAGE_SIZE);
+
+    match find_notifications(user.user_uuid, query.unread, per_page, (page - 1) * per_page).await {
+        Ok((notifications, total, unread)) => {
+            let response = NotificationsResponse { notifications, page, per_page, total, unread };
+            Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::OK).into_response())
+        }
+        Err(e) => {
+            error!("Failed to fetch notifications: {}", e);
+            let response = MarkReadResponse { message: "Failed to fetch notifications.".to_string(), marked: 0 };
+            Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::INTERNAL_SERVER_ERROR).into_response())
+        }
+    }
+}
+
+pub async fn mark_read_handler(user: User, data: MarkReadData) -> Result<impl warp::Reply, Rejection> {
+    match mark_notifications_read(user.user_uuid, data.ids.as_deref()).await {
+        Ok(marked) => {
+            let response = MarkReadResponse { message: "Notifications marked as read.".to_string(), marked };
+            Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::OK))
+        }
+        Err(e) => {
+            error!("Failed to mark notifications as read: {}", e);
+            let response = MarkReadResponse { message: "Failed to mark notifications as read.".to_string(), marked: 0 };
+            Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+/// Входящие уведомления текущего пользователя:
+///
+/// - GET /api/notifications?unread=&page=&per_page=
+/// - POST /api/notifications/read
+pub fn notifications_routes() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let list = warp::path!("api" / "notifications")
+        .and(warp::get())
+        .and(authenticated())
+        .and(warp::query::<NotificationsQuery>())
+        .and_then(notifications_handler);
+
+    let mark_read = warp::path!("api" / "notifications" / "read")
+        .and(warp::post())
+        .and(authenticated())
+        .and(warp::body::json())
+        .and_then(mark_read_handler);
+
+    list.or(mark_read)
+}
@@ -0,0 +1,54 @@
+use warp::{Filter, Rejection, Reply, http::StatusCode};
+use crate::db::{find_pins, is_room_member};
+use crate::models::User;
+use crate::permissions::authenticated;
+use crate::protocol::PinnedMessage;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PinsResponse {
+    pub room: String,
+    pub pins: Vec<PinnedMessage>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RoomsErrorResponse {
+    pub message: String,
+}
+
+fn error_response(message: &str, status: StatusCode) -> warp::reply::Response {
+    let response = RoomsErrorResponse { message: message.to_string() };
+    warp::reply::with_status(warp::reply::json(&response), status).into_response()
+}
+
+pub async fn pins_handler(room: String, user: User) -> Result<warp::reply::Response, Rejection> {
+    // Чужая комната неотличима от несуществующей
+    match is_room_member(&room, user.user_uuid).await {
+        Ok(true) => {}
+        Ok(false) => return Ok(error_response("Room not found.", StatusCode::NOT_FOUND)),
+        Err(e) => {
+            error!("Failed to check room: {}", e);
+            return Ok(error_response("Failed to load pinned messages.", StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    }
+
+    match find_pins(&room, None, user.user_uuid).await {
+        Ok(pins) => {
+            let response = PinsResponse { room, pins };
+            Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::OK).into_response())
+        }
+        Err(e) => {
+            error!("Failed to load pinned messages: {}", e);
+            Ok(error_response("Failed to load pinned messages.", StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+/// GET /api/rooms/{room}/pins — закрепленные сообщения комнаты с полным текстом
+pub fn pins_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "rooms" / String / "pins")
+        .and(warp::get())
+        .and(authenticated())
+        .and_then(pins_handler)
+}
@@ -0,0 +1,80 @@
+use warp::{Filter, Rejection, Reply, http::StatusCode};
+use crate::models::{User, SearchFilter, SearchResult};
+use crate::db::search_messages;
+use crate::permissions::authenticated;
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+/// Размер страницы результатов поиска по умолчанию и максимальный
+const DEFAULT_SEARCH_PAGE_SIZE: i64 = 20;
+const MAX_SEARCH_PAGE_SIZE: i64 = 100;
+
+/// Максимальная длина поискового запроса в байтах
+const MAX_QUERY_BYTES: usize = 256;
+
+/// Параметры запроса GET /api/search
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SearchQuery {
+    pub q: String,
+    pub author: Option<String>,
+    pub room: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SearchResponse {
+    pub results: Vec<SearchResult>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SearchErrorResponse {
+    pub message: String,
+}
+
+pub async fn search_handler(user: User, query: SearchQuery) -> Result<warp::reply::Response, Rejection> {
+    let text = query.q.trim();
+    if text.is_empty() || text.len() > MAX_QUERY_BYTES {
+        let response = SearchErrorResponse { message: format!("Search query must be 1-{} bytes.", MAX_QUERY_BYTES) };
+        return Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::BAD_REQUEST).into_response());
+    }
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_SEARCH_PAGE_SIZE).clamp(1, MAX_SEARCH_PAGE_SIZE);
+    let filter = SearchFilter {
+        query: text.to_string(),
+        viewer: user.user_uuid,
+        author: query.author,
+        room: query.room,
+        from: query.from,
+        to: query.to,
+    };
+
+    match search_messages(&filter, per_page, (page - 1) * per_page).await {
+        Ok((results, total)) => {
+            let response = SearchResponse { results, page, per_page, total };
+            Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::OK).into_response())
+        }
+        Err(e) => {
+            error!("Failed to search messages: {}", e);
+            let response = SearchErrorResponse { message: "Failed to search messages.".to_string() };
+            Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::INTERNAL_SERVER_ERROR).into_response())
+        }
+    }
+}
+
+/// GET /api/search?q=...&author=&room=&from=&to=&page=&per_page= — полнотекстовый поиск по сообщениям
+/// комнат, в которых пользователь участник
+pub fn search_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "search")
+        .and(warp::get())
+        .and(authenticated())
+        .and(warp::query::<SearchQuery>())
+        .and_then(search_handler)
+}
@@ -0,0 +1,200 @@
+//! Загрузка файлов для вложений (POST /api/upload).
+//!
+//! Файл сохраняется в uploads.root/{год}/{месяц}/{uuid}.{расширение} и записывается в attachments
+//! без сообщения. Клиент прикрепляет его, передав id в команде message; загрузки, которые так
+//! и не прикрепили, удаляет фоновая очистка (см. retention).
+
+use warp::{Filter, Rejection, Reply, http::StatusCode};
+use warp::multipart::{FormData, Part};
+use bytes::Buf;
+use chrono::{Datelike, Utc};
+use crate::config;
+use crate::db::save_upload;
+use crate::models::{Attachment, User};
+use crate::permissions::authenticated;
+use crate::utils::uploaded_url;
+use futures_util::{StreamExt, TryStreamExt};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+/// Запас на заголовки частей и границы multipart сверх размера самого файла
+const MULTIPART_OVERHEAD_BYTES: u64 = 64 * 1024;
+
+/// Максимальная длина сохраняемого имени файла (в символах) и расширения на диске
+const MAX_FILE_NAME_CHARS: usize = 255;
+const MAX_EXTENSION_CHARS: usize = 10;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UploadResponse {
+    /// id для поля attachments команды message
+    pub id: i64,
+    #[serde(flatten)]
+    pub attachment: Attachment,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UploadErrorResponse {
+    pub message: String,
+}
+
+fn error_response(message: &str, status: StatusCode) -> warp::reply::Response {
+    let response = UploadErrorResponse { message: message.to_string() };
+    warp::reply::with_status(warp::reply::json(&response), status).into_response()
+}
+
+/// Почему не удалось сохранить файл
+enum StoreError {
+    TooLarge,
+    Failed(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Имя файла, которое показывается в чате: без пути и управляющих символов
+fn display_name(file_name: &str) -> String {
+    let name = file_name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).take(MAX_FILE_NAME_CHARS).collect();
+    match name.trim() {
+        "" => "file".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Расширение файла на диске: по нему сервер и nginx выбирают Content-Type при отдаче.
+/// Только строчные латинские буквы и цифры, иначе без расширения.
+fn extension(file_name: &str) -> Option<String> {
+    let (_, extension) = file_name.rsplit_once('.')?;
+    let extension = extension.to_ascii_lowercase();
+    let valid = !extension.is_empty()
+        && extension.len() <= MAX_EXTENSION_CHARS
+        && extension.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit());
+    valid.then_some(extension)
+}
+
+/// Записывает часть формы в файл, не больше max_bytes. Недописанный файл удаляется.
+async fn store(part: Part, path: &Path, max_bytes: u64) -> Result<u64, StoreError> {
+    let result = async {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await.map_err(|e| StoreError::Failed(e.into()))?;
+        }
+        let mut file = tokio::fs::File::create_new(path).await.map_err(|e| StoreError::Failed(e.into()))?;
+        let mut size = 0u64;
+        let mut data = part.stream();
+        while let Some(mut chunk) = data.try_next().await.map_err(|e| StoreError::Failed(e.into()))? {
+            size += chunk.remaining() as u64;
+            if size > max_bytes {
+                return Err(StoreError::TooLarge);
+            }
+            while chunk.has_remaining() {
+                let bytes = chunk.chunk();
+                file.write_all(bytes).await.map_err(|e| StoreError::Failed(e.into()))?;
+                let written = bytes.len();
+                chunk.advance(written);
+            }
+        }
+        file.flush().await.map_err(|e| StoreError::Failed(e.into()))?;
+        Ok(size)
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+    result
+}
+
+pub async fn upload_handler(user: User, form: FormData) -> Result<warp::reply::Response, Rejection> {
+    let max_bytes = config::get().uploads.max_file_bytes;
+
+    // Из формы берется первая часть file; остальные поля не нужны
+    let mut parts = form.filter_map(|part| async move { part.ok().filter(|part| part.name() == "file") }).boxed();
+    let Some(part) = parts.next().await else {
+        return Ok(error_response("No file uploaded.", StatusCode::BAD_REQUEST));
+    };
+
+    let file_name = display_name(part.filename().unwrap_or_default());
+    let content_type = part
+        .content_type()
+        .map(str::to_string)
+        .or_else(|| mime_guess::from_path(&file_name).first().map(|mime| mime.to_string()));
+
+    let now = Utc::now();
+    let mut relative = format!("{}/{:02}/{}", now.year(), now.month(), Uuid::new_v4().simple());
+    if let Some(extension) = extension(&file_name) {
+        relative.push('.');
+        relative.push_str(&extension);
+    }
+    let path = config::get().uploads.root.join(&relative);
+
+    let size = match store(part, &path, max_bytes).await {
+        Ok(size) => size,
+        Err(StoreError::TooLarge) => {
+            return Ok(error_response(&format!("File must be at most {} bytes.", max_bytes), StatusCode::PAYLOAD_TOO_LARGE));
+        }
+        Err(StoreError::Failed(e)) => {
+            error!("Failed to store upload {}: {}", relative, e);
+            return Ok(error_response("Failed to upload file.", StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+
+    let size = size as i64;
+    let id = match save_upload(user.user_uuid, &relative, &file_name, content_type.as_deref(), size).await {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Failed to save upload {}: {}", relative, e);
+            let _ = tokio::fs::remove_file(&path).await;
+            return Ok(error_response("Failed to upload file.", StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+
+    info!("User {} uploaded {} ({} bytes) as {}", user.username, file_name, size, relative);
+
+    let response = UploadResponse {
+        id,
+        attachment: Attachment { file_name, content_type, size, url: uploaded_url(&relative) },
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::CREATED).into_response())
+}
+
+/// POST /api/upload — загрузка файла (multipart, поле file) для вложения в сообщение.
+/// Если uploads.max_file_bytes = 0, маршрута нет.
+pub fn upload_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let max_bytes = config::get().uploads.max_file_bytes;
+    warp::path!("api" / "upload")
+        .and(warp::post())
+        .and_then(move || async move {
+            if max_bytes > 0 {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+        .and(authenticated())
+        .and(warp::multipart::form().max_length(max_bytes + MULTIPART_OVERHEAD_BYTES))
+        .and_then(upload_handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{display_name, extension};
+
+    #[test]
+    fn keeps_only_the_base_name() {
+        assert_eq!(display_name("C:\\Users\\me\\report.pdf"), "report.pdf");
+        assert_eq!(display_name("../../etc/passwd"), "passwd");
+        assert_eq!(display_name("a\u{0}b\nc.txt"), "abc.txt");
+        assert_eq!(display_name("  "), "file");
+    }
+
+    #[test]
+    fn stores_only_simple_extensions() {
+        assert_eq!(extension("photo.JPG").as_deref(), Some("jpg"));
+        assert_eq!(extension("archive.tar.gz").as_deref(), Some("gz"));
+        assert_eq!(extension("page.html%00.png").as_deref(), Some("png"));
+        assert_eq!(extension("script.p h p"), None);
+        assert_eq!(extension("noext"), None);
+        assert_eq!(extension("x.verylongextension"), None);
+    }
+}
@@ -1,56 +1,221 @@
+mod audit;
+mod config;
 mod db;
+mod fanout;
 mod utils;
 mod models;
 mod handlers;
+mod markdown;
+mod metrics;
+mod mentions;
+mod password;
+mod permissions;
+mod previews;
+mod protocol;
+mod retention;
+mod security;
+mod shutdown;
+mod tls;
 
 use warp::Filter;
 use dotenv::dotenv;
-use log::info;
+use log::{info, error, warn};
 use std::sync::{Arc, Mutex};
-use tokio::sync::broadcast;
-use handlers::auth::{register_route, login_route};
-use handlers::chat::client_connection;
-
-type Clients = Arc<Mutex<std::collections::HashMap<String, usize>>>;
-type Sender = Arc<Mutex<broadcast::Sender<String>>>;
+use config::FanOutBackend;
+use handlers::auth::{register_route, login_route, logout_route};
+use handlers::admin::{set_role_route, audit_route, create_room_route, set_retention_route};
+use models::{User, Role};
+use permissions::handle_rejection;
+use uuid::Uuid;
+use serde_json::json;
+use std::io::Write;
+use handlers::chat::{chat_route, Clients, Sender};
+use handlers::moderation::moderation_routes;
+use handlers::search::search_route;
+use handlers::export::export_route;
+use handlers::health::health_routes;
+use handlers::files::files_routes;
+use handlers::rooms::pins_route;
+use handlers::notifications::notifications_routes;
+use handlers::uploads::upload_route;
 
 #[tokio::main]
 async fn main() {
     // Загрузка переменных окружения из .env файла
     dotenv().ok();
 
+    // Настройки нужны до всего остального, включая логирование
+    let settings = match config::Config::load() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     // Инициализация логирования
-    env_logger::init();
+    init_logging(&settings.log);
+    config::init(settings);
 
     // Логирование начала работы сервера
     info!("Initializing server...");
 
+    db::init_schema().await.expect("Failed to initialize database schema");
+
+    // Служебные команды: `create-admin <username>` создает администратора или повышает существующего пользователя
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 1 {
+        match (args[1].as_str(), args.get(2)) {
+            ("create-admin", Some(username)) => create_admin(username).await,
+            _ => error!("Usage: {} [create-admin <username>]", args[0]),
+        }
+        return;
+    }
+
+    retention::spawn_purge_job();
+
     let clients: Clients = Arc::new(Mutex::new(std::collections::HashMap::new()));
-    let sender: Sender = Arc::new(Mutex::new(broadcast::channel(100).0));
-    let clients_clone = Arc::clone(&clients);
-    let sender_clone = Arc::clone(&sender);
-
-     let chat_route = warp::path("api")
-        .and(warp::path("ws"))
-        .and(warp::ws())
-        .and(warp::addr::remote())
-        .and(warp::query::<std::collections::HashMap<String, String>>()) // Получение параметров из URL
-        .map(move |ws: warp::ws::Ws, addr: Option<std::net::SocketAddr>, params: std::collections::HashMap<String, String> | {
-            let clients_clone = Arc::clone(&clients_clone);
-            let sender_clone = Arc::clone(&sender_clone);
-            let peer_addr = addr.expect("Failed to get peer address");
-             let username_from_url = params.get("username").map(|s| s.to_string());
-            ws.on_upgrade(move |socket| {
-                client_connection(socket, clients_clone, sender_clone, peer_addr, username_from_url)
-            })
-        });
+    let capacity = config::get().chat.broadcast_capacity;
+    let sender: Sender = match config::get().fanout.backend {
+        FanOutBackend::Local => Arc::new(fanout::LocalFanOut::new(capacity, Arc::clone(&clients))),
+        FanOutBackend::Postgres => Arc::new(fanout::PostgresFanOut::start(capacity)),
+    };
+    let fetcher: previews::SharedFetcher = Arc::new(previews::HttpFetcher::default());
+    let chat_route = chat_route(Arc::clone(&clients), Arc::clone(&sender), fetcher);
+    let moderation_routes = moderation_routes(Arc::clone(&clients), Arc::clone(&sender));
 
     let register_route = register_route();
     let login_route = login_route();
+    let logout_route = logout_route();
+    let set_role_route = set_role_route();
+    let audit_route = audit_route();
+    let create_room_route = create_room_route();
+    let set_retention_route = set_retention_route();
+    let search_route = search_route();
+    let export_route = export_route();
+    let pins_route = pins_route();
+    let notifications_routes = notifications_routes();
+    let upload_route = upload_route();
+    let health_routes = health_routes(Arc::clone(&clients));
+
+    let routes = chat_route
+        .or(register_route)
+        .or(login_route)
+        .or(logout_route)
+        .or(set_role_route)
+        .or(audit_route)
+        .or(create_room_route)
+        .or(set_retention_route)
+        .or(search_route)
+        .or(export_route)
+        .or(pins_route)
+        .or(notifications_routes)
+        .or(upload_route)
+        .or(moderation_routes)
+        .or(health_routes)
+        .or(files_routes())
+        .recover(handle_rejection)
+        .with(warp::log::custom(|info| {
+            metrics::observe_request(info.path(), info.method().as_str(), info.status().as_u16(), info.elapsed());
+        }));
+
+
+    let address = config::get().server.socket_addr();
+    info!("Starting server on {}", address);
+
+    // По SIGTERM/SIGINT сервер перестает принимать подключения и отключает клиентов (см. shutdown.rs)
+    let (deadline_sender, deadline) = shutdown::deadline_channel();
+    let signal = shutdown::signal(Arc::clone(&clients), deadline_sender);
+    // CORS и заголовки безопасности для всех ответов (см. security.rs)
+    let routes = security::wrap(routes);
+
+    let server = async move {
+        if config::get().tls.enabled {
+            if let Err(e) = tls::serve(routes, address, signal).await {
+                error!("Failed to start HTTPS server: {}", e);
+                std::process::exit(1);
+            }
+        } else {
+            warp::serve(routes).bind_with_graceful_shutdown(address, signal).1.await;
+        }
+    };
+
+    // Незавершенные HTTP-запросы не задерживают остановку дольше срока
+    tokio::select! {
+        _ = server => {}
+        _ = shutdown::expired(deadline.clone()) => warn!("HTTP requests did not finish in time"),
+    }
+
+    shutdown::drain(deadline, &clients, &sender).await;
+}
+
+/// Настраивает env_logger: уровень из настроек (RUST_LOG имеет приоритет), формат text или json
+fn init_logging(settings: &config::LogConfig) {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(settings.level.as_str()));
+    if settings.format == config::LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = json!({
+                "time": chrono::Utc::now().to_rfc3339(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+/// Создает пользователя с ролью администратора. Пароль берется из переменной
+/// CYB3RIA_ADMIN_PASSWORD или читается из stdin. Если пользователь уже есть,
+/// он просто получает роль администратора.
+async fn create_admin(username: &str) {
+    if let Ok(Some((user_uuid, previous))) = db::set_user_role(username, Role::Admin).await {
+        info!("User {} promoted to admin", username);
+        audit::record(audit::ROLE_CHANGED, None, Some(user_uuid), None, json!({ "from": previous, "to": Role::Admin, "source": "cli" })).await;
+        return;
+    }
+
+    let password = match std::env::var("CYB3RIA_ADMIN_PASSWORD") {
+        Ok(password) => password,
+        Err(_) => {
+            println!("Password for {}:", username);
+            let mut line = String::new();
+            if let Err(e) = std::io::stdin().read_line(&mut line) {
+                error!("Failed to read password: {}", e);
+                return;
+            }
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+
+    if let Err(message) = password::check_password_policy(&password) {
+        error!("{}", message);
+        return;
+    }
+
+    let password_hash = match password::hash_password(password).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("Failed to hash password: {}", e);
+            return;
+        }
+    };
 
-    let routes = chat_route.or(register_route).or(login_route);
-    
+    let user_uuid = Uuid::new_v4();
+    let user = User {
+        username: username.to_string(),
+        password_hash,
+        invitation_code: "cli".to_string(),
+        user_uuid,
+        role: Role::Admin,
+    };
 
-    info!("Starting server on 127.0.0.1:8081");
-    warp::serve(routes).run(([127, 0, 0, 1], 8081)).await;
-}
\ No newline at end of file
+    match db::save_user_to_db(user).await {
+        Ok(_) => {
+            info!("Admin {} created", username);
+            audit::record(audit::ROLE_CHANGED, None, Some(user_uuid), None, json!({ "to": Role::Admin, "source": "cli" })).await;
+        }
+        Err(e) => error!("Failed to create admin: {}", e),
+    }
+}
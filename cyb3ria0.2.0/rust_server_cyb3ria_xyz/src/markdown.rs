@@ -0,0 +1,591 @@
+//! Упрощенный Markdown для сообщений чата.
+//!
+//! Поддерживается только безопасное подмножество: **жирный**, *курсив* (или _курсив_),
+//! `код`, блоки кода в ```, ссылки [текст](url) и голые http(s)-адреса. Все остальное,
+//! включая любой HTML, выводится как экранированный текст. Рендерер сам формирует каждый
+//! тег и всегда закрывает его, а текст пользователя попадает в вывод только через escape_html,
+//! поэтому результат можно вставлять в страницу как HTML.
+
+use crate::utils::escape_html;
+
+/// Схемы, разрешенные в ссылках. Все прочие (javascript:, data:, vbscript: и т.п.) запрещены.
+const ALLOWED_SCHEMES: &[&str] = &["http://", "https://", "mailto:"];
+
+// Символы, которыми обычно заканчивается предложение, а не адрес: "см. https://example.com."
+const URL_TRAILING_PUNCTUATION: &[char] = &['.', ',', ';', ':', '!', '?', ')', '\'', '"'];
+
+const CODE_FENCE: &str = "```";
+
+/// Превращает исходный текст сообщения в безопасный HTML
+pub fn render(source: &str) -> String {
+    let mut html = String::with_capacity(source.len() + source.len() / 4);
+    let mut lines = source.lines().peekable();
+    let mut first = true;
+
+    while let Some(line) = lines.next() {
+        if !first {
+            html.push_str("<br>");
+        }
+        first = false;
+
+        if line.trim_start().starts_with(CODE_FENCE) {
+            // Блок кода до закрывающей строки ``` (или до конца сообщения, если ее нет).
+            // Язык после открывающих ``` игнорируется.
+            let mut code: Vec<&str> = Vec::new();
+            for line in lines.by_ref() {
+                if line.trim() == CODE_FENCE {
+                    break;
+                }
+                code.push(line);
+            }
+            html.push_str("<pre><code>");
+            html.push_str(&escape_html(&code.join("\n")));
+            html.push_str("</code></pre>");
+            // После блока кода перенос строки не нужен
+            if lines.peek().is_some() {
+                first = true;
+            }
+            continue;
+        }
+
+        let line = Line::new(line);
+        render_inline(&line, 0, line.len(), &mut html, true);
+    }
+
+    html
+}
+
+/// Строка сообщения и то, что о ней нужно разбору, посчитанное заранее за один проход
+/// с конца и с начала строки. Без этого каждый незакрытый *, _, [ или ` заставлял бы
+/// просматривать строку до конца, и время разбора росло бы квадратично от длины.
+struct Line {
+    chars: Vec<char>,
+    /// Ближайший разделитель начиная с позиции, как его нашел бы поиск вперед
+    /// с пропуском экранированных символов
+    next_star: Vec<Option<usize>>,
+    next_double_star: Vec<Option<usize>>,
+    next_underscore: Vec<Option<usize>>,
+    next_bracket: Vec<Option<usize>>,
+    /// Ближайший '`' начиная с позиции; внутри `кода` экранирования нет
+    next_backtick: Vec<Option<usize>>,
+    /// Для '(' — парная ')' с учетом вложенных скобок
+    closing_paren: Vec<Option<usize>>,
+    /// Первый непробельный символ начиная с позиции (len, если его нет)
+    next_non_space: Vec<usize>,
+    /// Последний непробельный символ перед позицией
+    last_non_space: Vec<Option<usize>>,
+    /// Граница голого адреса: первый пробел или <, >, ", ` начиная с позиции (len, если ее нет)
+    url_boundary: Vec<usize>,
+    /// Последний символ перед позицией, не относящийся к знакам препинания в конце адреса
+    last_non_punctuation: Vec<Option<usize>>,
+    /// Число символов, недопустимых в адресе, на отрезке [0, i)
+    unsafe_before: Vec<usize>,
+}
+
+impl Line {
+    fn new(line: &str) -> Self {
+        let chars: Vec<char> = line.chars().collect();
+        let len = chars.len();
+
+        let next_delimiter = |delimiter: &[char]| {
+            let mut next = vec![None; len + 2];
+            for i in (0..len).rev() {
+                next[i] = if chars[i] == '\\' {
+                    next[i + 2]
+                } else if chars[i..].starts_with(delimiter) {
+                    Some(i)
+                } else {
+                    next[i + 1]
+                };
+            }
+            next
+        };
+
+        let mut next_backtick = vec![None; len + 1];
+        let mut next_non_space = vec![len; len + 1];
+        let mut url_boundary = vec![len; len + 1];
+        for i in (0..len).rev() {
+            next_backtick[i] = if chars[i] == '`' { Some(i) } else { next_backtick[i + 1] };
+            next_non_space[i] = if chars[i].is_whitespace() { next_non_space[i + 1] } else { i };
+            url_boundary[i] = if is_url_boundary(chars[i]) { i } else { url_boundary[i + 1] };
+        }
+
+        let mut closing_paren = vec![None; len];
+        let mut open_parens = Vec::new();
+        let mut last_non_space = vec![None; len + 1];
+        let mut last_non_punctuation = vec![None; len + 1];
+        let mut unsafe_before = vec![0; len + 1];
+        for (i, &c) in chars.iter().enumerate() {
+            match c {
+                '(' => open_parens.push(i),
+                ')' => {
+                    if let Some(open) = open_parens.pop() {
+                        closing_paren[open] = Some(i);
+                    }
+                }
+                _ => {}
+            }
+            last_non_space[i + 1] = if c.is_whitespace() { last_non_space[i] } else { Some(i) };
+            last_non_punctuation[i + 1] = if URL_TRAILING_PUNCTUATION.contains(&c) { last_non_punctuation[i] } else { Some(i) };
+            unsafe_before[i + 1] = unsafe_before[i] + usize::from(is_unsafe_url_char(c));
+        }
+
+        Line {
+            next_star: next_delimiter(&['*']),
+            next_double_star: next_delimiter(&['*', '*']),
+            next_underscore: next_delimiter(&['_']),
+            next_bracket: next_delimiter(&[']']),
+            chars,
+            next_backtick,
+            closing_paren,
+            next_non_space,
+            last_non_space,
+            url_boundary,
+            last_non_punctuation,
+            unsafe_before,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.chars.len()
+    }
+
+    /// Символ в позиции i, если она внутри отрезка, который сейчас разбирается (до end)
+    fn get(&self, i: usize, end: usize) -> Option<&char> {
+        if i < end {
+            self.chars.get(i)
+        } else {
+            None
+        }
+    }
+
+    /// Символ перед позицией i, если он внутри отрезка, начинающегося с start
+    fn before(&self, i: usize, start: usize) -> Option<&char> {
+        if i > start {
+            self.chars.get(i - 1)
+        } else {
+            None
+        }
+    }
+
+    /// Закрывающий разделитель длины width из таблицы next, начиная с позиции from.
+    /// Разделитель должен целиком помещаться до end.
+    fn closing(&self, next: &[Option<usize>], from: usize, width: usize, end: usize) -> Option<usize> {
+        next.get(from).copied().flatten().filter(|&found| found + width <= end)
+    }
+
+    // Внутри `кода` экранирование не действует, поэтому ищем просто следующий '`'
+    fn code_end(&self, from: usize, end: usize) -> Option<usize> {
+        self.closing(&self.next_backtick, from, 1, end).filter(|&found| found > from)
+    }
+
+    /// Адрес из символов [from, to), если его можно вывести в href (как is_safe_url).
+    /// Неподходящий адрес отбрасывается без копирования символов.
+    fn safe_url(&self, from: usize, to: usize) -> Option<String> {
+        if from >= to || self.unsafe_before[to] != self.unsafe_before[from] {
+            return None;
+        }
+        let prefix: String = self.chars[from..to.min(from + 8)].iter().collect::<String>().to_ascii_lowercase();
+        if !ALLOWED_SCHEMES.iter().any(|scheme| prefix.starts_with(scheme)) {
+            return None;
+        }
+        // Здесь адрес может не подойти, только если он состоит из одной схемы
+        let url: String = self.chars[from..to].iter().collect();
+        is_safe_url(&url).then_some(url)
+    }
+}
+
+/// Символ, на котором заканчивается голый адрес
+fn is_url_boundary(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '<' | '>' | '"' | '`')
+}
+
+/// Символ, который не может стоять в адресе ссылки (см. is_safe_url)
+fn is_unsafe_url_char(c: char) -> bool {
+    c.is_whitespace() || c.is_control() || matches!(c, '"' | '\'' | '<' | '>' | '`' | '\\')
+}
+
+/// Проверяет, что адрес ссылки можно вывести в атрибут href
+fn is_safe_url(url: &str) -> bool {
+    let lowercase = url.to_ascii_lowercase();
+    ALLOWED_SCHEMES.iter().any(|scheme| lowercase.starts_with(scheme) && lowercase.len() > scheme.len())
+        && !url.chars().any(is_unsafe_url_char)
+}
+
+fn push_link(html: &mut String, url: &str, line: &Line, text_start: usize, text_end: usize) {
+    html.push_str("<a href=\"");
+    html.push_str(&escape_html(url));
+    html.push_str("\" rel=\"nofollow noopener noreferrer\" target=\"_blank\">");
+    // Внутри ссылки допускается форматирование, но не вложенные ссылки
+    render_inline(line, text_start, text_end, html, false);
+    html.push_str("</a>");
+}
+
+fn is_word_char(c: Option<&char>) -> bool {
+    c.is_some_and(|c| c.is_alphanumeric())
+}
+
+/// Разбирает [текст](url), начиная с '[' в позиции start, не выходя за end.
+/// Возвращает границы текста, адрес и позицию после закрывающей скобки.
+fn parse_link(line: &Line, start: usize, end: usize) -> Option<(usize, usize, String, usize)> {
+    let text_end = line.closing(&line.next_bracket, start + 1, 1, end)?;
+    if line.get(text_end + 1, end) != Some(&'(') {
+        return None;
+    }
+
+    // Скобки внутри адреса допустимы, если они сбалансированы (адреса Википедии)
+    let url_end = line.closing_paren[text_end + 1].filter(|&close| close < end)?;
+    if text_end == start + 1 {
+        return None;
+    }
+
+    // Пробелы вокруг адреса отбрасываются
+    let url_start = line.next_non_space[text_end + 2];
+    let url_last = line.last_non_space[url_end].filter(|&last| last >= url_start)?;
+    let url = line.safe_url(url_start, url_last + 1)?;
+    Some((start + 1, text_end, url, url_end + 1))
+}
+
+/// Разбирает голый адрес http(s)://, начиная с позиции start. Возвращает адрес и позицию после него.
+fn parse_autolink(line: &Line, start: usize, end: usize) -> Option<(String, usize)> {
+    let rest: String = line.chars[start..end.min(start + 8)].iter().collect::<String>().to_ascii_lowercase();
+    if !rest.starts_with("http://") && !rest.starts_with("https://") {
+        return None;
+    }
+
+    let boundary = line.url_boundary[start].min(end);
+    let url_end = match line.last_non_punctuation[boundary] {
+        Some(last) if last >= start => last + 1,
+        _ => start,
+    };
+    line.safe_url(start, url_end).map(|url| (url, url_end))
+}
+
+/// Разбирает строчное форматирование символов [start, end) строки и дописывает HTML в out
+fn render_inline(line: &Line, start: usize, end: usize, out: &mut String, allow_links: bool) {
+    let chars = &line.chars;
+    let mut text = String::new();
+    let mut i = start;
+
+    while i < end {
+        let c = chars[i];
+
+        // \* и подобные — символ выводится как есть, без форматирования
+        if c == '\\' && line.get(i + 1, end).is_some_and(|next| next.is_ascii_punctuation()) {
+            text.push(chars[i + 1]);
+            i += 2;
+            continue;
+        }
+
+        if c == '`' {
+            if let Some(code_end) = line.code_end(i + 1, end) {
+                out.push_str(&escape_html(&text));
+                text.clear();
+                let code: String = chars[i + 1..code_end].iter().collect();
+                out.push_str("<code>");
+                out.push_str(&escape_html(&code));
+                out.push_str("</code>");
+                i = code_end + 1;
+                continue;
+            }
+        }
+
+        if c == '*' && line.get(i + 1, end) == Some(&'*') {
+            if let Some(close) = line.closing(&line.next_double_star, i + 2, 2, end).filter(|&close| close > i + 2) {
+                out.push_str(&escape_html(&text));
+                text.clear();
+                out.push_str("<strong>");
+                render_inline(line, i + 2, close, out, allow_links);
+                out.push_str("</strong>");
+                i = close + 2;
+                continue;
+            }
+        }
+
+        // *курсив* не должен начинаться с пробела: "2 * 3 * 4" остается текстом
+        if c == '*' && line.get(i + 1, end).is_some_and(|next| !next.is_whitespace() && *next != '*') {
+            if let Some(close) = line.closing(&line.next_star, i + 1, 1, end) {
+                out.push_str(&escape_html(&text));
+                text.clear();
+                out.push_str("<em>");
+                render_inline(line, i + 1, close, out, allow_links);
+                out.push_str("</em>");
+                i = close + 1;
+                continue;
+            }
+        }
+
+        // _курсив_ только на границах слов, чтобы не ломать snake_case
+        if c == '_' && !is_word_char(line.before(i, start)) {
+            let close = line.closing(&line.next_underscore, i + 1, 1, end)
+                .filter(|&close| close > i + 1 && !is_word_char(line.get(close + 1, end)));
+            if let Some(close) = close {
+                out.push_str(&escape_html(&text));
+                text.clear();
+                out.push_str("<em>");
+                render_inline(line, i + 1, close, out, allow_links);
+                out.push_str("</em>");
+                i = close + 1;
+                continue;
+            }
+        }
+
+        if allow_links && c == '[' {
+            if let Some((text_start, text_end, url, next)) = parse_link(line, i, end) {
+                out.push_str(&escape_html(&text));
+                text.clear();
+                push_link(out, &url, line, text_start, text_end);
+                i = next;
+                continue;
+            }
+        }
+
+        if allow_links && (c == 'h' || c == 'H') && !is_word_char(line.before(i, start)) {
+            if let Some((url, next)) = parse_autolink(line, i, end) {
+                out.push_str(&escape_html(&text));
+                text.clear();
+                let href = escape_html(&url);
+                out.push_str("<a href=\"");
+                out.push_str(&href);
+                out.push_str("\" rel=\"nofollow noopener noreferrer\" target=\"_blank\">");
+                out.push_str(&href);
+                out.push_str("</a>");
+                i = next;
+                continue;
+            }
+        }
+
+        text.push(c);
+        i += 1;
+    }
+
+    out.push_str(&escape_html(&text));
+}
+
+/// Возвращает первую http(s)-ссылку сообщения (явную или голый адрес) вне блоков и фрагментов кода
+pub fn first_link(source: &str) -> Option<String> {
+    let mut in_code_block = false;
+
+    for line in source.lines() {
+        if line.trim_start().starts_with(CODE_FENCE) {
+            // Открывающая строка может содержать язык, закрывающая — только ```
+            in_code_block = !in_code_block || line.trim() != CODE_FENCE;
+            continue;
+        }
+        if in_code_block {
+            continue;
+        }
+
+        let line = Line::new(line);
+        let end = line.len();
+        let mut i = 0;
+        while i < end {
+            match line.chars[i] {
+                '\\' => i += 2,
+                '`' => i = line.code_end(i + 1, end).map_or(i + 1, |code_end| code_end + 1),
+                '[' => match parse_link(&line, i, end) {
+                    Some((_, _, url, _)) if is_http_url(&url) => return Some(url),
+                    Some((_, _, _, next)) => i = next,
+                    None => i += 1,
+                },
+                'h' | 'H' if !is_word_char(line.before(i, 0)) => match parse_autolink(&line, i, end) {
+                    Some((url, _)) => return Some(url),
+                    None => i += 1,
+                },
+                _ => i += 1,
+            }
+        }
+    }
+
+    None
+}
+
+fn is_http_url(url: &str) -> bool {
+    let lowercase = url.to_ascii_lowercase();
+    lowercase.starts_with("http://") || lowercase.starts_with("https://")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, first_link};
+
+    /// Вывод не должен содержать ни одного тега, кроме тех, что создает сам рендерер
+    fn assert_only_safe_tags(html: &str) {
+        const ALLOWED: &[&str] = &["strong", "/strong", "em", "/em", "code", "/code", "pre", "/pre", "br", "a", "/a"];
+        let mut rest = html;
+        while let Some(start) = rest.find('<') {
+            let tag = &rest[start + 1..];
+            let end = tag.find('>').expect("unterminated tag in output");
+            let name = tag[..end].split_whitespace().next().unwrap_or("");
+            assert!(ALLOWED.contains(&name), "unexpected tag <{}> in {}", &tag[..end], html);
+            if name == "a" {
+                let attributes = &tag[..end];
+                assert!(
+                    attributes.starts_with("a href=\"http://")
+                        || attributes.starts_with("a href=\"https://")
+                        || attributes.starts_with("a href=\"mailto:"),
+                    "unsafe link {} in {}", attributes, html
+                );
+                assert!(attributes.ends_with("rel=\"nofollow noopener noreferrer\" target=\"_blank\""), "unexpected attributes in {}", html);
+            }
+            rest = &tag[end + 1..];
+        }
+    }
+
+    fn assert_safe(source: &str) -> String {
+        let html = render(source);
+        assert_only_safe_tags(&html);
+        html
+    }
+
+    #[test]
+    fn formats_supported_subset() {
+        assert_eq!(render("**bold** and *italic* and _also_"), "<strong>bold</strong> and <em>italic</em> and <em>also</em>");
+        assert_eq!(render("use `cargo build`"), "use <code>cargo build</code>");
+        assert_eq!(render("```\nfn main() {}\n```"), "<pre><code>fn main() {}</code></pre>");
+        assert_eq!(render("line one\nline two"), "line one<br>line two");
+        assert_eq!(
+            render("[docs](https://example.com/a_(b))"),
+            "<a href=\"https://example.com/a_(b)\" rel=\"nofollow noopener noreferrer\" target=\"_blank\">docs</a>"
+        );
+        assert_eq!(
+            render("see https://example.com."),
+            "see <a href=\"https://example.com\" rel=\"nofollow noopener noreferrer\" target=\"_blank\">https://example.com</a>."
+        );
+    }
+
+    #[test]
+    fn leaves_plain_text_alone() {
+        assert_eq!(render("snake_case_name"), "snake_case_name");
+        assert_eq!(render("2 * 3 * 4"), "2 * 3 * 4");
+        assert_eq!(render("**unterminated"), "**unterminated");
+        assert_eq!(render("\\*not italic\\*"), "*not italic*");
+        assert_eq!(render("[no url]"), "[no url]");
+    }
+
+    #[test]
+    fn escapes_raw_html() {
+        assert_eq!(render("<script>alert(1)</script>"), "&lt;script&gt;alert(1)&lt;/script&gt;");
+        assert_eq!(render("<img src=x onerror=alert(1)>"), "&lt;img src=x onerror=alert(1)&gt;");
+        assert_eq!(render("**<b>bold</b>**"), "<strong>&lt;b&gt;bold&lt;/b&gt;</strong>");
+        assert_eq!(render("`<script>`"), "<code>&lt;script&gt;</code>");
+        assert_eq!(render("```\n</code></pre><script>alert(1)</script>\n```"), "<pre><code>&lt;/code&gt;&lt;/pre&gt;&lt;script&gt;alert(1)&lt;/script&gt;</code></pre>");
+        assert_eq!(render("&lt;script&gt;"), "&amp;lt;script&amp;gt;");
+    }
+
+    #[test]
+    fn rejects_dangerous_link_schemes() {
+        for source in [
+            "[click](javascript:alert(1))",
+            "[click](JaVaScRiPt:alert(1))",
+            "[click]( javascript:alert(1))",
+            "[click](data:text/html;base64,PHNjcmlwdD5hbGVydCgxKTwvc2NyaXB0Pg==)",
+            "[click](vbscript:msgbox(1))",
+            "[click](&#106;avascript:alert(1))",
+            "[click](java\tscript:alert(1))",
+            "[click](//evil.example.com)",
+            "[click](http:)",
+            "javascript:alert(1)",
+        ] {
+            let html = assert_safe(source);
+            assert!(!html.contains("<a"), "{} produced a link: {}", source, html);
+        }
+    }
+
+    #[test]
+    fn link_urls_cannot_break_out_of_attribute() {
+        for source in [
+            "[x](https://example.com/\"onmouseover=\"alert(1))",
+            "[x](https://example.com/'onmouseover='alert(1))",
+            "[x](https://example.com/><script>alert(1)</script>)",
+            "https://example.com/\"><script>alert(1)</script>",
+            "https://example.com/`onmouseover=alert(1)",
+            "[x](https://example.com/\\\"onclick=alert(1))",
+        ] {
+            let html = assert_safe(source);
+            assert!(!html.contains("<script"), "{} produced {}", source, html);
+            assert!(!html.contains("\" onmouseover") && !html.contains("\"onmouseover"), "{} produced {}", source, html);
+        }
+    }
+
+    #[test]
+    fn link_text_is_escaped_and_not_nested() {
+        let html = assert_safe("[<img src=x onerror=alert(1)>](https://example.com)");
+        assert!(html.contains("&lt;img src=x onerror=alert(1)&gt;</a>"), "{}", html);
+
+        let html = assert_safe("[[inner](https://a.example)](https://b.example)");
+        for link in html.split("<a ").skip(1) {
+            let inner = &link[..link.find("</a>").expect("unclosed link")];
+            assert!(!inner.contains("<a "), "nested link in {}", html);
+        }
+    }
+
+    #[test]
+    fn nested_and_broken_markup_stays_balanced() {
+        for source in [
+            "**bold *italic** text*",
+            "*a **b* c**",
+            "`code **not bold**`",
+            "**[x](javascript:alert(1))**",
+            "_<svg onload=alert(1)>_",
+            "```\nunterminated <script>",
+            "\\<script>",
+            "[x](https://example.com)**",
+            "**",
+            "****",
+            "*",
+            "``",
+            "[](https://example.com)",
+        ] {
+            let html = assert_safe(source);
+            for tag in ["strong", "em", "code", "pre", "a"] {
+                let open = html.matches(&format!("<{}>", tag)).count() + html.matches(&format!("<{} ", tag)).count();
+                let close = html.matches(&format!("</{}>", tag)).count();
+                assert_eq!(open, close, "unbalanced <{}> for {}: {}", tag, source, html);
+            }
+        }
+    }
+
+    #[test]
+    fn finds_first_http_link_outside_code() {
+        assert_eq!(first_link("see https://example.com/a."), Some("https://example.com/a".to_string()));
+        assert_eq!(first_link("[docs](https://example.com/docs) and https://other.org"), Some("https://example.com/docs".to_string()));
+        assert_eq!(first_link("[mail](mailto:a@b.c) then http://example.com"), Some("http://example.com".to_string()));
+        assert_eq!(first_link("`https://example.com` is code"), None);
+        assert_eq!(first_link("```\nhttps://example.com\n```\nplain"), None);
+        assert_eq!(first_link("[x](javascript:alert(1))"), None);
+    }
+
+    #[test]
+    fn handles_control_and_unicode_characters() {
+        assert_safe("\u{0}<script>\u{0}");
+        assert_safe("**жирный** и *курсив* <b>");
+        assert_safe("[ссылка](https://пример.рф/путь)");
+        assert_safe("\u{202e}[x](https://example.com)\u{202c}");
+        assert_safe("\r\n<script>\r\n");
+    }
+
+    /// Незакрытые разделители не должны заставлять разбор просматривать строку заново:
+    /// на квадратичном разборе такие строки обрабатывались секунды
+    #[test]
+    fn pathological_input_renders_in_linear_time() {
+        const LEN: usize = 120_000;
+        let inputs = [
+            "[a ".repeat(LEN / 3),
+            "*a ".repeat(LEN / 3),
+            "**a ".repeat(LEN / 4),
+            "_a ".repeat(LEN / 3),
+            "`a ".repeat(LEN / 3),
+            "[a](x".repeat(LEN / 10) + &")".repeat(LEN / 10),
+            "http://\\".repeat(LEN / 16) + &".".repeat(LEN / 2),
+        ];
+        for input in &inputs {
+            let started = std::time::Instant::now();
+            let html = render(input);
+            first_link(input);
+            let elapsed = started.elapsed();
+            assert!(html.len() >= input.len() / 2);
+            assert!(elapsed < std::time::Duration::from_secs(2), "{:?}... took {:?}", &input[..8], elapsed);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::collections::HashSet;
+
+/// Сколько разных пользователей можно упомянуть в одном сообщении
+pub const MAX_MENTIONS: usize = 20;
+
+// Символы, которыми часто заканчивается упоминание в тексте: "@bob," или "(@alice)"
+const TRAILING_PUNCTUATION: &[char] = &['.', ',', '!', '?', ':', ';', ')', ']', '"', '\''];
+
+/// Упоминания, найденные в тексте сообщения
+#[derive(Debug, Clone, Default)]
+pub struct Mentions {
+    /// Имена упомянутых пользователей без '@', без повторов, в порядке появления
+    pub usernames: Vec<String>,
+    /// @here — все, кто сейчас в комнате
+    pub here: bool,
+    /// @room — все участники комнаты
+    pub room: bool,
+}
+
+impl Mentions {
+    pub fn is_empty(&self) -> bool {
+        self.usernames.is_empty() && !self.here && !self.room
+    }
+}
+
+/// Находит упоминания вида @username в тексте. Упоминание начинается с '@' в начале
+/// строки или после пробела/открывающей скобки и продолжается до пробела.
+pub fn parse_mentions(text: &str) -> Mentions {
+    let mut mentions = Mentions::default();
+    let mut seen = HashSet::new();
+    let mut previous: Option<char> = None;
+
+    for (index, c) in text.char_indices() {
+        let at_word_start = previous.is_none_or(|p| p.is_whitespace() || p == '(' || p == '[');
+        previous = Some(c);
+        if c != '@' || !at_word_start {
+            continue;
+        }
+
+        let rest = &text[index + 1..];
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let name = rest[..end].trim_end_matches(TRAILING_PUNCTUATION);
+
+        match name {
+            "here" => mentions.here = true,
+            "room" => mentions.room = true,
+            // Имена пользователей от 3 до 16 байт (см. проверку при регистрации)
+            _ if (3..=16).contains(&name.len())
+                && mentions.usernames.len() < MAX_MENTIONS
+                && seen.insert(name.to_string()) =>
+            {
+                mentions.usernames.push(name.to_string());
+            }
+            _ => {}
+        }
+    }
+
+    mentions
+}
@@ -0,0 +1,193 @@
+//! Метрики сервера в текстовом формате Prometheus (GET /metrics).
+//!
+//! Счетчики живут в статической памяти процесса и обновляются из обработчиков. Число
+//! WebSocket-клиентов не хранится отдельно, а берется из реестра Clients при каждом запросе.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Границы корзин гистограмм (секунды)
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Маршруты, по которым группируется время ответа. Сегмент в фигурных скобках совпадает
+/// с любым значением; остальные пути попадают в route="other", чтобы число рядов не росло
+/// от случайных запросов.
+const ROUTES: &[&str] = &[
+    "/healthz",
+    "/readyz",
+    "/metrics",
+    "/api/register",
+    "/api/login",
+    "/api/logout",
+    "/api/ws",
+    "/api/search",
+    "/api/export",
+    "/api/notifications",
+    "/api/notifications/read",
+    "/api/upload",
+    "/api/messages/{id}",
+    "/api/messages/{id}/pin",
+    "/api/moderation/mute",
+    "/api/moderation/kick",
+    "/api/moderation/ban",
+    "/api/moderation/bans/{id}",
+    "/api/rooms/{room}/pins",
+    "/api/rooms/{room}/topic",
+    "/api/rooms/{room}/members/{username}",
+    "/api/admin/users/{username}/role",
+    "/api/admin/audit",
+    "/api/admin/rooms",
+    "/api/admin/rooms/{room}/retention",
+];
+
+static MESSAGES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static BROADCAST_EVENTS: AtomicU64 = AtomicU64::new(0);
+static BROADCAST_LAGGED: AtomicU64 = AtomicU64::new(0);
+static BROADCAST_RESYNCS: AtomicU64 = AtomicU64::new(0);
+static OUTBOUND_OVERFLOWS: AtomicU64 = AtomicU64::new(0);
+static IDLE_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+static LOGIN_SUCCESSES: AtomicU64 = AtomicU64::new(0);
+static LOGIN_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// Время ответа по (маршрут, метод, статус)
+static HTTP_REQUESTS: Mutex<BTreeMap<(&'static str, String, u16), Histogram>> = Mutex::new(BTreeMap::new());
+/// Время выполнения отдельных запросов к базе данных
+static DB_QUERIES: Mutex<Histogram> = Mutex::new(Histogram::new());
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    /// counts[i] — наблюдения не больше BUCKETS[i] (без накопления)
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram { counts: [0; BUCKETS.len()], count: 0, sum: 0.0 }
+    }
+
+    fn observe(&mut self, seconds: f64) {
+        if let Some(i) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.counts[i] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, self.count);
+        let braced = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(out, "{}_sum{} {}", name, braced, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braced, self.count);
+    }
+}
+
+/// Сообщение чата получено от клиента
+pub fn message_received() {
+    MESSAGES_RECEIVED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Событие отправлено в общий канал рассылки
+pub fn event_broadcast() {
+    BROADCAST_EVENTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Медленный клиент отстал от канала рассылки, пропустил count событий и получил resync
+pub fn broadcast_lagged(count: u64) {
+    BROADCAST_RESYNCS.fetch_add(1, Ordering::Relaxed);
+    BROADCAST_LAGGED.fetch_add(count, Ordering::Relaxed);
+}
+
+/// Клиент отключен, потому что его очередь исходящих сообщений не освобождалась
+pub fn outbound_overflow() {
+    OUTBOUND_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Клиент отключен, потому что перестал отвечать на ping
+pub fn idle_timeout() {
+    IDLE_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn login_succeeded() {
+    LOGIN_SUCCESSES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn login_failed() {
+    LOGIN_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Учитывает время ответа на HTTP-запрос
+pub fn observe_request(path: &str, method: &str, status: u16, elapsed: Duration) {
+    let key = (route_label(path), method.to_string(), status);
+    HTTP_REQUESTS.lock().unwrap().entry(key).or_insert_with(Histogram::new).observe(elapsed.as_secs_f64());
+}
+
+/// Учитывает время выполнения запроса к базе данных
+pub fn observe_db_query(elapsed: Duration) {
+    DB_QUERIES.lock().unwrap().observe(elapsed.as_secs_f64());
+}
+
+/// Шаблон маршрута для пути запроса
+fn route_label(path: &str) -> &'static str {
+    let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+    ROUTES
+        .iter()
+        .find(|route| {
+            let pattern: Vec<&str> = route.split('/').collect();
+            pattern.len() == segments.len()
+                && pattern.iter().zip(&segments).all(|(expected, actual)| expected.starts_with('{') || expected == actual)
+        })
+        .copied()
+        .unwrap_or("other")
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Все метрики в текстовом формате Prometheus
+pub fn render(connected_clients: usize) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# HELP cyb3ria_websocket_clients Connected WebSocket clients.");
+    let _ = writeln!(out, "# TYPE cyb3ria_websocket_clients gauge");
+    let _ = writeln!(out, "cyb3ria_websocket_clients {}", connected_clients);
+
+    render_counter(&mut out, "cyb3ria_messages_received_total", "Chat messages received from clients.", MESSAGES_RECEIVED.load(Ordering::Relaxed));
+    render_counter(&mut out, "cyb3ria_broadcast_events_total", "Events sent to the broadcast channel.", BROADCAST_EVENTS.load(Ordering::Relaxed));
+    render_counter(&mut out, "cyb3ria_broadcast_lagged_total", "Broadcast events dropped for clients that fell behind.", BROADCAST_LAGGED.load(Ordering::Relaxed));
+    render_counter(&mut out, "cyb3ria_broadcast_resyncs_total", "Times a client fell behind the broadcast channel and was told to resync.", BROADCAST_RESYNCS.load(Ordering::Relaxed));
+
+    render_counter(&mut out, "cyb3ria_outbound_overflows_total", "Clients disconnected because their outbound queue stayed full.", OUTBOUND_OVERFLOWS.load(Ordering::Relaxed));
+    render_counter(&mut out, "cyb3ria_idle_timeouts_total", "Clients disconnected after missing too many pongs.", IDLE_TIMEOUTS.load(Ordering::Relaxed));
+
+    let _ = writeln!(out, "# HELP cyb3ria_logins_total Login attempts by result.");
+    let _ = writeln!(out, "# TYPE cyb3ria_logins_total counter");
+    let _ = writeln!(out, "cyb3ria_logins_total{{result=\"success\"}} {}", LOGIN_SUCCESSES.load(Ordering::Relaxed));
+    let _ = writeln!(out, "cyb3ria_logins_total{{result=\"failure\"}} {}", LOGIN_FAILURES.load(Ordering::Relaxed));
+
+    let _ = writeln!(out, "# HELP cyb3ria_http_request_duration_seconds HTTP response time by route.");
+    let _ = writeln!(out, "# TYPE cyb3ria_http_request_duration_seconds histogram");
+    for ((route, method, status), histogram) in HTTP_REQUESTS.lock().unwrap().iter() {
+        let labels = format!("route=\"{}\",method=\"{}\",status=\"{}\"", route, method, status);
+        histogram.render(&mut out, "cyb3ria_http_request_duration_seconds", &labels);
+    }
+
+    let _ = writeln!(out, "# HELP cyb3ria_db_query_duration_seconds Time to run one database query.");
+    let _ = writeln!(out, "# TYPE cyb3ria_db_query_duration_seconds histogram");
+    DB_QUERIES.lock().unwrap().render(&mut out, "cyb3ria_db_query_duration_seconds", "");
+
+    out
+}
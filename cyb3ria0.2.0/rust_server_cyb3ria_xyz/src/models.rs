@@ -1,6 +1,46 @@
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
+use std::fmt;
+use std::str::FromStr;
+
+/// Роль пользователя. Порядок вариантов задает старшинство: Admin > Moderator > Member.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Member,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "member" => Ok(Role::Member),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            other => Err(format!("Unknown role: {}", other)),
+        }
+    }
+}
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct User {
@@ -8,6 +48,7 @@ pub struct User {
     pub password_hash: String,
     pub invitation_code: String,
     pub user_uuid: Uuid,
+    pub role: Role,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -23,4 +64,197 @@ pub struct Session {
     pub user_uuid: Uuid,
     pub device_id: Uuid,
     pub expires_at: Option<DateTime<Utc>>,
-}
\ No newline at end of file
+}
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Ban {
+    pub id: i64,
+    pub user_uuid: Option<Uuid>,
+    /// Адрес или подсеть в нотации CIDR
+    pub ip: Option<String>,
+    pub reason: String,
+    pub created_by: Uuid,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Запись журнала аудита
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    pub actor_uuid: Option<Uuid>,
+    /// Имя пользователя-инициатора (заполняется при чтении журнала)
+    pub actor: Option<String>,
+    pub target_uuid: Option<Uuid>,
+    /// Имя пользователя, над которым выполнено действие (заполняется при чтении журнала)
+    pub target: Option<String>,
+    pub action: String,
+    pub ip: Option<String>,
+    pub details: serde_json::Value,
+}
+
+/// Фильтры выборки журнала аудита
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub action: Option<String>,
+    pub ip: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Фильтры полнотекстового поиска по сообщениям
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct SearchFilter {
+    pub query: String,
+    /// Кто ищет: результаты только из комнат, где он участник
+    pub viewer: Uuid,
+    pub author: Option<String>,
+    pub room: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Найденное сообщение с фрагментом текста, в котором совпадения выделены тегом <mark>
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SearchResult {
+    pub id: i64,
+    pub room: String,
+    pub username: String,
+    pub timestamp: DateTime<Utc>,
+    pub parent_id: Option<i64>,
+    /// HTML: текст сообщения экранирован, разметка — только <mark>
+    pub snippet: String,
+}
+
+/// Уведомление пользователя об упоминании в сообщении
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Notification {
+    pub id: i64,
+    /// mention, here или room
+    pub kind: String,
+    pub message_id: i64,
+    pub room: String,
+    /// Автор сообщения
+    pub author: String,
+    /// Текст сообщения; None, если сообщение удалено модератором
+    pub message: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub read_at: Option<DateTime<Utc>>,
+}
+
+/// Превью ссылки из сообщения: метаданные OpenGraph или заголовок страницы
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Абсолютный http(s)-адрес картинки
+    pub image: Option<String>,
+    pub site_name: Option<String>,
+}
+
+impl LinkPreview {
+    /// Превью без заголовка и описания не показывается
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.description.is_none()
+    }
+}
+
+/// Срок хранения сообщений комнаты. Задано не больше одного ограничения; оба None — хранить всегда.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RoomRetention {
+    pub room: String,
+    /// Удалять сообщения старше стольких дней
+    pub days: Option<i32>,
+    /// Хранить только столько последних корневых сообщений (с их ветками)
+    pub messages: Option<i32>,
+}
+
+/// Итог удаления одной пачки сообщений с истекшим сроком хранения
+#[derive(Debug, Clone, Default)]
+pub struct PurgedBatch {
+    /// Удаленные сообщения, отобранные по сроку хранения
+    pub messages: i64,
+    /// Ответы, удаленные каскадно вместе с корнем ветки
+    pub replies: i64,
+    /// Пути файлов удаленных вложений внутри uploads.root
+    pub files: Vec<String>,
+}
+
+/// Тема и описание комнаты
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RoomTopic {
+    pub room: String,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    /// Кто и когда последним менял тему; None, если тема не задавалась
+    pub updated_by: Option<String>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// Результат попытки закрепить сообщение
+#[derive(Debug, Clone, PartialEq)]
+pub enum PinOutcome {
+    /// Сообщение закреплено в комнате room
+    Pinned { room: String },
+    AlreadyPinned,
+    /// В комнате уже закреплено максимальное число сообщений
+    LimitReached,
+    NotFound,
+}
+
+/// Что выгружается через /api/export
+#[derive(Debug, Clone)]
+pub enum ExportScope {
+    /// Вся история комнаты
+    Room(String),
+    /// Все сообщения пользователя во всех комнатах
+    User(Uuid),
+}
+
+/// Сообщение в выгрузке истории
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ExportedMessage {
+    pub id: i64,
+    pub room: String,
+    pub username: String,
+    pub timestamp: DateTime<Utc>,
+    pub parent_id: Option<i64>,
+    pub message: String,
+    /// Безопасный HTML сообщения (не попадает в JSON Lines)
+    #[serde(skip)]
+    pub html: String,
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Предыдущие версии сообщения, от старых к новым
+    pub edits: Vec<MessageEdit>,
+    /// Прикрепленные файлы в порядке загрузки
+    pub attachments: Vec<Attachment>,
+}
+
+/// Файл, прикрепленный к сообщению
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Attachment {
+    pub file_name: String,
+    pub content_type: Option<String>,
+    pub size: i64,
+    /// Ссылка на файл: /uploaded/{path}
+    pub url: String,
+}
+
+/// Предыдущая версия отредактированного сообщения
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MessageEdit {
+    pub message: String,
+    /// Когда эту версию заменила следующая
+    pub replaced_at: DateTime<Utc>,
+}
+
+/// Результат попытки отредактировать сообщение
+#[derive(Debug, Clone)]
+pub enum EditOutcome {
+    Edited { edited_at: DateTime<Utc> },
+    NotFound,
+    NotAuthor,
+    WindowExpired,
+}
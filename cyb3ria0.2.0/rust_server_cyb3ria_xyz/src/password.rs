@@ -0,0 +1,151 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use std::collections::HashSet;
+use std::error::Error as StdError;
+use std::sync::OnceLock;
+
+/// Минимальная длина пароля при регистрации (в символах)
+pub const MIN_PASSWORD_CHARS: usize = 8;
+
+/// Максимальная длина пароля в символах. Argon2 не обрезает ввод, как bcrypt,
+/// но совсем без ограничения хэширование превращается в способ нагрузить сервер.
+/// Символы, а не байты: maxlength в формах считает UTF-16 единицы, которых не меньше,
+/// чем символов, поэтому пароль, принятый формой, принимает и сервер.
+pub const MAX_PASSWORD_CHARS: usize = 1024;
+
+static COMMON_PASSWORDS: OnceLock<HashSet<String>> = OnceLock::new();
+
+/// Проверяет, есть ли пароль в списке распространённых паролей
+fn is_common_password(password: &str) -> bool {
+    let list = COMMON_PASSWORDS.get_or_init(|| {
+        include_str!("data/common_passwords.txt")
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_lowercase)
+            .collect()
+    });
+    list.contains(&password.to_lowercase())
+}
+
+/// Проверяет пароль на соответствие политике: длина и отсутствие в списке распространённых
+pub fn check_password_policy(password: &str) -> Result<(), String> {
+    if !(MIN_PASSWORD_CHARS..=MAX_PASSWORD_CHARS).contains(&password.chars().count()) {
+        return Err(format!("Password must be between {} and {} characters", MIN_PASSWORD_CHARS, MAX_PASSWORD_CHARS));
+    }
+    if is_common_password(password) {
+        return Err("Password is too common, please choose another one".to_string());
+    }
+    Ok(())
+}
+
+// Параметры Argon2id по рекомендации OWASP (19 MiB, 2 итерации, 1 поток)
+fn argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::default())
+}
+
+/// Хэширует пароль с помощью Argon2id в отдельном blocking-потоке
+pub async fn hash_password(password: String) -> Result<String, Box<dyn StdError + Send + Sync>> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        argon2()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| e.to_string().into())
+    })
+    .await?
+}
+
+/// Проверяет пароль по хэшу. Поддерживает Argon2 и старые хэши bcrypt.
+pub async fn verify_password(password: String, password_hash: String) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+    tokio::task::spawn_blocking(move || {
+        if is_bcrypt_hash(&password_hash) {
+            return Ok(bcrypt::verify(&password, &password_hash)?);
+        }
+
+        let parsed = PasswordHash::new(&password_hash).map_err(|e| e.to_string())?;
+        Ok(argon2().verify_password(password.as_bytes(), &parsed).is_ok())
+    })
+    .await?
+}
+
+/// Новый хэш Argon2id для пароля, который только что прошел проверку, если старый хэш
+/// устарел (см. needs_rehash); None, если обновлять нечего
+pub async fn upgraded_hash(password: String, password_hash: &str) -> Result<Option<String>, Box<dyn StdError + Send + Sync>> {
+    if !needs_rehash(password_hash) {
+        return Ok(None);
+    }
+    hash_password(password).await.map(Some)
+}
+
+/// Нужно ли перехэшировать пароль после успешного входа:
+/// хэш bcrypt или Argon2 с устаревшими параметрами.
+fn needs_rehash(password_hash: &str) -> bool {
+    if is_bcrypt_hash(password_hash) {
+        return true;
+    }
+
+    match PasswordHash::new(password_hash) {
+        Ok(parsed) => {
+            parsed.algorithm != Algorithm::Argon2id.ident()
+                || Params::try_from(&parsed).map(|params| !has_default_cost(&params)).unwrap_or(true)
+        }
+        Err(_) => true,
+    }
+}
+
+// Сравниваются только параметры стоимости: длина результата в разобранном хэше
+// указана явно, а в Params::default() — нет
+fn has_default_cost(params: &Params) -> bool {
+    let default = Params::default();
+    params.m_cost() == default.m_cost() && params.t_cost() == default.t_cost() && params.p_cost() == default.p_cost()
+}
+
+fn is_bcrypt_hash(password_hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| password_hash.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hashes_and_verifies_with_argon2id() {
+        let hash = hash_password("correct horse battery".to_string()).await.unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse battery".to_string(), hash.clone()).await.unwrap());
+        assert!(!verify_password("correct horse battery!".to_string(), hash.clone()).await.unwrap());
+        assert!(!needs_rehash(&hash));
+    }
+
+    #[tokio::test]
+    async fn upgrades_legacy_bcrypt_hash_on_login() {
+        let legacy = bcrypt::hash("old secret", 4).unwrap();
+        assert!(verify_password("old secret".to_string(), legacy.clone()).await.unwrap());
+
+        let upgraded = upgraded_hash("old secret".to_string(), &legacy).await.unwrap().expect("bcrypt hash must be upgraded");
+        assert!(upgraded.starts_with("$argon2id$"));
+        assert!(verify_password("old secret".to_string(), upgraded.clone()).await.unwrap());
+
+        // Свежий хэш повторно не обновляется
+        assert!(upgraded_hash("old secret".to_string(), &upgraded).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn enforces_length_in_characters() {
+        assert!(check_password_policy("Zq8vLp2").is_err());
+        assert!(check_password_policy("Zq8vLp2x").is_ok());
+
+        // Многобайтные символы считаются по одному, как в maxlength формы
+        assert!(check_password_policy(&"ж".repeat(MAX_PASSWORD_CHARS)).is_ok());
+        assert!(check_password_policy(&"ж".repeat(MAX_PASSWORD_CHARS + 1)).is_err());
+        assert!(check_password_policy(&"x".repeat(MAX_PASSWORD_CHARS + 1)).is_err());
+    }
+
+    #[test]
+    fn rejects_common_passwords() {
+        assert!(check_password_policy("password123").is_err());
+        assert!(check_password_policy("PASSWORD123").is_err());
+    }
+}
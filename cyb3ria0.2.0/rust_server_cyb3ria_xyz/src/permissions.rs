@@ -0,0 +1,101 @@
+use warp::{Filter, Rejection, Reply, http::StatusCode, reject::Reject};
+use crate::models::{User, Role};
+use crate::db::find_user_by_session;
+use crate::config;
+use crate::handlers::files;
+use log::error;
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Имя cookie, в которой хранится идентификатор сессии
+pub const SESSION_COOKIE: &str = "session_id";
+
+/// Запрос без действующей сессии
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl Reject for Unauthorized {}
+
+/// Пользователь вошел в систему, но его роли недостаточно
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl Reject for Forbidden {}
+
+/// Пользователь или его IP-адрес забанен
+#[derive(Debug)]
+pub struct Banned;
+
+impl Reject for Banned {}
+
+/// WebSocket открыт со страницы чужого сайта (заголовок Origin не из списка разрешенных)
+#[derive(Debug)]
+pub struct ForeignOrigin;
+
+impl Reject for ForeignOrigin {}
+
+/// Проверку доступа не удалось выполнить (например, недоступна база данных)
+#[derive(Debug)]
+pub struct InternalError;
+
+impl Reject for InternalError {}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    message: String,
+}
+
+/// Определяет пользователя по cookie сессии, если она есть и действительна
+pub async fn user_from_session_cookie(cookie: Option<String>) -> Option<User> {
+    let session_id = cookie.and_then(|value| Uuid::parse_str(&value).ok())?;
+    match find_user_by_session(session_id).await {
+        Ok(user) => user,
+        Err(e) => {
+            error!("Failed to find session: {}", e);
+            None
+        }
+    }
+}
+
+/// Фильтр, извлекающий текущего пользователя из сессии. Без сессии запрос отклоняется.
+pub fn authenticated() -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+    warp::cookie::optional::<String>(SESSION_COOKIE).and_then(|cookie: Option<String>| async move {
+        user_from_session_cookie(cookie).await.ok_or_else(|| warp::reject::custom(Unauthorized))
+    })
+}
+
+/// Фильтр, пропускающий только пользователей с ролью не ниже `role`
+pub fn require_role(role: Role) -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+    authenticated().and_then(move |user: User| async move {
+        if user.role >= role {
+            Ok(user)
+        } else {
+            Err(warp::reject::custom(Forbidden))
+        }
+    })
+}
+
+/// Превращает отказы фильтров доступа в JSON-ответы с нужным статусом. Если сервер сам
+/// раздает фронтенд, на несуществующие адреса отдается 404.html.
+pub async fn handle_rejection(err: Rejection) -> Result<warp::reply::Response, Rejection> {
+    let (status, message) = if err.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, "Authentication required.")
+    } else if err.find::<Forbidden>().is_some() {
+        (StatusCode::FORBIDDEN, "Insufficient permissions.")
+    } else if err.find::<Banned>().is_some() {
+        (StatusCode::FORBIDDEN, "You are banned.")
+    } else if err.find::<ForeignOrigin>().is_some() {
+        (StatusCode::FORBIDDEN, "Origin not allowed.")
+    } else if err.find::<InternalError>().is_some() {
+        (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error.")
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "Request body is too large.")
+    } else if err.is_not_found() && config::get().static_files.enabled {
+        return Ok(files::not_found_page().await);
+    } else {
+        return Err(err);
+    };
+
+    let response = ErrorResponse { message: message.to_string() };
+    Ok(warp::reply::with_status(warp::reply::json(&response), status).into_response())
+}
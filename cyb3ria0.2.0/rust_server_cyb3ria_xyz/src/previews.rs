@@ -0,0 +1,592 @@
+//! Превью ссылок из сообщений.
+//!
+//! Страница загружается в фоне после отправки сообщения, превью кэшируется в таблице link_previews.
+//! Загрузка ограничена по времени и размеру. Чтобы сервер нельзя было использовать для запросов
+//! во внутреннюю сеть (SSRF), имя хоста разрешается заранее, каждый адрес проверяется, а соединение
+//! открывается только с проверенными адресами. Перенаправления проверяются так же, по одному.
+
+use async_trait::async_trait;
+use log::{debug, error};
+use reqwest::header::{ACCEPT, CONTENT_TYPE, LOCATION};
+use reqwest::{redirect, Url};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use url::Host;
+use crate::db::{find_link_preview, save_link_preview};
+use crate::models::LinkPreview;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Читается только начало страницы: метаданные находятся в <head>
+const MAX_BODY_BYTES: usize = 256 * 1024;
+const MAX_REDIRECTS: usize = 3;
+const MAX_CONCURRENT_FETCHES: usize = 8;
+const MAX_URL_LEN: usize = 2048;
+const MAX_TITLE_CHARS: usize = 200;
+const MAX_DESCRIPTION_CHARS: usize = 500;
+const USER_AGENT: &str = "cyb3ria-preview/0.1";
+/// Сколько часов превью берется из кэша без повторной загрузки
+const CACHE_TTL_HOURS: i64 = 24;
+
+pub type SharedFetcher = Arc<dyn Fetcher>;
+
+/// Загруженная HTML-страница и ее адрес после перенаправлений
+pub struct Page {
+    pub url: Url,
+    pub html: String,
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// Адрес не http(s) или разрешается в запрещенную сеть
+    Blocked(String),
+    Timeout,
+    Status(u16),
+    NotHtml,
+    TooManyRedirects,
+    Http(reqwest::Error),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Blocked(reason) => write!(f, "blocked: {}", reason),
+            FetchError::Timeout => f.write_str("timed out"),
+            FetchError::Status(status) => write!(f, "unexpected status {}", status),
+            FetchError::NotHtml => f.write_str("not an HTML page"),
+            FetchError::TooManyRedirects => f.write_str("too many redirects"),
+            FetchError::Http(e) => write!(f, "http error: {}", e),
+            FetchError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> Self {
+        FetchError::Http(e)
+    }
+}
+
+impl From<std::io::Error> for FetchError {
+    fn from(e: std::io::Error) -> Self {
+        FetchError::Io(e)
+    }
+}
+
+/// Источник страниц для превью
+#[async_trait]
+pub trait Fetcher: Send + Sync {
+    async fn fetch(&self, url: &Url) -> Result<Page, FetchError>;
+}
+
+/// Загрузка страниц по HTTP(S) с проверкой адресов назначения
+pub struct HttpFetcher {
+    /// Разрешен ли адрес назначения; по умолчанию только публичные адреса
+    is_allowed: fn(&IpAddr) -> bool,
+    timeout: Duration,
+    permits: Semaphore,
+}
+
+impl Default for HttpFetcher {
+    fn default() -> Self {
+        HttpFetcher {
+            is_allowed: is_public_address,
+            timeout: FETCH_TIMEOUT,
+            permits: Semaphore::new(MAX_CONCURRENT_FETCHES),
+        }
+    }
+}
+
+impl HttpFetcher {
+    /// Разрешает имя хоста и возвращает его адреса, если все они разрешены
+    async fn resolve(&self, url: &Url) -> Result<Vec<SocketAddr>, FetchError> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(FetchError::Blocked(format!("scheme {}", url.scheme())));
+        }
+        let port = url.port_or_known_default().ok_or_else(|| FetchError::Blocked("no port".to_string()))?;
+
+        let addrs: Vec<SocketAddr> = match url.host() {
+            Some(Host::Ipv4(ip)) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+            Some(Host::Ipv6(ip)) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+            Some(Host::Domain(domain)) => tokio::net::lookup_host((domain, port)).await?.collect(),
+            None => return Err(FetchError::Blocked("no host".to_string())),
+        };
+
+        if addrs.is_empty() {
+            return Err(FetchError::Blocked("host has no addresses".to_string()));
+        }
+        // Достаточно одного запрещенного адреса: иначе выбор адреса при соединении решал бы, куда пойдет запрос
+        if let Some(addr) = addrs.iter().find(|addr| !(self.is_allowed)(&addr.ip())) {
+            return Err(FetchError::Blocked(format!("address {}", addr.ip())));
+        }
+
+        Ok(addrs)
+    }
+
+    /// Выполняет один запрос без перенаправлений к проверенным адресам
+    async fn request(&self, url: &Url) -> Result<reqwest::Response, FetchError> {
+        let addrs = self.resolve(url).await?;
+
+        // Соединение только с уже проверенными адресами: повторное разрешение имени
+        // могло бы вернуть другой адрес (DNS rebinding). Прокси из окружения не используются.
+        let mut builder = reqwest::Client::builder()
+            .redirect(redirect::Policy::none())
+            .no_proxy()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .user_agent(USER_AGENT);
+        if let Some(Host::Domain(domain)) = url.host() {
+            builder = builder.resolve_to_addrs(domain, &addrs);
+        }
+
+        let response = builder.build()?
+            .get(url.clone())
+            .header(ACCEPT, "text/html,application/xhtml+xml")
+            .send()
+            .await?;
+        Ok(response)
+    }
+
+    async fn fetch_page(&self, url: &Url) -> Result<Page, FetchError> {
+        let mut url = url.clone();
+
+        for _ in 0..=MAX_REDIRECTS {
+            let mut response = self.request(&url).await?;
+            let status = response.status();
+
+            if status.is_redirection() {
+                let location = response.headers()
+                    .get(LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or(FetchError::Status(status.as_u16()))?;
+                url = url.join(location).map_err(|_| FetchError::Blocked("invalid redirect".to_string()))?;
+                continue;
+            }
+            if !status.is_success() {
+                return Err(FetchError::Status(status.as_u16()));
+            }
+
+            let content_type = response.headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            if !content_type.starts_with("text/html") && !content_type.starts_with("application/xhtml+xml") {
+                return Err(FetchError::NotHtml);
+            }
+
+            let mut body = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                let take = chunk.len().min(MAX_BODY_BYTES - body.len());
+                body.extend_from_slice(&chunk[..take]);
+                if body.len() >= MAX_BODY_BYTES {
+                    break;
+                }
+            }
+
+            return Ok(Page { url, html: String::from_utf8_lossy(&body).into_owned() });
+        }
+
+        Err(FetchError::TooManyRedirects)
+    }
+}
+
+#[async_trait]
+impl Fetcher for HttpFetcher {
+    async fn fetch(&self, url: &Url) -> Result<Page, FetchError> {
+        let _permit = self.permits.acquire().await.map_err(|_| FetchError::Timeout)?;
+        tokio::time::timeout(self.timeout, self.fetch_page(url)).await.unwrap_or(Err(FetchError::Timeout))
+    }
+}
+
+/// Проверяет, что адрес публичный: не локальный, не частный, не служебный
+pub fn is_public_address(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(ipv4) = ip.to_ipv4_mapped() {
+                return is_public_ipv4(&ipv4);
+            }
+            let segments = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || segments[..6] == [0; 6]                          // ::a.b.c.d (IPv4-compatible)
+                || (segments[0] & 0xfe00) == 0xfc00                 // fc00::/7, unique local
+                || (segments[0] & 0xffc0) == 0xfe80                 // fe80::/10, link-local
+                || (segments[0] & 0xffc0) == 0xfec0                 // fec0::/10, site-local
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8) // 2001:db8::/32, документация
+                || (segments[0] == 0x2001 && segments[1] == 0)      // 2001::/32, Teredo
+                || (segments[0] == 0x64 && segments[1] == 0xff9b)   // 64:ff9b::/96, NAT64
+                || segments[0] == 0x2002)                           // 2002::/16, 6to4
+        }
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(a == 0                                // 0.0.0.0/8
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()               // 169.254.0.0/16, в том числе метаданные облаков
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || (a == 100 && (b & 0xc0) == 64)   // 100.64.0.0/10, CGNAT
+        || (a == 192 && b == 0 && c == 0)   // 192.0.0.0/24
+        || (a == 198 && (b & 0xfe) == 18)   // 198.18.0.0/15
+        || a >= 240)                        // 240.0.0.0/4
+}
+
+/// Возвращает превью ссылки из кэша или загружает страницу. None, если превью у страницы нет
+/// или загрузить ее не удалось; неудачные загрузки тоже кэшируются.
+pub async fn load_preview(fetcher: &dyn Fetcher, url: &str) -> Option<LinkPreview> {
+    if url.len() > MAX_URL_LEN {
+        return None;
+    }
+
+    match find_link_preview(url, chrono::Duration::hours(CACHE_TTL_HOURS)).await {
+        Ok(Some(preview)) => return Some(preview).filter(|preview| !preview.is_empty()),
+        Ok(None) => {}
+        Err(e) => {
+            error!("Failed to read link preview cache: {}", e);
+            return None;
+        }
+    }
+
+    let parsed = Url::parse(url).ok()?;
+    let preview = match fetcher.fetch(&parsed).await {
+        Ok(page) => parse_preview(url, &page.url, &page.html),
+        Err(e) => {
+            debug!("No preview for {}: {}", url, e);
+            LinkPreview { url: url.to_string(), ..Default::default() }
+        }
+    };
+
+    if let Err(e) = save_link_preview(&preview).await {
+        error!("Failed to save link preview: {}", e);
+    }
+
+    Some(preview).filter(|preview| !preview.is_empty())
+}
+
+/// Извлекает превью из HTML: OpenGraph, затем twitter:* и обычные <title> и description.
+/// Адрес картинки разрешается относительно адреса страницы.
+pub fn parse_preview(url: &str, page_url: &Url, html: &str) -> LinkPreview {
+    // Смещения в lowercase совпадают с исходной строкой: меняются только ASCII-буквы
+    let lowercase = html.to_ascii_lowercase();
+
+    let mut meta: HashMap<String, String> = HashMap::new();
+    let mut position = 0;
+    while let Some(offset) = lowercase[position..].find("<meta") {
+        let start = position + offset + "<meta".len();
+        let end = lowercase[start..].find('>').map_or(lowercase.len(), |end| start + end);
+        let attributes = parse_attributes(&html[start..end]);
+        let key = attributes.get("property").or_else(|| attributes.get("name"));
+        if let (Some(key), Some(content)) = (key, attributes.get("content")) {
+            meta.entry(key.to_ascii_lowercase()).or_insert_with(|| content.clone());
+        }
+        position = end;
+    }
+
+    let title_tag = lowercase.find("<title").and_then(|start| {
+        let start = start + lowercase[start..].find('>')? + 1;
+        let end = start + lowercase[start..].find("</title")?;
+        Some(&html[start..end])
+    });
+
+    let first = |keys: &[&str]| keys.iter().find_map(|key| meta.get(*key).map(String::as_str));
+
+    LinkPreview {
+        url: url.to_string(),
+        title: first(&["og:title", "twitter:title"]).or(title_tag).and_then(|title| clean_text(title, MAX_TITLE_CHARS)),
+        description: first(&["og:description", "twitter:description", "description"])
+            .and_then(|description| clean_text(description, MAX_DESCRIPTION_CHARS)),
+        image: first(&["og:image", "og:image:url", "twitter:image"])
+            .and_then(|image| page_url.join(decode_entities(image.trim()).as_str()).ok())
+            .filter(|image| matches!(image.scheme(), "http" | "https") && image.as_str().len() <= MAX_URL_LEN)
+            .map(String::from),
+        site_name: first(&["og:site_name"]).and_then(|name| clean_text(name, MAX_TITLE_CHARS)),
+    }
+}
+
+/// Разбирает атрибуты тега: name="value", name='value', name=value и атрибуты без значения
+fn parse_attributes(tag: &str) -> HashMap<String, String> {
+    let chars: Vec<char> = tag.chars().collect();
+    let mut attributes = HashMap::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        if chars[i].is_whitespace() || chars[i] == '/' {
+            i += 1;
+            continue;
+        }
+
+        let name_start = i;
+        while i < chars.len() && !chars[i].is_whitespace() && chars[i] != '=' {
+            i += 1;
+        }
+        let name: String = chars[name_start..i].iter().collect::<String>().to_ascii_lowercase();
+
+        while i < chars.len() && chars[i].is_whitespace() {
+            i += 1;
+        }
+        if chars.get(i) != Some(&'=') {
+            attributes.entry(name).or_default();
+            continue;
+        }
+        i += 1;
+        while i < chars.len() && chars[i].is_whitespace() {
+            i += 1;
+        }
+
+        let value: String = match chars.get(i) {
+            Some(&quote) if quote == '"' || quote == '\'' => {
+                let start = i + 1;
+                let end = chars[start..].iter().position(|&c| c == quote).map_or(chars.len(), |end| start + end);
+                i = end + 1;
+                chars[start..end].iter().collect()
+            }
+            _ => {
+                let start = i;
+                while i < chars.len() && !chars[i].is_whitespace() {
+                    i += 1;
+                }
+                chars[start..i].iter().collect()
+            }
+        };
+        attributes.entry(name).or_insert(value);
+    }
+
+    attributes
+}
+
+/// Декодирует сущности HTML, схлопывает пробелы и обрезает текст до max_chars символов
+fn clean_text(text: &str, max_chars: usize) -> Option<String> {
+    let decoded = decode_entities(text).replace(|c: char| c.is_control(), " ");
+    let collapsed = decoded.split_whitespace().collect::<Vec<_>>().join(" ");
+    if collapsed.is_empty() {
+        return None;
+    }
+    if collapsed.chars().count() <= max_chars {
+        return Some(collapsed);
+    }
+    let mut truncated: String = collapsed.chars().take(max_chars - 1).collect();
+    truncated.push('…');
+    Some(truncated)
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest[1..].find(';').filter(|&end| end <= 10).map(|end| &rest[1..end + 1]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => {
+                let code = entity.strip_prefix('#')?;
+                let code = match code.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => code.parse().ok()?,
+                };
+                char::from_u32(code)
+            }
+        });
+
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const PAGE: &str = "<html><head>\
+        <title>Fallback &amp; title</title>\
+        <meta property=\"og:title\" content=\"Cyb3ria &quot;chat&quot;\">\
+        <meta name='description' content='Plain description'>\
+        <meta property=og:description content=\"OG description\">\
+        <meta property=\"og:image\" content=\"/img/cover.png\">\
+        <meta property=\"og:site_name\" content=\"Cyb3ria\">\
+        </head><body>hello</body></html>";
+
+    /// Локальный HTTP-сервер для тестов загрузчика: отвечает по пути запроса
+    async fn test_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 1024];
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match socket.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buffer[..n]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request);
+                    let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+
+                    let response = match path.as_str() {
+                        "/page" => html_response(PAGE),
+                        "/title-only" => html_response("<title>\n  Just   a title\n</title>"),
+                        "/huge" => html_response(&format!("<title>Huge</title>{}", "x".repeat(4 * MAX_BODY_BYTES))),
+                        "/json" => "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nConnection: close\r\n\r\n{}".to_string(),
+                        "/redirect" => "HTTP/1.1 302 Found\r\nLocation: /page\r\nConnection: close\r\n\r\n".to_string(),
+                        "/redirect-private" => "HTTP/1.1 302 Found\r\nLocation: http://10.0.0.1/\r\nConnection: close\r\n\r\n".to_string(),
+                        "/slow" => {
+                            tokio::time::sleep(Duration::from_secs(10)).await;
+                            html_response(PAGE)
+                        }
+                        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                    };
+                    let _ = socket.write_all(response.as_bytes()).await;
+                    let _ = socket.shutdown().await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    fn html_response(body: &str) -> String {
+        format!("HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+    }
+
+    /// Загрузчик, которому разрешен только loopback, чтобы ходить на локальный тестовый сервер
+    fn local_fetcher() -> HttpFetcher {
+        HttpFetcher { is_allowed: |ip| ip.is_loopback(), timeout: Duration::from_secs(1), ..HttpFetcher::default() }
+    }
+
+    fn url(addr: SocketAddr, path: &str) -> Url {
+        Url::parse(&format!("http://{}{}", addr, path)).unwrap()
+    }
+
+    #[test]
+    fn parses_open_graph_and_falls_back_to_title() {
+        let page_url = Url::parse("https://example.com/post/1").unwrap();
+        let preview = parse_preview("https://example.com/post/1", &page_url, PAGE);
+        assert_eq!(preview.title.as_deref(), Some("Cyb3ria \"chat\""));
+        assert_eq!(preview.description.as_deref(), Some("OG description"));
+        assert_eq!(preview.image.as_deref(), Some("https://example.com/img/cover.png"));
+        assert_eq!(preview.site_name.as_deref(), Some("Cyb3ria"));
+
+        let preview = parse_preview("https://example.com", &page_url, "<TITLE>Tom &#38; Jerry &#x263A;</TITLE>");
+        assert_eq!(preview.title.as_deref(), Some("Tom & Jerry ☺"));
+        assert_eq!(preview.description, None);
+
+        assert!(parse_preview("https://example.com", &page_url, "<p>no metadata</p>").is_empty());
+    }
+
+    #[test]
+    fn rejects_unsafe_images_and_truncates_long_text() {
+        let page_url = Url::parse("https://example.com/").unwrap();
+        let html = format!(
+            "<meta property=\"og:image\" content=\"javascript:alert(1)\"><meta property=\"og:title\" content=\"{}\">",
+            "a".repeat(1000)
+        );
+        let preview = parse_preview("https://example.com/", &page_url, &html);
+        assert_eq!(preview.image, None);
+        let title = preview.title.unwrap();
+        assert_eq!(title.chars().count(), MAX_TITLE_CHARS);
+        assert!(title.ends_with('…'));
+    }
+
+    #[test]
+    fn blocks_private_and_loopback_addresses() {
+        for blocked in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0",
+            "100.64.0.1", "255.255.255.255", "224.0.0.1", "::1", "::", "fc00::1", "fe80::1",
+            "::ffff:127.0.0.1", "::ffff:10.0.0.1", "64:ff9b::a00:1", "2002:a00:1::",
+        ] {
+            assert!(!is_public_address(&blocked.parse().unwrap()), "{} must be blocked", blocked);
+        }
+        for allowed in ["93.184.216.34", "1.1.1.1", "2606:4700:4700::1111", "::ffff:93.184.216.34"] {
+            assert!(is_public_address(&allowed.parse().unwrap()), "{} must be allowed", allowed);
+        }
+        assert!(!is_public_address(&IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    }
+
+    #[tokio::test]
+    async fn default_fetcher_refuses_local_server() {
+        let addr = test_server().await;
+        let fetcher = HttpFetcher::default();
+
+        for target in [url(addr, "/page"), Url::parse(&format!("http://localhost:{}/page", addr.port())).unwrap()] {
+            assert!(matches!(fetcher.fetch(&target).await, Err(FetchError::Blocked(_))), "{} must be blocked", target);
+        }
+        let ftp = Url::parse("ftp://example.com/").unwrap();
+        assert!(matches!(fetcher.fetch(&ftp).await, Err(FetchError::Blocked(_))));
+    }
+
+    #[tokio::test]
+    async fn fetches_and_parses_page_from_local_server() {
+        let addr = test_server().await;
+        let fetcher = local_fetcher();
+
+        let page = fetcher.fetch(&url(addr, "/page")).await.unwrap();
+        let preview = parse_preview(page.url.as_str(), &page.url, &page.html);
+        assert_eq!(preview.title.as_deref(), Some("Cyb3ria \"chat\""));
+        assert_eq!(preview.image, Some(format!("http://{}/img/cover.png", addr)));
+
+        let page = fetcher.fetch(&url(addr, "/title-only")).await.unwrap();
+        assert_eq!(parse_preview("", &page.url, &page.html).title.as_deref(), Some("Just a title"));
+    }
+
+    #[tokio::test]
+    async fn follows_redirects_and_checks_every_hop() {
+        let addr = test_server().await;
+        let fetcher = local_fetcher();
+
+        let page = fetcher.fetch(&url(addr, "/redirect")).await.unwrap();
+        assert_eq!(page.url, url(addr, "/page"));
+
+        assert!(matches!(fetcher.fetch(&url(addr, "/redirect-private")).await, Err(FetchError::Blocked(_))));
+    }
+
+    #[tokio::test]
+    async fn enforces_size_type_and_time_limits() {
+        let addr = test_server().await;
+        let fetcher = local_fetcher();
+
+        let page = fetcher.fetch(&url(addr, "/huge")).await.unwrap();
+        assert_eq!(page.html.len(), MAX_BODY_BYTES);
+
+        assert!(matches!(fetcher.fetch(&url(addr, "/json")).await, Err(FetchError::NotHtml)));
+        assert!(matches!(fetcher.fetch(&url(addr, "/missing")).await, Err(FetchError::Status(404))));
+        assert!(matches!(fetcher.fetch(&url(addr, "/slow")).await, Err(FetchError::Timeout)));
+    }
+}
@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use crate::models::{Attachment, Notification, LinkPreview, RoomTopic};
+
+/// Сообщение чата в том виде, в котором оно уходит клиентам
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ChatMessage {
+    pub id: i64,
+    pub username: String,
+    /// Исходный текст сообщения
+    pub message: String,
+    /// Безопасный HTML, отрисованный сервером из исходного текста
+    pub html: String,
+    pub timestamp: DateTime<Utc>,
+    /// Время последнего редактирования; None, если сообщение не редактировалось
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Корневое сообщение ветки, если это ответ
+    pub parent_id: Option<i64>,
+    /// Число ответов и время последнего ответа (для корневых сообщений)
+    pub reply_count: i64,
+    pub last_reply_at: Option<DateTime<Utc>>,
+    /// Реакции на сообщение, сгруппированные по эмодзи
+    pub reactions: Vec<ReactionSummary>,
+    /// Превью первой ссылки сообщения, если оно уже загружено
+    pub preview: Option<LinkPreview>,
+    /// Прикрепленные файлы
+    pub attachments: Vec<Attachment>,
+}
+
+/// Сводка реакций одного эмодзи на сообщении
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReactionSummary {
+    pub emoji: String,
+    pub count: i64,
+    /// Поставил ли эту реакцию пользователь, получающий историю
+    pub reacted: bool,
+}
+
+/// Закрепленное сообщение комнаты
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PinnedMessage {
+    pub message: ChatMessage,
+    pub pinned_by: String,
+    pub pinned_at: DateTime<Utc>,
+}
+
+/// Непрочитанные сообщения пользователя в комнате
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RoomUnread {
+    pub room: String,
+    pub last_read_id: Option<i64>,
+    pub unread: i64,
+}
+
+/// События, которые сервер отправляет клиентам по WebSocket
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Message(ChatMessage),
+    Deleted { id: i64 },
+    Edited { id: i64, message: String, html: String, edited_at: DateTime<Utc> },
+    /// Ответы ветки в ответ на команду thread
+    Thread { id: i64, replies: Vec<ChatMessage> },
+    /// Новая сводка ветки после ответа
+    ThreadUpdated { id: i64, reply_count: i64, last_reply_at: Option<DateTime<Utc>> },
+    /// Превью ссылки, загруженное после отправки сообщения
+    Preview { id: i64, preview: LinkPreview },
+    ReactionAdded { id: i64, emoji: String, username: String, count: i64 },
+    ReactionRemoved { id: i64, emoji: String, username: String, count: i64 },
+    /// Тема комнаты: после истории и при каждом изменении
+    Topic(RoomTopic),
+    /// Закрепленные сообщения комнаты, отправляются после истории
+    Pins { pins: Vec<PinnedMessage> },
+    Pinned(PinnedMessage),
+    Unpinned { id: i64 },
+    /// Сводка непрочитанного по всем комнатам, отправляется после истории
+    Unread { rooms: Vec<RoomUnread> },
+    /// Пользователь дочитал комнату до сообщения id
+    ReadReceipt { username: String, id: i64 },
+    TypingStarted { username: String },
+    TypingStopped { username: String },
+    /// Новое уведомление (упоминание) для этого пользователя
+    Notification(Notification),
+    Kicked { reason: String },
+    /// Ответ на heartbeat клиента
+    Heartbeat,
+    /// Клиент отстал от рассылки и пропустил missed событий; ему нужно заново загрузить комнату (sync)
+    Resync { missed: u64 },
+    /// Сервер останавливается; переподключиться стоит не раньше чем через reconnect_after_secs
+    ServerShutdown { reconnect_after_secs: u64 },
+    /// Подтверждение выполненной команды
+    Notice { message: String },
+    Error { message: String },
+}
+
+impl ServerEvent {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("ServerEvent is always serializable")
+    }
+}
+
+/// Команды, которые клиент отправляет серверу по WebSocket
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientCommand {
+    Message {
+        message: String,
+        /// Сообщение, на которое отвечает клиент
+        #[serde(default)]
+        parent_id: Option<i64>,
+        /// id файлов, загруженных через POST /api/upload
+        #[serde(default)]
+        attachments: Vec<i64>,
+    },
+    /// Открыть ветку: загрузить ее ответы и получать новые
+    Thread { id: i64 },
+    /// Закрыть ветку и перестать получать ее обновления
+    CloseThread { id: i64 },
+    Edit { id: i64, message: String },
+    React { id: i64, emoji: String },
+    Unreact { id: i64, emoji: String },
+    Delete { id: i64 },
+    Pin { id: i64 },
+    Unpin { id: i64 },
+    /// Задать тему и описание текущей комнаты; пустые значения убирают их
+    SetTopic {
+        #[serde(default)]
+        topic: Option<String>,
+        #[serde(default)]
+        description: Option<String>,
+    },
+    /// Отметить сообщения текущей комнаты прочитанными до id включительно
+    Read { id: i64 },
+    TypingStart,
+    TypingStop,
+    /// Заново получить состояние комнаты, как при подключении (после resync)
+    Sync,
+    /// Проверка связи для клиентов, которые не видят ping/pong протокола WebSocket; сервер отвечает heartbeat
+    Heartbeat,
+    Mute { username: String, minutes: i64 },
+    Kick { username: String, reason: Option<String> },
+    Ban {
+        username: Option<String>,
+        ip: Option<String>,
+        minutes: Option<i64>,
+        reason: Option<String>,
+    },
+}
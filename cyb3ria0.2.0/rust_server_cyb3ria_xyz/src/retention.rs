@@ -0,0 +1,230 @@
+//! Очистка сообщений с истекшим сроком хранения (см. rooms.retention_days и rooms.retention_messages).
+//! Фоновая задача раз в retention.purge_interval_secs удаляет сообщения пачками, чтобы не держать
+//! долгие блокировки, вместе с их вложениями и файлами в uploads.root, и записывает итог
+//! в журнал аудита. Тем же проходом удаляются загрузки, которые так и не прикрепили к сообщению.
+
+use crate::audit;
+use crate::config;
+use crate::db::{find_room_retentions, purge_expired_messages, purge_unattached_uploads};
+use crate::models::RoomRetention;
+use log::{debug, error, info, warn};
+use serde_json::json;
+use std::io::ErrorKind;
+use std::path::{Component, Path};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Duration};
+
+/// Сколько сообщений удаляется одним запросом
+const PURGE_BATCH_SIZE: i64 = 500;
+
+/// Сколько секунд загрузка может пролежать неприкрепленной: клиент прикрепляет файл сразу
+/// после загрузки, так что сутки — с большим запасом
+const UNATTACHED_UPLOAD_TTL_SECS: f64 = 24.0 * 60.0 * 60.0;
+
+/// Сколько файлов вложений удалено, уже отсутствовало на диске и не удалось удалить
+#[derive(Debug, Default)]
+struct RemovedFiles {
+    deleted: u64,
+    missing: u64,
+    failed: u64,
+}
+
+/// Запускает фоновую очистку. Первый проход выполняется сразу после запуска сервера.
+pub fn spawn_purge_job() -> JoinHandle<()> {
+    tokio::spawn(async {
+        let mut timer = interval(Duration::from_secs(config::get().retention.purge_interval_secs));
+        loop {
+            timer.tick().await;
+            purge_expired().await;
+        }
+    })
+}
+
+/// Один проход очистки по всем комнатам, для которых задан срок хранения, и по неприкрепленным загрузкам
+pub async fn purge_expired() {
+    let retentions = match find_room_retentions().await {
+        Ok(retentions) => retentions,
+        Err(e) => {
+            error!("Failed to load retention settings: {}", e);
+            return;
+        }
+    };
+
+    let mut rooms = serde_json::Map::new();
+    let mut total = 0;
+    let mut files = RemovedFiles::default();
+
+    for retention in &retentions {
+        let purged = purge_room(retention, &mut files).await;
+        if purged > 0 {
+            rooms.insert(retention.room.clone(), json!(purged));
+            total += purged;
+        }
+    }
+
+    let uploads = purge_uploads(&mut files).await;
+
+    if total == 0 && uploads == 0 {
+        debug!("Retention purge: nothing to delete");
+        return;
+    }
+
+    let rooms = serde_json::Value::Object(rooms);
+    info!(
+        "Retention purge deleted {} messages: {}; unattached uploads {}; attachment files deleted {}, missing {}, failed {}",
+        total, rooms, uploads, files.deleted, files.missing, files.failed
+    );
+    audit::record(
+        audit::MESSAGES_PURGED,
+        None,
+        None,
+        None,
+        json!({
+            "total": total,
+            "rooms": rooms,
+            "unattached_uploads": uploads,
+            "files": { "deleted": files.deleted, "missing": files.missing, "failed": files.failed },
+        }),
+    )
+    .await;
+}
+
+/// Удаляет пачками сообщения комнаты с истекшим сроком хранения и файлы их вложений.
+/// Возвращает число удаленных сообщений вместе с ответами.
+async fn purge_room(retention: &RoomRetention, files: &mut RemovedFiles) -> i64 {
+    let mut purged = 0;
+    loop {
+        match purge_expired_messages(retention, PURGE_BATCH_SIZE).await {
+            Ok(batch) => {
+                purged += batch.messages + batch.replies;
+                remove_files(&batch.files, files).await;
+                if batch.messages < PURGE_BATCH_SIZE {
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("Failed to purge messages of room {}: {}", retention.room, e);
+                break;
+            }
+        }
+        // Между пачками даем поработать другим задачам
+        tokio::task::yield_now().await;
+    }
+    purged
+}
+
+/// Удаляет пачками загрузки, не прикрепленные к сообщению дольше UNATTACHED_UPLOAD_TTL_SECS,
+/// вместе с файлами. Возвращает число удаленных загрузок.
+async fn purge_uploads(files: &mut RemovedFiles) -> usize {
+    let mut purged = 0;
+    loop {
+        match purge_unattached_uploads(UNATTACHED_UPLOAD_TTL_SECS, PURGE_BATCH_SIZE).await {
+            Ok(paths) => {
+                purged += paths.len();
+                remove_files(&paths, files).await;
+                if (paths.len() as i64) < PURGE_BATCH_SIZE {
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("Failed to purge unattached uploads: {}", e);
+                break;
+            }
+        }
+        tokio::task::yield_now().await;
+    }
+    purged
+}
+
+/// Удаляет файлы вложений из uploads.root. Отсутствующий файл или ошибка удаления только
+/// записываются в лог и не прерывают очистку: записи о вложениях уже удалены.
+async fn remove_files(paths: &[String], removed: &mut RemovedFiles) {
+    let root = &config::get().uploads.root;
+    for path in paths {
+        // Путь из базы не должен выводить за пределы uploads.root
+        if !Path::new(path).components().all(|component| matches!(component, Component::Normal(_))) {
+            error!("Refusing to delete attachment file outside uploads root: {}", path);
+            removed.failed += 1;
+            continue;
+        }
+        match tokio::fs::remove_file(root.join(path)).await {
+            Ok(()) => removed.deleted += 1,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                warn!("Attachment file {} is already missing", path);
+                removed.missing += 1;
+            }
+            Err(e) => {
+                error!("Failed to delete attachment file {}: {}", path, e);
+                removed.failed += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{connect_dedicated, init_schema, save_message_to_db, save_room_to_db, save_upload, save_user_to_db, set_room_retention};
+    use crate::models::{Role, User};
+    use uuid::Uuid;
+
+    /// Загрузка с файлом в uploads.root; возвращает id вложения и путь файла на диске
+    async fn upload(user_uuid: Uuid) -> (i64, std::path::PathBuf) {
+        let path = format!("test/{}.txt", Uuid::new_v4().simple());
+        let file = config::get().uploads.root.join(&path);
+        tokio::fs::create_dir_all(file.parent().unwrap()).await.unwrap();
+        tokio::fs::write(&file, b"data").await.unwrap();
+        (save_upload(user_uuid, &path, "data.txt", Some("text/plain"), 4).await.unwrap(), file)
+    }
+
+    #[tokio::test]
+    async fn purge_removes_attachment_rows_and_files() {
+        if config::init_for_tests().is_none() {
+            eprintln!("DATABASE_URL is not set, skipping");
+            return;
+        }
+        init_schema().await.unwrap();
+
+        let user_uuid = Uuid::new_v4();
+        let username = format!("retention_{}", &user_uuid.simple().to_string()[..8]);
+        let user = User { username, password_hash: String::new(), invitation_code: "test".to_string(), user_uuid, role: Role::Member };
+        save_user_to_db(user).await.unwrap();
+        let room = format!("retention-test-{}", Uuid::new_v4().simple());
+        save_room_to_db(&room).await.unwrap();
+        let retention = RoomRetention { room: room.clone(), days: Some(1), messages: None };
+        set_room_retention(&retention).await.unwrap();
+
+        let (attached, attached_file) = upload(user_uuid).await;
+        let (message, _, _) = save_message_to_db("old", "old", user_uuid, &room, None, &[attached]).await.unwrap();
+        let (unattached, unattached_file) = upload(user_uuid).await;
+        let (fresh, fresh_file) = upload(user_uuid).await;
+
+        let client = connect_dedicated().await.unwrap();
+        client.execute("UPDATE messages SET timestamp = now() - INTERVAL '2 days' WHERE id = $1", &[&message]).await.unwrap();
+        client.execute("UPDATE attachments SET created_at = now() - INTERVAL '2 days' WHERE id = $1", &[&unattached]).await.unwrap();
+
+        let mut files = RemovedFiles::default();
+        assert_eq!(purge_room(&retention, &mut files).await, 1);
+        assert!(purge_uploads(&mut files).await >= 1);
+
+        // Удалены записи и файлы вложения устаревшего сообщения и давней неприкрепленной загрузки;
+        // свежая загрузка еще ждет, пока ее прикрепят
+        let remaining: Vec<i64> = client
+            .query("SELECT id FROM attachments WHERE id = ANY($1)", &[&vec![attached, unattached, fresh]])
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(remaining, vec![fresh]);
+        assert!(!attached_file.exists());
+        assert!(!unattached_file.exists());
+        assert!(fresh_file.exists());
+
+        tokio::fs::remove_file(&fresh_file).await.unwrap();
+        client.execute("DELETE FROM attachments WHERE id = $1", &[&fresh]).await.unwrap();
+        client.execute("DELETE FROM messages WHERE room = $1", &[&room]).await.unwrap();
+        client.execute("DELETE FROM rooms WHERE name = $1", &[&room]).await.unwrap();
+        client.execute("DELETE FROM users WHERE user_uuid = $1", &[&user_uuid]).await.unwrap();
+    }
+}
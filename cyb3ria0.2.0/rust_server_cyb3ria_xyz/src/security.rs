@@ -0,0 +1,138 @@
+//! Заголовки безопасности и CORS для всех маршрутов.
+//!
+//! wrap оборачивает готовые маршруты: каждый ответ получает CSP, X-Frame-Options,
+//! Referrer-Policy, X-Content-Type-Options и, если настроено, HSTS. Заголовок, который маршрут
+//! выставил сам (например, CSP sandbox у загруженных файлов), не перезаписывается.
+//! Запросы из браузера с чужих источников пропускаются только из cors.allowed_origins.
+//! Отказы, которые не превратил в ответ handle_rejection (405, 400), warp отдает сам, без этих
+//! заголовков; тела у таких ответов нет.
+//!
+//! Браузер не применяет CORS к WebSocket, поэтому chat_route проверяет Origin сам
+//! (allowed_origin), иначе чужая страница могла бы открыть чат с cookie пользователя.
+
+use crate::config;
+use crate::permissions::ForeignOrigin;
+use std::sync::Arc;
+use warp::filters::BoxedFilter;
+use warp::http::header::{
+    HeaderMap, HeaderValue, CONTENT_SECURITY_POLICY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS,
+    X_FRAME_OPTIONS,
+};
+use warp::http::uri::Authority;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+/// Заголовки из настроек security_headers; пустые значения пропускаются
+fn security_headers() -> HeaderMap {
+    let settings = &config::get().security_headers;
+    let mut headers = HeaderMap::new();
+
+    // Значения проверены при загрузке настроек
+    for (name, value) in [
+        (CONTENT_SECURITY_POLICY, &settings.content_security_policy),
+        (X_FRAME_OPTIONS, &settings.frame_options),
+        (REFERRER_POLICY, &settings.referrer_policy),
+    ] {
+        if !value.is_empty() {
+            headers.insert(name, HeaderValue::from_str(value).unwrap());
+        }
+    }
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+
+    // По HTTP браузеры этот заголовок игнорируют, так что он безопасен и за nginx
+    if settings.hsts_max_age_secs > 0 {
+        let mut hsts = format!("max-age={}", settings.hsts_max_age_secs);
+        if settings.hsts_include_subdomains {
+            hsts.push_str("; includeSubDomains");
+        }
+        headers.insert(STRICT_TRANSPORT_SECURITY, HeaderValue::from_str(&hsts).unwrap());
+    }
+
+    headers
+}
+
+/// CORS по списку cors.allowed_origins; None, если список пуст
+fn cors() -> Option<warp::cors::Builder> {
+    let allowed_origins = &config::get().cors.allowed_origins;
+    if allowed_origins.is_empty() {
+        return None;
+    }
+    Some(
+        warp::cors()
+            .allow_origins(allowed_origins.iter().map(String::as_str))
+            .allow_credentials(true)
+            .allow_methods(vec!["GET", "POST", "PUT", "DELETE"])
+            .allow_headers(vec!["content-type"]),
+    )
+}
+
+/// Оборачивает все маршруты сервера: CORS и заголовки безопасности в каждом ответе,
+/// включая ответы на preflight-запросы
+pub fn wrap<F, T>(routes: F) -> BoxedFilter<(Response,)>
+where
+    F: Filter<Extract = (T,), Error = Rejection> + Clone + Send + Sync + 'static,
+    T: Reply + 'static,
+{
+    let routes = match cors() {
+        Some(cors) => routes.with(cors).map(Reply::into_response).boxed(),
+        None => routes.map(Reply::into_response).boxed(),
+    };
+
+    let headers = Arc::new(security_headers());
+    routes
+        .map(move |mut response: Response| {
+            for (name, value) in headers.iter() {
+                if !response.headers().contains_key(name) {
+                    response.headers_mut().insert(name, value.clone());
+                }
+            }
+            response
+        })
+        .boxed()
+}
+
+/// Пропускает запрос без Origin (не браузер), со своего источника (совпадает с Host)
+/// или из cors.allowed_origins; остальные отклоняются с ForeignOrigin
+pub fn allowed_origin() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("origin")
+        .and(warp::host::optional())
+        .and_then(|origin: Option<String>, host: Option<Authority>| async move {
+            match origin {
+                None => Ok(()),
+                Some(origin) if is_allowed_origin(&origin, host.as_ref()) => Ok(()),
+                Some(_) => Err(warp::reject::custom(ForeignOrigin)),
+            }
+        })
+        .untuple_one()
+}
+
+fn is_allowed_origin(origin: &str, host: Option<&Authority>) -> bool {
+    // Origin: null (sandbox, file://) не разбирается как URL и отклоняется
+    let Ok(url) = url::Url::parse(origin) else {
+        return false;
+    };
+
+    let same_origin = host.is_some_and(|host| {
+        url.host_str().is_some_and(|origin_host| origin_host.eq_ignore_ascii_case(host.host()))
+            && url.port_or_known_default() == host.port_u16().or_else(|| default_port(url.scheme()))
+    });
+    if same_origin {
+        return true;
+    }
+
+    let origin = url.origin();
+    config::get()
+        .cors
+        .allowed_origins
+        .iter()
+        .any(|allowed| url::Url::parse(allowed).is_ok_and(|allowed| allowed.origin() == origin))
+}
+
+/// Порт, который подразумевает Host без порта
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "https" => Some(443),
+        "http" => Some(80),
+        _ => None,
+    }
+}
@@ -0,0 +1,101 @@
+//! Плавная остановка сервера по SIGTERM или SIGINT.
+//!
+//! Сервер перестает принимать новые подключения и дожидается текущих HTTP-запросов.
+//! Каждому WebSocket-клиенту уходят накопившиеся события, затем server_shutdown с подсказкой,
+//! через сколько переподключаться, и закрытие с кодом 1001 (Going Away). Вся остановка —
+//! HTTP-запросы, соединения клиентов и соединения с базой данных — укладывается в
+//! server.shutdown_timeout_secs с момента получения сигнала.
+
+use crate::config;
+use crate::db;
+use crate::handlers::chat::{Clients, Control, Sender};
+use log::{error, info, warn};
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+
+/// Через сколько секунд клиентам советуется переподключиться
+pub const RECONNECT_AFTER_SECS: u64 = 5;
+
+/// Как часто проверяется, закончили ли работу соединения
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Срок завершения остановки; None, пока сигнал не получен
+pub type Deadline = watch::Receiver<Option<Instant>>;
+
+/// Канал, через который signal сообщает срок остановки
+pub fn deadline_channel() -> (watch::Sender<Option<Instant>>, Deadline) {
+    watch::channel(None)
+}
+
+/// Ждет SIGTERM или SIGINT, отсчитывает от него срок остановки и просит всех клиентов отключиться
+pub async fn signal(clients: Clients, deadline: watch::Sender<Option<Instant>>) {
+    let mut terminate = match unix_signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            error!("Failed to install SIGTERM handler: {}", e);
+            return std::future::pending().await;
+        }
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+        result = tokio::signal::ctrl_c() => match result {
+            Ok(()) => info!("Received SIGINT, shutting down"),
+            Err(e) => {
+                error!("Failed to listen for SIGINT: {}", e);
+                return std::future::pending().await;
+            }
+        },
+    }
+
+    deadline.send_replace(Some(Instant::now() + Duration::from_secs(config::get().server.shutdown_timeout_secs)));
+
+    let clients = clients.lock().unwrap();
+    info!("Disconnecting {} client(s)", clients.len());
+    for client in clients.values() {
+        // Ошибка значит, что соединение уже завершается само
+        let _ = client.control.send(Control::Shutdown);
+    }
+}
+
+/// Ждет получения сигнала и возвращает срок остановки
+pub async fn deadline(mut deadline: Deadline) -> Instant {
+    match deadline.wait_for(Option::is_some).await {
+        Ok(deadline) => deadline.unwrap(),
+        // signal завершился, не получив сигнала: сервер остановился сам
+        Err(_) => Instant::now() + Duration::from_secs(config::get().server.shutdown_timeout_secs),
+    }
+}
+
+/// Завершается, когда срок остановки истек
+pub async fn expired(deadline: Deadline) {
+    sleep_until(self::deadline(deadline).await).await;
+}
+
+/// Дожидается закрытия клиентских соединений и соединений с базой данных, но не позже
+/// срока остановки. Между ними останавливается рассылка между экземплярами:
+/// у нее свои долгоживущие соединения.
+pub async fn drain(deadline: Deadline, clients: &Clients, sender: &Sender) {
+    let deadline = self::deadline(deadline).await;
+
+    while !clients.lock().unwrap().is_empty() {
+        if Instant::now() >= deadline {
+            warn!("{} client(s) did not disconnect in time", clients.lock().unwrap().len());
+            break;
+        }
+        sleep(DRAIN_POLL_INTERVAL).await;
+    }
+
+    sender.shutdown().await;
+
+    while db::open_connections() > 0 {
+        if Instant::now() >= deadline {
+            warn!("{} database connection(s) still open, exiting anyway", db::open_connections());
+            break;
+        }
+        sleep(DRAIN_POLL_INTERVAL).await;
+    }
+
+    info!("Shutdown complete");
+}
@@ -0,0 +1,217 @@
+//! HTTPS без nginx (tls.enabled).
+//!
+//! Сервер сам принимает TLS-соединения на server.port. Сертификат выбирается при каждом
+//! рукопожатии из CertResolver, поэтому по SIGHUP его можно перечитать с диска, не трогая
+//! уже открытые соединения, в том числе WebSocket. Если новый сертификат не читается,
+//! остается прежний. По tls.redirect_http_port сервер дополнительно слушает HTTP
+//! и перенаправляет все запросы на HTTPS.
+
+use crate::config;
+use crate::utils::PeerAddr;
+use futures_util::FutureExt;
+use log::{debug, error, info};
+use std::convert::Infallible;
+use std::error::Error as StdError;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout, Duration};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use warp::filters::BoxedFilter;
+use warp::filters::path::FullPath;
+use warp::http::uri::{Authority, Uri};
+use warp::http::StatusCode;
+use warp::hyper::server::accept;
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+/// Сколько ждать завершения TLS-рукопожатия
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Пауза после ошибки accept (например, кончились файловые дескрипторы)
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Отдает текущий сертификат при каждом рукопожатии
+struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    fn load(cert_path: &Path, key_path: &Path) -> Result<Self, Box<dyn StdError + Send + Sync>> {
+        let current = load_certified_key(cert_path, key_path)?;
+        Ok(CertResolver { cert_path: cert_path.to_path_buf(), key_path: key_path.to_path_buf(), current: RwLock::new(Arc::new(current)) })
+    }
+
+    /// Перечитывает сертификат и ключ; при ошибке остается прежний сертификат
+    fn reload(&self) -> Result<(), Box<dyn StdError + Send + Sync>> {
+        let reloaded = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(reloaded);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.current.read().unwrap()))
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, Box<dyn StdError + Send + Sync>> {
+    let open = |path: &Path| File::open(path).map(BufReader::new).map_err(|e| format!("cannot open {}: {}", path.display(), e));
+
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut open(cert_path)?)?.into_iter().map(Certificate).collect();
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", cert_path.display()).into());
+    }
+
+    let mut reader = open(key_path)?;
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key)) => break PrivateKey(key),
+            Some(_) => continue,
+            None => return Err(format!("no private key in {}", key_path.display()).into()),
+        }
+    };
+    let key = sign::any_supported_type(&key).map_err(|e| format!("unsupported private key in {}: {}", key_path.display(), e))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Обслуживает routes по HTTPS на address до сигнала остановки. Ошибка — только если сервер
+/// не удалось запустить (сертификат, занятый порт).
+pub async fn serve<S>(routes: BoxedFilter<(Response,)>, address: SocketAddr, signal: S) -> Result<(), Box<dyn StdError + Send + Sync>>
+where
+    S: Future<Output = ()> + Send + 'static,
+{
+    let settings = &config::get().tls;
+    let resolver = Arc::new(CertResolver::load(&settings.cert_path, &settings.key_path)?);
+
+    let mut tls_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>);
+    tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config));
+
+    let listener = TcpListener::bind(address).await?;
+    let signal = signal.shared();
+
+    if settings.redirect_http_port != 0 {
+        let redirect_address = SocketAddr::new(address.ip(), settings.redirect_http_port);
+        let (_, redirect) = warp::serve(redirect_route(address.port())).try_bind_with_graceful_shutdown(redirect_address, signal.clone())?;
+        info!("Redirecting HTTP on {} to HTTPS", redirect_address);
+        tokio::spawn(redirect);
+    }
+
+    let reloader = tokio::spawn(reload_on_sighup(resolver));
+
+    // Рукопожатия идут в отдельных задачах, чтобы медленный клиент не задерживал остальных
+    let (accepted_tx, mut accepted_rx) = mpsc::unbounded_channel();
+    let stop_accepting = signal.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = accept_loop(listener, acceptor, accepted_tx) => {}
+            _ = stop_accepting => {}
+        }
+    });
+    let incoming = futures_util::stream::poll_fn(move |cx| accepted_rx.poll_recv(cx));
+
+    let make_service = make_service_fn(move |stream: &TlsStream<TcpStream>| {
+        let peer = stream.get_ref().0.peer_addr().ok();
+        let service = warp::service(routes.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut request| {
+                if let Some(peer) = peer {
+                    request.extensions_mut().insert(PeerAddr(peer));
+                }
+                service.clone().call(request)
+            }))
+        }
+    });
+
+    info!("Serving HTTPS on {}", address);
+    if let Err(e) = warp::hyper::Server::builder(accept::from_stream(incoming))
+        .serve(make_service)
+        .with_graceful_shutdown(signal)
+        .await
+    {
+        error!("HTTPS server error: {}", e);
+    }
+
+    reloader.abort();
+    Ok(())
+}
+
+async fn accept_loop(listener: TcpListener, acceptor: TlsAcceptor, accepted: mpsc::UnboundedSender<io::Result<TlsStream<TcpStream>>>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
+                sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+
+        let acceptor = acceptor.clone();
+        let accepted = accepted.clone();
+        tokio::spawn(async move {
+            match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _ = accepted.send(Ok(stream));
+                }
+                Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", peer, e),
+                Err(_) => debug!("TLS handshake with {} timed out", peer),
+            }
+        });
+    }
+}
+
+async fn reload_on_sighup(resolver: Arc<CertResolver>) {
+    let mut hangup = match unix_signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        match resolver.reload() {
+            Ok(()) => info!("TLS certificate reloaded"),
+            Err(e) => error!("Failed to reload TLS certificate, keeping the previous one: {}", e),
+        }
+    }
+}
+
+/// Перенаправляет любой HTTP-запрос на тот же адрес по HTTPS
+fn redirect_route(https_port: u16) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    warp::host::optional()
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(move |authority: Option<Authority>, path: FullPath, query: String| {
+            let Some(authority) = authority else {
+                return warp::reply::with_status("Host header is required.", StatusCode::BAD_REQUEST).into_response();
+            };
+            let port = if https_port == 443 { String::new() } else { format!(":{}", https_port) };
+            let query = if query.is_empty() { query } else { format!("?{}", query) };
+            match format!("https://{}{}{}{}", authority.host(), port, path.as_str(), query).parse::<Uri>() {
+                Ok(location) => warp::redirect::permanent(location).into_response(),
+                Err(_) => StatusCode::BAD_REQUEST.into_response(),
+            }
+        })
+}
@@ -1,5 +1,166 @@
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use unicode_segmentation::UnicodeSegmentation;
 use uuid::Uuid;
+use warp::Filter;
+use std::net::{IpAddr, SocketAddr};
+
+/// Удаленный адрес соединения, которое принял не warp::serve (HTTPS, см. tls.rs):
+/// warp::addr::remote для таких соединений пуст, поэтому адрес кладется в расширения запроса
+#[derive(Clone, Copy, Debug)]
+pub struct PeerAddr(pub SocketAddr);
 
 pub fn generate_client_id() -> String {
     Uuid::new_v4().to_string()
 }
+
+/// Фильтр, возвращающий IP-адрес клиента. За nginx удаленным адресом всегда
+/// будет 127.0.0.1, поэтому для локальных подключений доверяем заголовку X-Real-IP.
+pub fn real_ip() -> impl Filter<Extract = (IpAddr,), Error = warp::Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<PeerAddr>())
+        .and(warp::header::optional::<String>("x-real-ip"))
+        .map(|addr: Option<SocketAddr>, peer: Option<PeerAddr>, real_ip: Option<String>| {
+            let peer_ip = addr.or(peer.map(|peer| peer.0)).expect("Failed to get peer address").ip();
+            match real_ip.and_then(|value| value.trim().parse::<IpAddr>().ok()) {
+                Some(real_ip) if peer_ip.is_loopback() => real_ip,
+                _ => peer_ip,
+            }
+        })
+}
+
+/// Максимальная длина реакции в символах: хватает на эмодзи из нескольких
+/// частей, соединенных ZWJ (семьи, флаги регионов и т.п.)
+const MAX_EMOJI_CHARS: usize = 16;
+
+// Свойство Extended_Pictographic из Unicode (emoji-data.txt): символы, с которых
+// начинается эмодзи и которые соединяются ZWJ
+fn is_extended_pictographic(c: char) -> bool {
+    matches!(c as u32,
+        0x00A9 | 0x00AE | 0x203C | 0x2049 | 0x2122 | 0x2139 | 0x2194..=0x2199 | 0x21A9..=0x21AA
+        | 0x231A..=0x231B | 0x2328 | 0x23CF | 0x23E9..=0x23F3 | 0x23F8..=0x23FA | 0x24C2
+        | 0x25AA..=0x25AB | 0x25B6 | 0x25C0 | 0x25FB..=0x25FE | 0x2600..=0x2604 | 0x260E | 0x2611
+        | 0x2614..=0x2615 | 0x2618 | 0x261D | 0x2620 | 0x2622..=0x2623 | 0x2626 | 0x262A
+        | 0x262E..=0x262F | 0x2638..=0x263A | 0x2640 | 0x2642 | 0x2648..=0x2653 | 0x265F..=0x2660
+        | 0x2663 | 0x2665..=0x2666 | 0x2668 | 0x267B | 0x267E..=0x267F | 0x2692..=0x2697 | 0x2699
+        | 0x269B..=0x269C | 0x26A0..=0x26A1 | 0x26A7 | 0x26AA..=0x26AB | 0x26B0..=0x26B1
+        | 0x26BD..=0x26BE | 0x26C4..=0x26C5 | 0x26C8 | 0x26CE..=0x26CF | 0x26D1 | 0x26D3..=0x26D4
+        | 0x26E9..=0x26EA | 0x26F0..=0x26F5 | 0x26F7..=0x26FA | 0x26FD | 0x2702 | 0x2705
+        | 0x2708..=0x270D | 0x270F | 0x2712 | 0x2714 | 0x2716 | 0x271D | 0x2721 | 0x2728
+        | 0x2733..=0x2734 | 0x2744 | 0x2747 | 0x274C | 0x274E | 0x2753..=0x2755 | 0x2757
+        | 0x2763..=0x2764 | 0x2795..=0x2797 | 0x27A1 | 0x27B0 | 0x27BF | 0x2934..=0x2935
+        | 0x2B05..=0x2B07 | 0x2B1B..=0x2B1C | 0x2B50 | 0x2B55 | 0x3030 | 0x303D | 0x3297 | 0x3299
+        | 0x1F004 | 0x1F02C..=0x1F02F | 0x1F094..=0x1F09F | 0x1F0AF..=0x1F0B0 | 0x1F0C0
+        | 0x1F0CF..=0x1F0D0 | 0x1F0F6..=0x1F0FF | 0x1F170..=0x1F171 | 0x1F17E..=0x1F17F | 0x1F18E
+        | 0x1F191..=0x1F19A | 0x1F1AE..=0x1F1E5 | 0x1F201..=0x1F20F | 0x1F21A | 0x1F22F
+        | 0x1F232..=0x1F23A | 0x1F23C..=0x1F23F | 0x1F249..=0x1F25F | 0x1F266..=0x1F321
+        | 0x1F324..=0x1F393 | 0x1F396..=0x1F397 | 0x1F399..=0x1F39B | 0x1F39E..=0x1F3F0
+        | 0x1F3F3..=0x1F3F5 | 0x1F3F7..=0x1F3FA | 0x1F400..=0x1F4FD | 0x1F4FF..=0x1F53D
+        | 0x1F549..=0x1F54E | 0x1F550..=0x1F567 | 0x1F56F..=0x1F570 | 0x1F573..=0x1F57A | 0x1F587
+        | 0x1F58A..=0x1F58D | 0x1F590 | 0x1F595..=0x1F596 | 0x1F5A4..=0x1F5A5 | 0x1F5A8
+        | 0x1F5B1..=0x1F5B2 | 0x1F5BC | 0x1F5C2..=0x1F5C4 | 0x1F5D1..=0x1F5D3 | 0x1F5DC..=0x1F5DE
+        | 0x1F5E1 | 0x1F5E3 | 0x1F5E8 | 0x1F5EF | 0x1F5F3 | 0x1F5FA..=0x1F64F | 0x1F680..=0x1F6C5
+        | 0x1F6CB..=0x1F6D2 | 0x1F6D5..=0x1F6E5 | 0x1F6E9 | 0x1F6EB..=0x1F6F0 | 0x1F6F3..=0x1F6FF
+        | 0x1F7DA..=0x1F7FF | 0x1F80C..=0x1F80F | 0x1F848..=0x1F84F | 0x1F85A..=0x1F85F
+        | 0x1F888..=0x1F88F | 0x1F8AE..=0x1F8AF | 0x1F8BC..=0x1F8BF | 0x1F8C2..=0x1F8CF
+        | 0x1F8D9..=0x1F8FF | 0x1F90C..=0x1F93A | 0x1F93C..=0x1F945 | 0x1F947..=0x1F9FF
+        | 0x1FA58..=0x1FA5F | 0x1FA6E..=0x1FAFF | 0x1FC00..=0x1FFFD)
+}
+
+// Служебные символы внутри эмодзи: ZWJ, селекторы вариантов, keycap,
+// модификаторы тона кожи, теги флагов регионов
+fn is_emoji_component(c: char) -> bool {
+    matches!(c as u32, 0x200D | 0xFE0E | 0xFE0F | 0x20E3 | 0x1F3FB..=0x1F3FF | 0xE0020..=0xE007F)
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    matches!(c as u32, 0x1F1E6..=0x1F1FF)
+}
+
+/// Проверяет, что строка — ровно один Unicode-эмодзи (одна графема), а не текст
+/// и не несколько эмодзи подряд
+pub fn is_emoji(value: &str) -> bool {
+    if value.chars().count() > MAX_EMOJI_CHARS {
+        return false;
+    }
+    let mut graphemes = value.graphemes(true);
+    let (Some(grapheme), None) = (graphemes.next(), graphemes.next()) else {
+        return false;
+    };
+
+    let mut chars = grapheme.chars();
+    let Some(base) = chars.next() else {
+        return false;
+    };
+    // Флаг страны — пара региональных индикаторов
+    if is_regional_indicator(base) {
+        return grapheme.chars().count() == 2 && grapheme.chars().all(is_regional_indicator);
+    }
+    // Keycap: цифра, # или *, затем U+20E3 (1️⃣, #️⃣)
+    if base.is_ascii_digit() || base == '#' || base == '*' {
+        return matches!(chars.as_str(), "\u{20E3}" | "\u{FE0F}\u{20E3}");
+    }
+    is_extended_pictographic(base) && chars.all(|c| is_extended_pictographic(c) || is_emoji_component(c))
+}
+
+/// Экранирует текст для безопасной вставки в HTML
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Символы, которые в сегменте пути URL остаются как есть (unreserved из RFC 3986)
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// Ссылка /uploaded/{path} на загруженный файл; path — путь внутри uploads.root,
+/// каждый его сегмент кодируется
+pub fn uploaded_url(path: &str) -> String {
+    let mut url = String::from("/uploaded");
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        url.push('/');
+        url.extend(utf8_percent_encode(segment, PATH_SEGMENT));
+    }
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_emoji, uploaded_url};
+
+    #[test]
+    fn accepts_single_emoji() {
+        for emoji in ["👍", "❤️", "👍🏽", "👩‍💻", "👨‍👩‍👧‍👦", "🇺🇦", "1️⃣", "#️⃣", "🏴󠁧󠁢󠁳󠁣󠁴󠁿", "↩️", "©️"] {
+            assert!(is_emoji(emoji), "{:?} should be accepted", emoji);
+        }
+    }
+
+    #[test]
+    fn rejects_several_emoji() {
+        for value in ["👍👍", "👍🎉", "🇺🇦🇵🇱", "🇺🇦🇵", "😀".repeat(10).as_str()] {
+            assert!(!is_emoji(value), "{:?} should be rejected", value);
+        }
+    }
+
+    #[test]
+    fn rejects_arrows_shapes_and_text() {
+        for value in ["→", "←", "■", "→←■", "⤴x", "a", "1", "#", "👍 ", "", "\u{200D}", "\u{FE0F}", "\u{1F3FB}"] {
+            assert!(!is_emoji(value), "{:?} should be rejected", value);
+        }
+    }
+
+    #[test]
+    fn uploaded_url_encodes_path_segments() {
+        assert_eq!(uploaded_url("2025/02/photo.png"), "/uploaded/2025/02/photo.png");
+        assert_eq!(uploaded_url("a b/\"<x>&.txt"), "/uploaded/a%20b/%22%3Cx%3E%26.txt");
+        assert_eq!(uploaded_url("отчет?.pdf"), "/uploaded/%D0%BE%D1%82%D1%87%D0%B5%D1%82%3F.pdf");
+    }
+}
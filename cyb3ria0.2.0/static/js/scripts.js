@@ -1,70 +1,607 @@
-// Получаем IP-адрес клиента
-let ipAddress = '';
-fetch('https://api.ipify.org?format=json')
-    .then(response => response.json())
-    .then(data => {
-        ipAddress = data.ip;
-        console.log('Client IP Address:', ipAddress);
-    })
-    .catch(error => {
-        console.error('Error fetching IP address:', error);
-    });
-
-// MAC-адрес нельзя получить напрямую в браузере, но можно использовать заглушку
-let macAddress = '00:00:00:00:00:00'; // Заглушка для MAC-адреса
-
 const messages = document.getElementById('messages');
 const form = document.getElementById('form');
 const input = document.getElementById('name');
+const fileInput = document.getElementById('file');
+const thread = document.getElementById('thread');
+const threadMessages = document.getElementById('thread-messages');
+const threadForm = document.getElementById('thread-form');
+const threadInput = document.getElementById('thread-input');
+const pins = document.getElementById('pins');
 
-const urlParams = new URLSearchParams(window.location.search);
-const username = urlParams.get('username');
+// Роль сохраняется страницей входа; сервер все равно проверяет права сам
+const role = sessionStorage.getItem('role') || 'member';
+const isModerator = role === 'moderator' || role === 'admin';
+// Имя текущего пользователя передается страницей входа в адресе чата, комната — параметром room
+const params = new URLSearchParams(window.location.search);
+const currentUsername = params.get('username');
+const currentRoom = params.get('room') || 'general';
+
+const TYPING_INTERVAL = 2000; // Как часто повторять typing_start, пока пользователь печатает
+const TYPING_IDLE = 5000; // Через сколько после последнего нажатия считать, что набор закончен
+const TYPING_EXPIRE = 7000; // Сколько показывать чужой набор без обновления
+const RECONNECT_DELAY = 5000; // Пауза перед переподключением после обрыва
+const HEARTBEAT_INTERVAL = 30000; // Как часто проверять, что сервер на связи
 
 let ws = null;
+let kicked = false;
+let reconnectDelay = RECONNECT_DELAY;
+let heartbeatTimer = null;
+let heartbeatPending = false; // heartbeat отправлен, ответа еще нет
+let openThreadId = null; // Корневое сообщение открытой ветки
+let lastTypingSent = 0;
+let typingIdleTimer = null;
+let readTimer = null;
+let lastReadId = 0;
+const typingUsers = new Map(); // username -> таймер скрытия
+const readers = new Map(); // username -> id последнего прочитанного сообщения
+
+function setStatus(text) {
+    document.getElementById('connection-status').textContent = text;
+}
+
+function sendCommand(command) {
+    if (ws && ws.readyState === WebSocket.OPEN) {
+        ws.send(JSON.stringify(command));
+    }
+}
+
+// html приходит от сервера уже очищенным (безопасное подмножество Markdown);
+// имя автора и исходный текст вставляются только как текст
+function setMessageText(li, username, text, html, editedAt) {
+    const span = li.querySelector('.message-text');
+    span.textContent = `${username}: `;
+    const body = document.createElement('span');
+    body.innerHTML = html;
+    span.appendChild(body);
+    li.dataset.text = text;
+    if (editedAt) {
+        const edited = document.createElement('small');
+        edited.textContent = ' (edited)';
+        edited.title = new Date(editedAt).toLocaleString();
+        span.appendChild(edited);
+    }
+}
+
+// Карточка превью ссылки под текстом сообщения; preview = null убирает ее.
+// Все поля вставляются как текст, адреса — только http(s)
+function setPreview(li, preview) {
+    const existing = li.querySelector('.preview');
+    if (existing) {
+        existing.remove();
+    }
+    if (!preview || !/^https?:\/\//i.test(preview.url)) {
+        return;
+    }
+    const card = document.createElement('div');
+    card.className = 'preview';
+    if (preview.image && /^https?:\/\//i.test(preview.image)) {
+        const image = document.createElement('img');
+        image.src = preview.image;
+        image.alt = '';
+        image.referrerPolicy = 'no-referrer';
+        image.loading = 'lazy';
+        card.appendChild(image);
+    }
+    const link = document.createElement('a');
+    link.href = preview.url;
+    link.rel = 'nofollow noopener noreferrer';
+    link.target = '_blank';
+    link.textContent = preview.title || preview.url;
+    card.appendChild(link);
+    if (preview.site_name) {
+        const site = document.createElement('small');
+        site.textContent = ` — ${preview.site_name}`;
+        card.appendChild(site);
+    }
+    if (preview.description) {
+        const description = document.createElement('p');
+        description.textContent = preview.description;
+        card.appendChild(description);
+    }
+    li.querySelector('.message-text').after(card);
+}
+
+// Ссылки на вложения сообщения. Имя файла вставляется как текст, адрес — только /uploaded/
+function setAttachments(li, attachments) {
+    if (!attachments || attachments.length === 0) {
+        return;
+    }
+    const list = document.createElement('ul');
+    list.className = 'attachments';
+    for (const attachment of attachments) {
+        if (!attachment.url.startsWith('/uploaded/')) {
+            continue;
+        }
+        const item = document.createElement('li');
+        const link = document.createElement('a');
+        link.href = attachment.url;
+        link.target = '_blank';
+        link.rel = 'noopener';
+        link.textContent = `📎 ${attachment.file_name}`;
+        item.appendChild(link);
+        const size = document.createElement('small');
+        size.textContent = ` (${formatSize(attachment.size)})`;
+        item.appendChild(size);
+        list.appendChild(item);
+    }
+    li.querySelector('.message-text').after(list);
+}
+
+function formatSize(bytes) {
+    if (bytes < 1024) {
+        return `${bytes} B`;
+    }
+    if (bytes < 1024 * 1024) {
+        return `${(bytes / 1024).toFixed(1)} KB`;
+    }
+    return `${(bytes / 1024 / 1024).toFixed(1)} MB`;
+}
+
+// Загружает файл и возвращает id вложения для команды message
+async function uploadFile(file) {
+    const body = new FormData();
+    body.append('file', file);
+    const response = await fetch('/api/upload', { method: 'POST', body });
+    const data = await response.json().catch(() => ({}));
+    if (!response.ok) {
+        throw new Error(data.message || `Upload failed (${response.status})`);
+    }
+    return data.id;
+}
+
+function setReplySummary(li, replyCount, lastReplyAt) {
+    const button = li.querySelector('.replies');
+    if (!button) {
+        return;
+    }
+    button.textContent = replyCount > 0 ? `💬 ${replyCount}` : '💬';
+    button.title = lastReplyAt ? `Last reply: ${new Date(lastReplyAt).toLocaleString()}` : 'Reply in thread';
+}
+
+// Обновляет кнопку реакции; при count = 0 кнопка убирается
+function setReaction(li, emoji, count, reacted) {
+    const bar = li.querySelector('.reactions');
+    let button = [...bar.children].find(child => child.dataset.emoji === emoji);
+    if (count <= 0) {
+        if (button) {
+            button.remove();
+        }
+        return;
+    }
+    if (!button) {
+        button = document.createElement('button');
+        button.dataset.emoji = emoji;
+        button.addEventListener('click', () => {
+            const type = button.classList.contains('reacted') ? 'unreact' : 'react';
+            sendCommand({ type, id: Number(li.id.replace('message-', '')), emoji });
+        });
+        bar.insertBefore(button, bar.lastChild);
+    }
+    button.textContent = `${emoji} ${count}`;
+    if (reacted !== undefined) {
+        button.classList.toggle('reacted', reacted);
+    }
+}
+
+function openThread(id) {
+    if (openThreadId !== null && openThreadId !== id) {
+        sendCommand({ type: 'close_thread', id: openThreadId });
+    }
+    sendCommand({ type: 'thread', id });
+}
+
+function closeThread() {
+    if (openThreadId !== null) {
+        sendCommand({ type: 'close_thread', id: openThreadId });
+    }
+    openThreadId = null;
+    thread.hidden = true;
+    threadMessages.innerHTML = '';
+}
+
+function renderMessage(message, list = messages) {
+    const li = document.createElement('li');
+    li.id = `message-${message.id}`;
+    li.dataset.username = message.username;
+    const span = document.createElement('span');
+    span.className = 'message-text';
+    li.appendChild(span);
+    setMessageText(li, message.username, message.message, message.html, message.edited_at);
+    setAttachments(li, message.attachments);
+    setPreview(li, message.preview);
+
+    if (message.username === currentUsername) {
+        const editButton = document.createElement('button');
+        editButton.textContent = '✎';
+        editButton.title = 'Edit message';
+        editButton.addEventListener('click', () => {
+            const text = prompt('Edit message', li.dataset.text);
+            if (text !== null && text.trim() !== '' && text !== li.dataset.text) {
+                sendCommand({ type: 'edit', id: message.id, message: text });
+            }
+        });
+        li.appendChild(editButton);
+    }
+
+    const bar = document.createElement('span');
+    bar.className = 'reactions';
+    const addReaction = document.createElement('button');
+    addReaction.textContent = '☺+';
+    addReaction.title = 'Add reaction';
+    addReaction.addEventListener('click', () => {
+        const emoji = prompt('Reaction emoji');
+        if (emoji) {
+            sendCommand({ type: 'react', id: message.id, emoji: emoji.trim() });
+        }
+    });
+    bar.appendChild(addReaction);
+    li.appendChild(bar);
+    message.reactions.forEach(reaction => setReaction(li, reaction.emoji, reaction.count, reaction.reacted));
+
+    if (message.parent_id === null) {
+        const repliesButton = document.createElement('button');
+        repliesButton.className = 'replies';
+        repliesButton.addEventListener('click', () => openThread(message.id));
+        li.appendChild(repliesButton);
+        setReplySummary(li, message.reply_count, message.last_reply_at);
+    }
+
+    if (isModerator) {
+        const pinButton = document.createElement('button');
+        pinButton.textContent = '📌';
+        pinButton.title = 'Pin / unpin message';
+        pinButton.addEventListener('click', () => {
+            const type = document.getElementById(`pin-${message.id}`) ? 'unpin' : 'pin';
+            sendCommand({ type, id: message.id });
+        });
+        li.appendChild(pinButton);
+
+        const deleteButton = document.createElement('button');
+        deleteButton.textContent = '✕';
+        deleteButton.title = 'Delete message';
+        deleteButton.addEventListener('click', () => sendCommand({ type: 'delete', id: message.id }));
+        li.appendChild(deleteButton);
+    }
+
+    // Одно и то же сообщение может прийти дважды (рассылка во время sync), а пропущенные — позже
+    // новых, поэтому сообщение заменяет уже показанное и встает на место по id
+    const existing = document.getElementById(li.id);
+    if (existing) {
+        existing.replaceWith(li);
+        return;
+    }
+    const next = [...list.children].find(other => Number(other.id.replace('message-', '')) > message.id);
+    list.insertBefore(li, next || null);
+    list.scrollTop = list.scrollHeight; // Auto-scroll to the bottom
+}
+
+// Тема и описание приходят простым текстом
+function renderTopic(topic) {
+    document.getElementById('topic-title').textContent = topic.topic || '';
+    document.getElementById('topic-description').textContent = topic.description || '';
+    document.getElementById('topic').hidden = !topic.topic && !topic.description;
+    if (topic.updated_by) {
+        document.getElementById('topic').title = `Set by ${topic.updated_by}, ${new Date(topic.updated_at).toLocaleString()}`;
+    }
+}
+
+function renderPin(pin) {
+    const li = document.createElement('li');
+    li.id = `pin-${pin.message.id}`;
+    li.dataset.username = pin.message.username;
+    li.title = `Pinned by ${pin.pinned_by}, ${new Date(pin.pinned_at).toLocaleString()}`;
+    const span = document.createElement('span');
+    span.className = 'message-text';
+    li.appendChild(span);
+    setMessageText(li, pin.message.username, pin.message.message, pin.message.html, pin.message.edited_at);
+    li.addEventListener('click', () => {
+        const message = document.getElementById(`message-${pin.message.id}`);
+        if (message) {
+            message.scrollIntoView({ block: 'center' });
+        }
+    });
+    pins.appendChild(li);
+}
+
+function removePin(id) {
+    const li = document.getElementById(`pin-${id}`);
+    if (li) {
+        li.remove();
+    }
+}
+
+function renderRooms(rooms) {
+    const list = document.getElementById('rooms');
+    list.innerHTML = '';
+    rooms.forEach(room => {
+        const li = document.createElement('li');
+        const link = document.createElement('a');
+        link.href = `chat.html?username=${encodeURIComponent(currentUsername)}&room=${encodeURIComponent(room.room)}`;
+        link.textContent = room.unread > 0 && room.room !== currentRoom ? `#${room.room} (${room.unread})` : `#${room.room}`;
+        if (room.room === currentRoom) {
+            link.className = 'current';
+            lastReadId = room.last_read_id || 0;
+        }
+        li.appendChild(link);
+        list.appendChild(li);
+    });
+}
+
+function renderTyping() {
+    const names = [...typingUsers.keys()];
+    document.getElementById('typing').textContent =
+        names.length === 0 ? '' : `${names.join(', ')} ${names.length === 1 ? 'is' : 'are'} typing...`;
+}
+
+function setTyping(username, typing) {
+    if (username === currentUsername) {
+        return;
+    }
+    clearTimeout(typingUsers.get(username));
+    typingUsers.delete(username);
+    if (typing) {
+        typingUsers.set(username, setTimeout(() => setTyping(username, false), TYPING_EXPIRE));
+    }
+    renderTyping();
+}
+
+// Отправляет typing_start не чаще раза в TYPING_INTERVAL и typing_stop после паузы
+function onTyping() {
+    const now = Date.now();
+    if (now - lastTypingSent > TYPING_INTERVAL) {
+        lastTypingSent = now;
+        sendCommand({ type: 'typing_start' });
+    }
+    clearTimeout(typingIdleTimer);
+    typingIdleTimer = setTimeout(stopTyping, TYPING_IDLE);
+}
+
+function stopTyping() {
+    clearTimeout(typingIdleTimer);
+    if (lastTypingSent !== 0) {
+        lastTypingSent = 0;
+        sendCommand({ type: 'typing_stop' });
+    }
+}
+
+// Отмечает прочитанным последнее сообщение комнаты, пока вкладка на экране
+function scheduleRead() {
+    if (document.visibilityState !== 'visible') {
+        return;
+    }
+    clearTimeout(readTimer);
+    readTimer = setTimeout(() => {
+        const last = messages.lastElementChild;
+        const id = last ? Number(last.id.replace('message-', '')) : 0;
+        if (id > lastReadId) {
+            lastReadId = id;
+            sendCommand({ type: 'read', id });
+        }
+    }, 1000);
+}
+
+function setReader(username, id) {
+    const previous = document.getElementById(`message-${readers.get(username)}`);
+    readers.set(username, id);
+    [previous, document.getElementById(`message-${id}`)].forEach(li => {
+        if (li) {
+            const names = [...readers].filter(([, readId]) => `message-${readId}` === li.id).map(([name]) => name);
+            li.title = names.length > 0 ? `Read by: ${names.join(', ')}` : '';
+        }
+    });
+}
+
+function handleEvent(event) {
+    switch (event.type) {
+        case 'message':
+            if (event.parent_id === null) {
+                renderMessage(event);
+                setTyping(event.username, false);
+                scheduleRead();
+            } else if (event.parent_id === openThreadId) {
+                renderMessage(event, threadMessages);
+            }
+            break;
+        case 'topic':
+            renderTopic(event);
+            break;
+        case 'pins':
+            pins.innerHTML = '';
+            event.pins.forEach(renderPin);
+            break;
+        case 'pinned':
+            removePin(event.message.id);
+            renderPin(event);
+            break;
+        case 'unpinned':
+            removePin(event.id);
+            break;
+        case 'unread':
+            renderRooms(event.rooms);
+            scheduleRead();
+            break;
+        case 'typing_started':
+            setTyping(event.username, true);
+            break;
+        case 'typing_stopped':
+            setTyping(event.username, false);
+            break;
+        case 'read_receipt':
+            setReader(event.username, event.id);
+            break;
+        case 'thread':
+            openThreadId = event.id;
+            threadMessages.innerHTML = '';
+            event.replies.forEach(reply => renderMessage(reply, threadMessages));
+            thread.hidden = false;
+            break;
+        case 'reaction_added':
+        case 'reaction_removed': {
+            const li = document.getElementById(`message-${event.id}`);
+            if (li) {
+                const mine = event.username === currentUsername;
+                setReaction(li, event.emoji, event.count, mine ? event.type === 'reaction_added' : undefined);
+            }
+            break;
+        }
+        case 'thread_updated': {
+            const li = document.getElementById(`message-${event.id}`);
+            if (li) {
+                setReplySummary(li, event.reply_count, event.last_reply_at);
+            }
+            break;
+        }
+        case 'edited': {
+            const li = document.getElementById(`message-${event.id}`);
+            if (li) {
+                setMessageText(li, li.dataset.username, event.message, event.html, event.edited_at);
+                // Превью старой версии больше не актуально; новое придет отдельным событием
+                setPreview(li, null);
+            }
+            const pin = document.getElementById(`pin-${event.id}`);
+            if (pin) {
+                setMessageText(pin, pin.dataset.username, event.message, event.html, event.edited_at);
+            }
+            break;
+        }
+        case 'preview': {
+            const li = document.getElementById(`message-${event.id}`);
+            if (li) {
+                setPreview(li, event.preview);
+            }
+            break;
+        }
+        case 'deleted': {
+            const li = document.getElementById(`message-${event.id}`);
+            if (li) {
+                li.remove();
+            }
+            removePin(event.id);
+            break;
+        }
+        case 'notification':
+            // Полный список уведомлений доступен через GET /api/notifications
+            setStatus(event.kind === 'mention'
+                ? `${event.author} mentioned you in #${event.room}`
+                : `${event.author} notified @${event.kind} in #${event.room}`);
+            break;
+        case 'kicked':
+            kicked = true;
+            setStatus(`Disconnected: ${event.reason}`);
+            break;
+        case 'heartbeat':
+            heartbeatPending = false;
+            break;
+        case 'resync':
+            // Часть событий пропущена: загружаем комнату заново, как при подключении
+            console.log(`Missed ${event.missed} events, resyncing`);
+            messages.innerHTML = '';
+            sendCommand({ type: 'sync' });
+            if (openThreadId !== null) {
+                sendCommand({ type: 'thread', id: openThreadId });
+            }
+            break;
+        case 'server_shutdown':
+            // Случайная добавка, чтобы клиенты не переподключались все разом
+            reconnectDelay = (event.reconnect_after_secs + Math.random() * event.reconnect_after_secs) * 1000;
+            setStatus('Server is restarting, reconnecting...');
+            break;
+        case 'notice':
+            setStatus(event.message);
+            break;
+        case 'error':
+            setStatus(`Error: ${event.message}`);
+            break;
+        default:
+            console.log('Unknown event:', event);
+    }
+}
+
+// Браузер отвечает на ping сервера сам, но не видит их. Чтобы заметить пропавший сервер,
+// клиент шлет heartbeat и переподключается, если ответ не пришел до следующего.
+function startHeartbeat() {
+    clearInterval(heartbeatTimer);
+    heartbeatPending = false;
+    heartbeatTimer = setInterval(() => {
+        if (heartbeatPending) {
+            ws.close();
+            return;
+        }
+        heartbeatPending = true;
+        sendCommand({ type: 'heartbeat' });
+    }, HEARTBEAT_INTERVAL);
+}
 
 function connectWebSocket() {
   if (ws) {
       ws.close();
       console.log('WebSocket connection closed');
    }
-    ws = new WebSocket(`wss://cyb3ria.xyz/api/ws?username=${encodeURIComponent(username)}`);
+    // Пользователь определяется сервером по cookie сессии
+    ws = new WebSocket(`wss://cyb3ria.xyz/api/ws?room=${encodeURIComponent(currentRoom)}`);
 
     ws.onopen = () => {
         console.log('WebSocket connection established');
-         document.getElementById('connection-status').textContent = "Connected";
+        startHeartbeat();
+        messages.innerHTML = '';
+        if (openThreadId !== null) {
+            sendCommand({ type: 'thread', id: openThreadId }); // Переоткрываем ветку после переподключения
+        }
+        setStatus("Connected");
     };
 
     ws.onmessage = event => {
-        const li = document.createElement('li');
-        li.textContent = event.data;
-        messages.appendChild(li);
-        messages.scrollTop = messages.scrollHeight; // Auto-scroll to the bottom
+        handleEvent(JSON.parse(event.data));
     };
 
     ws.onerror = error => {
         console.error('WebSocket error:', error);
-         document.getElementById('connection-status').textContent = "Error";
-        setTimeout(connectWebSocket, 5000) // Попытка переподключения
+        setStatus("Error");
     };
 
     ws.onclose = () => {
         console.log('WebSocket connection closed');
-        document.getElementById('connection-status').textContent = "Disconnected";
-         setTimeout(connectWebSocket, 5000) // Попытка переподключения
+        clearInterval(heartbeatTimer);
+        if (kicked) {
+            return; // После kick/ban не переподключаемся
+        }
+        setStatus("Disconnected");
+        setTimeout(connectWebSocket, reconnectDelay) // Попытка переподключения
+        reconnectDelay = RECONNECT_DELAY;
     };
 }
 
 if (messages) {
     connectWebSocket();
-    form.addEventListener('submit', event => {
+    form.addEventListener('submit', async event => {
         event.preventDefault();
-        const message = {
-            username: username,
-            message: input.value,
-            ip: ipAddress,
-            mac: macAddress
-        };
-        ws.send(JSON.stringify(message));
+        const file = fileInput.files[0];
+        if (file) {
+            // Сначала загружаем файл; без текста сообщением становится имя файла
+            try {
+                const id = await uploadFile(file);
+                sendCommand({ type: 'message', message: input.value.trim() || file.name, attachments: [id] });
+            } catch (error) {
+                setStatus(`Error: ${error.message}`);
+                return;
+            }
+            fileInput.value = '';
+        } else {
+            sendCommand({ type: 'message', message: input.value });
+        }
         input.value = '';
+        lastTypingSent = 0; // Сервер сам завершает набор при отправке сообщения
+        clearTimeout(typingIdleTimer);
+    });
+    input.addEventListener('input', onTyping);
+    input.addEventListener('blur', stopTyping);
+    document.addEventListener('visibilitychange', scheduleRead);
+    threadForm.addEventListener('submit', event => {
+        event.preventDefault();
+        if (openThreadId !== null) {
+            sendCommand({ type: 'message', message: threadInput.value, parent_id: openThreadId });
+        }
+        threadInput.value = '';
     });
-}
\ No newline at end of file
+    document.getElementById('close-thread').addEventListener('click', closeThread);
+}